@@ -7,6 +7,7 @@ use test::Bencher;
 
 use mt::data_storage::memory::MemoryReadonlyDataStorage;
 use mt::data_storage::memory::MemoryDataStorage;
+use mt::tree_storage::flat::FlatTreeStorage;
 use mt::tree_storage::memory::MemoryTreeStorage;
 use mt::merkle_tree::MerkleTree;
 use mt::merkle_tree::MerkleTreeSimple;
@@ -59,6 +60,20 @@ macro_rules! bulk_generic_readonly {
     };
 }
 
+macro_rules! bulk_flat {
+    ($n: expr) => {
+        #[bench]
+        fn bulk_flat(b: &mut Bencher) {
+            let data = make_data($n);
+            b.iter(|| {
+                let mt: MerkleTree<MemoryDataStorage<Chunk4096>, FlatTreeStorage<Type>>;
+                mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data.clone()), Default::default()).unwrap();
+                test::black_box(mt);
+            });
+        }
+    };
+}
+
 macro_rules! bulk_simple {
     ($n: expr) => {
         #[bench]
@@ -90,6 +105,23 @@ macro_rules! step_by_step_generic {
     };
 }
 
+macro_rules! step_by_step_flat {
+    ($n: expr) => {
+        #[bench]
+        fn step_by_step_flat(b: &mut Bencher) {
+            let data = make_data($n);
+            b.iter(|| {
+                let mut mt: MerkleTree<MemoryDataStorage<Chunk4096>, FlatTreeStorage<Type>>;
+                mt = MerkleTree::default();
+                for &x in data.iter() {
+                    mt.push(x).unwrap();
+                }
+                test::black_box(mt);
+            });
+        }
+    };
+}
+
 macro_rules! step_by_step_simple {
     ($n: expr) => {
         #[bench]
@@ -125,6 +157,19 @@ macro_rules! step_bulk_generic {
                 });
             }
 
+            #[bench]
+            fn flat(b: &mut Bencher) {
+                let data = make_data($n);
+                b.iter(|| {
+                    let mut mt: MerkleTree<MemoryDataStorage<Chunk4096>, FlatTreeStorage<Type>>;
+                    mt = MerkleTree::default();
+                    for x in data.chunks($e) {
+                        mt.extend(x.iter().cloned().map(Ok)).unwrap();
+                    }
+                    test::black_box(mt);
+                });
+            }
+
             #[bench]
             fn simple(b: &mut Bencher) {
                 let data = make_data($n);
@@ -163,8 +208,10 @@ macro_rules! bench_n {
 
                 bulk_generic!($n);
                 bulk_generic_readonly!($n);
+                bulk_flat!($n);
                 bulk_simple!($n);
                 step_by_step_generic!($n);
+                step_by_step_flat!($n);
                 step_by_step_simple!($n);
                 step_bulk_generic!($n);
             }
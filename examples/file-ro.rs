@@ -6,7 +6,7 @@ mod fs;
 use std::fs::File;
 use std::io::Write;
 
-use mt::abc::TreeStorage;
+use mt::abc::TreeStorageReadonly;
 use mt::tree_storage::memory::MemoryTreeStorage;
 use mt::merkle_tree::MerkleTree;
 use mt::fun::sha256::Sha256;
@@ -0,0 +1,164 @@
+//! `#[derive(MTHash)]` for user structs and enums.
+//!
+//! Fields are hashed in declaration order, using their own `MTHash` implementations.
+//! Enum variants are prefixed with their index in declaration order, encoded as `u32`.
+//! A field marked with `#[mt(skip)]` is not hashed.
+//! `try_hash` is derived as well, so fields streamed from fallible sources report their errors.
+//!
+//! ```ignore
+//! #[macro_use]
+//! extern crate mt_derive;
+//!
+//! #[derive(Debug, Clone, PartialEq, Eq, MTHash)]
+//! struct Record {
+//!     id: u64,
+//!     name: String,
+//!     #[mt(skip)]
+//!     comment: String,
+//! }
+//! ```
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use proc_macro2::TokenStream as TokenStream2;
+use syn::Data;
+use syn::DeriveInput;
+use syn::Field;
+use syn::Fields;
+use syn::Ident;
+use syn::Index;
+
+
+#[proc_macro_derive(MTHash, attributes(mt))]
+pub fn derive_mthash(input: TokenStream) -> TokenStream {
+    let input = syn::parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+
+    let mut generics = input.generics.clone();
+    for param in generics.type_params_mut() {
+        param.bounds.push(syn::parse_quote!(::mt::abc::MTHash));
+    }
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let hash_body = expand_body(input, &|value| quote!(::mt::abc::MTHash::hash_nested(#value, state);))?;
+    let try_hash_body = expand_body(input, &|value| quote!(::mt::abc::MTHash::try_hash_nested(#value, state)?;))?;
+
+    Ok(quote! {
+        impl #impl_generics ::mt::abc::MTHash for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn hash<__H: ::mt::abc::MTContext>(&self, state: &mut __H) {
+                #hash_body
+            }
+
+            #[allow(unused_variables)]
+            fn try_hash<__H: ::mt::abc::MTContext>(&self, state: &mut __H) -> ::mt::error::Result<()> {
+                #try_hash_body
+                Ok(())
+            }
+        }
+    })
+}
+
+// Hashes all the fields of the value, using `call` to produce a statement, hashing a field
+fn expand_body(input: &DeriveInput, call: &Fn(TokenStream2) -> TokenStream2) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let body = match input.data {
+        Data::Struct(ref data) => expand_struct(&data.fields, call)?,
+        Data::Enum(ref data) => {
+            let mut arms = Vec::new();
+            for (index, variant) in data.variants.iter().enumerate() {
+                let index = index as u32;
+                let variant_name = &variant.ident;
+                let (pattern, hashes) = expand_variant(&variant.fields, call)?;
+                arms.push(quote! {
+                    #name::#variant_name #pattern => {
+                        ::mt::abc::MTHash::hash(&#index, state);
+                        #hashes
+                    }
+                });
+            }
+            quote! {
+                match *self {
+                    #(#arms)*
+                }
+            }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(input, "MTHash can not be derived for unions"));
+        }
+    };
+    Ok(body)
+}
+
+// Hashes the fields of a struct, accessing them through `self`
+fn expand_struct(fields: &Fields, call: &Fn(TokenStream2) -> TokenStream2) -> syn::Result<TokenStream2> {
+    let mut hashes = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        if is_skipped(field)? {
+            continue;
+        }
+        let member = match field.ident {
+            Some(ref ident) => quote!(#ident),
+            None => {
+                let index = Index::from(index);
+                quote!(#index)
+            }
+        };
+        hashes.push(call(quote!(&self.#member)));
+    }
+    Ok(quote!(#(#hashes)*))
+}
+
+// Returns a pattern, binding the fields of a variant, and hashes of the bound fields
+fn expand_variant(fields: &Fields, call: &Fn(TokenStream2) -> TokenStream2) -> syn::Result<(TokenStream2, TokenStream2)> {
+    let mut bindings = Vec::new();
+    let mut hashes = Vec::new();
+    for (index, field) in fields.iter().enumerate() {
+        let binding = Ident::new(&format!("__field{}", index), Span::call_site());
+        let skipped = is_skipped(field)?;
+        let pattern = if skipped { quote!(_) } else { quote!(ref #binding) };
+        bindings.push(match field.ident {
+            Some(ref ident) => quote!(#ident: #pattern),
+            None => pattern,
+        });
+        if !skipped {
+            hashes.push(call(quote!(#binding)));
+        }
+    }
+    let pattern = match *fields {
+        Fields::Named(_) => quote!({ #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(( #(#bindings),* )),
+        Fields::Unit => quote!(),
+    };
+    Ok((pattern, quote!(#(#hashes)*)))
+}
+
+// Checks for `#[mt(skip)]`
+fn is_skipped(field: &Field) -> syn::Result<bool> {
+    let mut skip = false;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("mt")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                skip = true;
+                Ok(())
+            } else {
+                Err(meta.error("unsupported mt attribute"))
+            }
+        })?;
+    }
+    Ok(skip)
+}
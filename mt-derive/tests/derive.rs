@@ -0,0 +1,79 @@
+extern crate mt;
+#[macro_use]
+extern crate mt_derive;
+
+use mt::abc::MTAlgorithm;
+use mt::abc::MTHash;
+use mt::fun::sha256::Sha256;
+
+
+#[derive(Debug, Clone, PartialEq, Eq, MTHash)]
+struct Record {
+    id: u64,
+    name: String,
+    #[mt(skip)]
+    comment: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, MTHash)]
+struct Pair(u8, Option<u16>);
+
+#[derive(Debug, Clone, PartialEq, Eq, MTHash)]
+struct Unit;
+
+#[derive(Debug, Clone, PartialEq, Eq, MTHash)]
+struct Wrapper<T> {
+    inner: Vec<T>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, MTHash)]
+enum Event {
+    Created,
+    Renamed(String),
+    Moved { from: u32, to: u32, #[mt(skip)] reason: String },
+}
+
+
+fn hash<V: MTHash>(value: &V) -> mt::fun::sha256::Sha256Value {
+    Sha256::eval_hash(value)
+}
+
+#[test]
+fn struct_fields_are_hashed_in_order() {
+    let record = Record { id: 1, name: "one".to_string(), comment: "first".to_string() };
+    assert_eq!(hash(&record), hash(&(1u64, "one")));
+
+    let pair = Pair(1, Some(2));
+    assert_eq!(hash(&pair), hash(&(1u8, Some(2u16))));
+
+    assert_eq!(hash(&Unit), Sha256::eval_hash(&&b""[..]));
+
+    let wrapper = Wrapper { inner: vec![1u8, 2, 3] };
+    assert_eq!(hash(&wrapper), hash(&vec![1u8, 2, 3]));
+}
+
+#[test]
+fn skipped_fields_do_not_affect_hash() {
+    let a = Record { id: 1, name: "one".to_string(), comment: "first".to_string() };
+    let b = Record { comment: "second".to_string(), .. a.clone() };
+    let c = Record { id: 2, .. a.clone() };
+    assert_eq!(hash(&a), hash(&b));
+    assert!(hash(&a) != hash(&c));
+}
+
+#[test]
+fn enum_variants_are_prefixed_with_index() {
+    assert_eq!(hash(&Event::Created), hash(&0u32));
+    assert_eq!(hash(&Event::Renamed("x".to_string())), hash(&(1u32, "x")));
+    let moved = Event::Moved { from: 3, to: 4, reason: "cleanup".to_string() };
+    assert_eq!(hash(&moved), hash(&(2u32, 3u32, 4u32)));
+    assert!(hash(&Event::Renamed(String::new())) != hash(&Event::Created));
+}
+
+#[test]
+fn try_hash_is_derived() {
+    let record = Record { id: 1, name: "one".to_string(), comment: "first".to_string() };
+    assert_eq!(Sha256::try_eval_hash(&record).unwrap(), hash(&record));
+    let moved = Event::Moved { from: 3, to: 4, reason: "cleanup".to_string() };
+    assert_eq!(Sha256::try_eval_hash(&moved).unwrap(), hash(&moved));
+}
@@ -0,0 +1,248 @@
+//! A least-recently-used cache, used by caching wrappers of storages.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+use prelude::*;
+
+
+/// The bound of a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheLimit {
+    /// The number of entries
+    Entries(usize),
+    /// The total weight of values, as measured by the weigher of the cache
+    /// (by default, the length of their encoding, see `encoded_len`)
+    Bytes(usize),
+}
+
+/// Counters of a cache
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// The number of entries
+    pub len: usize,
+    /// The total weight of entries (the same as `len`, if the cache is bounded by entries)
+    pub weight: usize,
+}
+
+
+/// A cache, which evicts least recently used entries, when it is over the limit
+pub struct LruCache<K, V> where K: Hash + Eq + Clone {
+    limit: CacheLimit,
+    weigh: fn(&V) -> usize,
+    // Values with the time of last use and the weight, as it was at insertion
+    entries: HashMap<K, (V, u64, usize)>,
+    // Keys by the time of last use
+    order: BTreeMap<u64, K>,
+    time: u64,
+    stats: CacheStats,
+}
+
+impl <K, V> LruCache<K, V> where K: Hash + Eq + Clone, V: MTHash {
+    /// Creates a cache; values are weighed by the length of their encoding
+    pub fn new(limit: CacheLimit) -> Self {
+        LruCache::with_weigher(limit, encoded_len)
+    }
+}
+
+impl <K, V> LruCache<K, V> where K: Hash + Eq + Clone, V: Clone {
+
+    /// Creates a cache, which weighs values with the function (used if bounded by bytes)
+    pub fn with_weigher(limit: CacheLimit, weigh: fn(&V) -> usize) -> Self {
+        LruCache {
+            limit,
+            weigh,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            time: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    /// Returns the bound of the cache
+    pub fn limit(&self) -> CacheLimit {
+        self.limit
+    }
+
+    /// Returns counters of the cache
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { len: self.entries.len(), .. self.stats }
+    }
+
+    /// Returns a copy of the value and marks it as used, or None if it is not cached
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        self.time += 1;
+        match self.entries.get_mut(key) {
+            Some(&mut (ref value, ref mut time, _)) => {
+                self.order.remove(time);
+                *time = self.time;
+                self.order.insert(self.time, key.clone());
+                self.stats.hits += 1;
+                Some(value.clone())
+            },
+            None => {
+                self.stats.misses += 1;
+                None
+            },
+        }
+    }
+
+    /// Inserts or replaces the value, then evicts entries over the limit
+    pub fn insert(&mut self, key: K, value: V) {
+        self.remove(&key);
+        self.time += 1;
+        let weight = self.weight_of(&value);
+        self.stats.weight += weight;
+        self.order.insert(self.time, key.clone());
+        self.entries.insert(key, (value, self.time, weight));
+        while self.is_over_limit() {
+            let (&time, _) = self.order.iter().next().expect("Cache is not empty");
+            let key = self.order.remove(&time).expect("Key exists");
+            self.remove(&key);
+        }
+    }
+
+    /// Removes the value, returns it
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (value, time, weight) = self.entries.remove(key)?;
+        self.order.remove(&time);
+        self.stats.weight -= weight;
+        Some(value)
+    }
+
+    /// Removes all values which match the predicate
+    pub fn retain<F: Fn(&K) -> bool>(&mut self, keep: F) {
+        let keys: Vec<K> = self.entries.keys().filter(|key| !keep(key)).cloned().collect();
+        for key in keys {
+            self.remove(&key);
+        }
+    }
+
+    /// Removes all values; counters of hits and misses are kept
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.stats.weight = 0;
+    }
+
+    fn weight_of(&self, value: &V) -> usize {
+        match self.limit {
+            CacheLimit::Entries(_) => 1,
+            CacheLimit::Bytes(_) => (self.weigh)(value),
+        }
+    }
+
+    fn is_over_limit(&self) -> bool {
+        match self.limit {
+            CacheLimit::Entries(limit) | CacheLimit::Bytes(limit) => self.stats.weight > limit,
+        }
+    }
+}
+
+/// Returns the number of bytes, which the value is hashed from.
+/// Unlike the stack size, that includes heap buffers of blocks, such as `Vec<u8>` or `String`.
+/// A value, which fails to be read, weighs as much as has been read.
+///
+/// The value is hashed to be weighed, so a value streamed from a file (`DirFile`, `ReadRegion`)
+/// is read whole; use `LruCache::with_weigher` to weigh such values by something cheaper
+pub fn encoded_len<V: MTHash>(value: &V) -> usize {
+    let mut counter = Counter(0);
+    let _ = value.try_hash(&mut counter);
+    counter.0
+}
+
+// A context, which only counts bytes
+struct Counter(usize);
+
+impl MTContext for Counter {
+    type Out = usize;
+
+    fn new() -> Self {
+        Counter(0)
+    }
+
+    fn update(&mut self, msg: &[u8]) {
+        self.0 += msg.len();
+    }
+
+    fn finish(self) -> usize {
+        self.0
+    }
+}
+
+
+impl <K, V> fmt::Debug for LruCache<K, V> where K: Hash + Eq + Clone {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "LruCache(limit={:?}, len={})", self.limit, self.entries.len())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lru_cache_evicts_least_recently_used() {
+        let mut cache = LruCache::new(CacheLimit::Entries(2));
+        cache.insert(1, "one");
+        cache.insert(2, "two");
+        assert_eq!(cache.get(&1), Some("one"));
+        cache.insert(3, "three");
+        assert_eq!(cache.get(&2), None);
+        assert_eq!(cache.get(&1), Some("one"));
+        assert_eq!(cache.get(&3), Some("three"));
+        cache.insert(3, "tres");
+        assert_eq!(cache.get(&3), Some("tres"));
+        assert_eq!(cache.stats(), CacheStats { hits: 4, misses: 1, len: 2, weight: 2 });
+    }
+
+    #[test]
+    fn lru_cache_is_bounded_by_bytes() {
+        let mut cache: LruCache<u32, Vec<u8>> = LruCache::with_weigher(CacheLimit::Bytes(10), Vec::len);
+        cache.insert(1, vec![0; 4]);
+        cache.insert(2, vec![0; 4]);
+        cache.insert(3, vec![0; 4]);
+        assert_eq!(cache.stats().weight, 8);
+        assert_eq!(cache.get(&1), None);
+        cache.insert(4, vec![0; 20]);
+        assert_eq!(cache.stats().len, 0);
+        cache.insert(5, vec![0; 10]);
+        assert_eq!(cache.remove(&5), Some(vec![0; 10]));
+        assert_eq!(cache.stats().weight, 0);
+    }
+
+    #[test]
+    fn lru_cache_keeps_weights_of_changed_values() {
+        use std::cell::Cell;
+        use std::rc::Rc;
+
+        // The weight of a value, which refers to a shared resource, changes after insertion
+        let mut cache: LruCache<u32, Rc<Cell<usize>>> = LruCache::with_weigher(CacheLimit::Bytes(10), |value| value.get());
+        let value = Rc::new(Cell::new(4));
+        cache.insert(1, value.clone());
+        value.set(100);
+        cache.insert(2, Rc::new(Cell::new(8)));
+        assert_eq!(cache.stats(), CacheStats { hits: 0, misses: 0, len: 1, weight: 8 });
+        value.set(0);
+        cache.insert(1, value.clone());
+        cache.remove(&2);
+        assert_eq!(cache.stats().weight, 0);
+    }
+
+    #[test]
+    fn lru_cache_weighs_heap_values() {
+        let mut cache: LruCache<u32, Vec<u8>> = LruCache::new(CacheLimit::Bytes(5000));
+        cache.insert(1, vec![0; 2000]);
+        cache.insert(2, vec![0; 2000]);
+        assert!(cache.stats().weight >= 4000);
+        cache.insert(3, vec![0; 2000]);
+        assert_eq!(cache.stats().len, 2);
+        assert_eq!(cache.get(&1), None);
+        assert!(encoded_len(&"abc") >= 3);
+    }
+}
@@ -0,0 +1,614 @@
+//! Storages, which keep data blocks together with the tree of hashes, so they can not drift apart.
+//!
+//! `MerkleTree` owns its data storage and its tree storage as two separate values.
+//! `CombinedStorage` is kept in memory: it is split into the in-memory storages, when the tree
+//! is opened, is put together by `into_combined()` and is always saved as a whole.
+//! `SqliteCombinedStorage` (the `sqlite` feature) is a handle to one SQLite database, which is
+//! both a data storage and a tree storage: the tree gets two clones of the handle, which share
+//! the connection and write data blocks and nodes in one transaction.
+
+use std::fmt;
+
+use data_storage::memory::MemoryDataStorage;
+use merkle_tree::MerkleTree;
+use prelude::*;
+use tree_storage::memory::MemoryTreeStorage;
+
+
+/// Data blocks and levels of the tree of hashes in one storage
+pub struct CombinedStorage<V, A> where V: MTHash, A: MTAlgorithm {
+    data: Vec<V>,
+    // Levels of the tree, from the bottom level to the root
+    layers: Vec<Vec<A::Value>>,
+}
+
+impl <V, A> Default for CombinedStorage<V, A> where V: MTHash, A: MTAlgorithm {
+    fn default() -> Self {
+        CombinedStorage { data: Vec::new(), layers: Vec::new() }
+    }
+}
+
+impl <V, A> CombinedStorage<V, A> where V: MTHash, A: MTAlgorithm {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns data blocks
+    pub fn data(&self) -> &[V] {
+        &self.data
+    }
+
+    /// Returns levels of the tree, from the bottom level to the root
+    pub fn layers(&self) -> &[Vec<A::Value>] {
+        &self.layers
+    }
+}
+
+impl <V, A> fmt::Debug for CombinedStorage<V, A> where V: MTHash, A: MTAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CombinedStorage(data={}, levels={})", self.data.len(), self.layers.len())
+    }
+}
+
+/// Serialized as a tuple of data blocks and levels of the tree
+#[cfg(feature = "serde")]
+impl <V, A> ::serde::Serialize for CombinedStorage<V, A>
+    where V: MTHash + ::serde::Serialize, A: MTAlgorithm, A::Value: ::serde::Serialize
+{
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serde::Serialize::serialize(&(&self.data, &self.layers), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de, V, A> ::serde::Deserialize<'de> for CombinedStorage<V, A>
+    where V: MTHash + ::serde::Deserialize<'de>, A: MTAlgorithm, A::Value: ::serde::Deserialize<'de>
+{
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let (data, layers) = ::serde::Deserialize::deserialize(deserializer)?;
+        Ok(CombinedStorage { data, layers })
+    }
+}
+
+#[cfg(feature = "json")]
+impl <V, A> CombinedStorage<V, A>
+    where V: MTHash + ::serde::Serialize + ::serde::de::DeserializeOwned,
+          A: MTAlgorithm, A::Value: ::serde::Serialize + ::serde::de::DeserializeOwned
+{
+    /// Reads the storage from a JSON file
+    pub fn open<P: AsRef<::std::path::Path>>(path: P) -> Result<Self> {
+        let file = ::std::fs::File::open(path)?;
+        let storage = ::serde_json::from_reader(::std::io::BufReader::new(file)).map_err(::std::io::Error::from)?;
+        Ok(storage)
+    }
+
+    /// Writes the storage to a JSON file. The file is replaced at once,
+    /// so it always has data and hashes of the same state
+    pub fn save<P: AsRef<::std::path::Path>>(&self, path: P) -> Result<()> {
+        save(path.as_ref(), &self.data, &self.layers)
+    }
+}
+
+// Writes data blocks and levels to a temporary file, which then replaces the file
+#[cfg(feature = "json")]
+fn save<V, T>(path: &::std::path::Path, data: &[V], layers: &[Vec<T>]) -> Result<()>
+    where V: ::serde::Serialize, T: ::serde::Serialize
+{
+    use std::io::Write;
+
+    let mut name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+    name.push(".tmp");
+    let temporary = path.with_file_name(name);
+    {
+        let mut file = ::std::fs::File::create(&temporary)?;
+        ::serde_json::to_writer(&mut file, &(data, layers)).map_err(::std::io::Error::from)?;
+        file.flush()?;
+        file.sync_all()?;
+    }
+    ::std::fs::rename(&temporary, path)?;
+    sync_directory(path)
+}
+
+// Makes renaming of the file durable
+#[cfg(all(feature = "json", unix))]
+fn sync_directory(path: &::std::path::Path) -> Result<()> {
+    let directory = match path.parent() {
+        Some(parent) if parent != ::std::path::Path::new("") => parent,
+        _ => ::std::path::Path::new("."),
+    };
+    ::std::fs::File::open(directory)?.sync_all()?;
+    Ok(())
+}
+
+// Directories can not be opened as files on other platforms
+#[cfg(all(feature = "json", not(unix)))]
+fn sync_directory(_path: &::std::path::Path) -> Result<()> {
+    Ok(())
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+impl <V, A> MerkleTree<MemoryDataStorage<V>, MemoryTreeStorage<A>> where V: MTHash, A: MTAlgorithm {
+    /// Creates an instance over the combined storage and checks the data and the tree
+    pub fn with_combined(storage: CombinedStorage<V, A>) -> Result<Self> {
+        let CombinedStorage { data, layers } = storage;
+        MerkleTree::new_and_check(MemoryDataStorage::with_data(data), MemoryTreeStorage::with_levels(layers))
+    }
+
+    /// Returns the combined storage, consuming the tree
+    pub fn into_combined(self) -> CombinedStorage<V, A> {
+        let (data, tree) = self.into_parts();
+        CombinedStorage { data: data.into_data(), layers: tree.into_levels() }
+    }
+}
+
+#[cfg(feature = "json")]
+impl <V, A> MerkleTree<MemoryDataStorage<V>, MemoryTreeStorage<A>>
+    where V: MTHash + ::serde::Serialize, A: MTAlgorithm, A::Value: ::serde::Serialize
+{
+    /// Writes data blocks and the tree to a JSON file, as `CombinedStorage::save()` does
+    pub fn save_combined<P: AsRef<::std::path::Path>>(&self, path: P) -> Result<()> {
+        save(path.as_ref(), self.data().data(), self.tree().levels())
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteCombinedStorage;
+
+#[cfg(feature = "sqlite")]
+mod sqlite {
+    use std::fmt;
+    use std::marker::PhantomData;
+    use std::path::Path;
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    use rusqlite::Connection;
+    use rusqlite::NO_PARAMS;
+    use rusqlite::OptionalExtension;
+    use rusqlite::types::{FromSql, ToSql};
+
+    use data_storage::sqlite::open_data_table;
+    use tree_storage::sqlite::open_tree_table;
+    use super::*;
+
+    // The state, shared by both views of the storage
+    struct Shared {
+        connection: Connection,
+        // Quoted names of the tables
+        data_table: String,
+        tree_table: String,
+        data_len: usize,
+        levels: Vec<usize>,
+        // The number of blocks and widths of levels before the open transaction
+        begun: Option<(usize, Vec<usize>)>,
+    }
+
+    impl Shared {
+        // Opens the transaction, if it is not open yet
+        fn begin(&mut self) -> Result<()> {
+            if self.begun.is_none() {
+                self.connection.execute_batch("BEGIN")?;
+                self.begun = Some((self.data_len, self.levels.clone()));
+            }
+            Ok(())
+        }
+    }
+
+    /// Data blocks and the tree of hashes in one SQLite database, behind one connection.
+    /// The storage is a handle: `MerkleTree::with_sqlite()` uses one clone of it
+    /// as the data storage and another one as the tree storage.
+    ///
+    /// The first write to either of them opens a transaction, which is committed
+    /// by `TreeStorage::commit`, so `MerkleTree` writes data blocks and their hashes at once.
+    /// Writes to `data_mut()` outside of `MerkleTree` are committed with the next change of the tree
+    /// and are rolled back, if the storage is closed before that.
+    ///
+    /// Both traits have methods with the same names, so they are called with the trait:
+    /// `DataStorageReadonly::len(mt.data())`
+    pub struct SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        shared: Arc<Mutex<Shared>>,
+        _marker: PhantomData<(V, A)>,
+    }
+
+    impl <V, A> SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        /// Opens the database file and creates the tables `data` and `tree`, if they do not exist
+        pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+            Self::with_connection(Connection::open(path)?, "data", "tree")
+        }
+
+        /// Creates the tables in the database, if they do not exist.
+        /// They have the same layout, as tables of `SqliteDataStorage` and `SqliteTreeStorage`
+        pub fn with_connection(connection: Connection, data_table: &str, tree_table: &str) -> Result<Self> {
+            let (data_table, data_len) = open_data_table(&connection, data_table)?;
+            let (tree_table, levels) = open_tree_table(&connection, tree_table)?;
+            let shared = Shared { connection, data_table, tree_table, data_len, levels, begun: None };
+            Ok(SqliteCombinedStorage { shared: Arc::new(Mutex::new(shared)), _marker: PhantomData })
+        }
+
+        fn lock(&self) -> Result<MutexGuard<Shared>> {
+            Ok(self.shared.lock().map_err(|_| StateError::InconsistentState)?)
+        }
+
+        fn check_index(&self, level: usize, index: usize) -> Result<()> {
+            match self.lock()?.levels.get(level) {
+                Some(&len) if index < len => Ok(()),
+                _ => Err(INDEX_IS_OUT_OF_BOUNDS),
+            }
+        }
+    }
+
+    /// Clones share the connection and the state
+    impl <V, A> Clone for SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        fn clone(&self) -> Self {
+            SqliteCombinedStorage { shared: self.shared.clone(), _marker: PhantomData }
+        }
+    }
+
+    impl <V, A> fmt::Debug for SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.shared.lock() {
+                Ok(shared) => write!(f, "SqliteCombinedStorage(data={}, levels={:?})", shared.data_len, shared.levels),
+                Err(_) => write!(f, "SqliteCombinedStorage(poisoned)"),
+            }
+        }
+    }
+
+    impl <V, A> DataStorageReadonly for SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        type DataValue = V;
+
+        fn len(&self) -> Result<usize> {
+            Ok(self.lock()?.data_len)
+        }
+
+        fn get(&self, index: usize) -> Result<V> {
+            let shared = self.lock()?;
+            let mut statement = shared.connection.prepare_cached(&format!("SELECT value FROM {} WHERE idx = ?", shared.data_table))?;
+            let value = statement.query_row(&[index as i64], |row| row.get(0)).optional()?;
+            value.ok_or(INDEX_IS_OUT_OF_BOUNDS)
+        }
+
+        fn is_writeable(&self) -> bool {
+            true
+        }
+    }
+
+    /// Every write is a part of the transaction, committed by `TreeStorage::commit`
+    impl <V, A> DataStorage for SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        fn push(&mut self, data: V) -> Result<()> {
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            let index = shared.data_len;
+            {
+                let mut statement = shared.connection.prepare_cached(&format!("INSERT INTO {} (idx, value) VALUES (?, ?)", shared.data_table))?;
+                statement.execute(&[&(index as i64) as &ToSql, &data])?;
+            }
+            shared.data_len += 1;
+            Ok(())
+        }
+
+        /// All the blocks are inserted in one savepoint
+        fn extend<DD: IntoIterator<Item=Result<V>>>(&mut self, data: DD) -> Result<()> {
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            let shared = &mut *shared;
+            let mut len = shared.data_len;
+            let transaction = shared.connection.savepoint()?;
+            {
+                let mut statement = transaction.prepare_cached(&format!("INSERT INTO {} (idx, value) VALUES (?, ?)", shared.data_table))?;
+                for value in data.into_iter() {
+                    statement.execute(&[&(len as i64) as &ToSql, &value?])?;
+                    len += 1;
+                }
+            }
+            transaction.commit()?;
+            shared.data_len = len;
+            Ok(())
+        }
+
+        fn set(&mut self, index: usize, data: V) -> Result<V> {
+            let previous = self.get(index)?;
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            let mut statement = shared.connection.prepare_cached(&format!("UPDATE {} SET value = ? WHERE idx = ?", shared.data_table))?;
+            statement.execute(&[&data as &ToSql, &(index as i64)])?;
+            Ok(previous)
+        }
+
+        fn truncate(&mut self, len: usize) -> Result<()> {
+            let mut shared = self.lock()?;
+            if len < shared.data_len {
+                shared.begin()?;
+                shared.connection.execute(&format!("DELETE FROM {} WHERE idx >= ?", shared.data_table), &[len as i64])?;
+                shared.data_len = len;
+            }
+            Ok(())
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            shared.connection.execute(&format!("DELETE FROM {}", shared.data_table), NO_PARAMS)?;
+            shared.data_len = 0;
+            Ok(())
+        }
+    }
+
+    impl <V, A> TreeStorageReadonly for SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        type Algorithm = A;
+
+        fn len(&self) -> Result<usize> {
+            Ok(self.lock()?.levels.len())
+        }
+
+        fn get_level_len(&self, level: usize) -> Result<usize> {
+            self.lock()?.levels.get(level).cloned().ok_or(INDEX_IS_OUT_OF_BOUNDS)
+        }
+
+        fn get_value(&self, level: usize, index: usize) -> Result<A::Value> {
+            self.check_index(level, index)?;
+            let shared = self.lock()?;
+            let mut statement = shared.connection.prepare_cached(&format!("SELECT hash FROM {} WHERE level = ? AND idx = ?", shared.tree_table))?;
+            let bytes: Vec<u8> = statement.query_row(&[level as i64, index as i64], |row| row.get(0))?;
+            A::Value::from_bytes(&bytes)
+        }
+
+        fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<A::Value>> + 's>> {
+            let len = self.get_level_len(level)?;
+            Ok(Box::new((0 .. len).map(move |index| self.get_value(level, index))))
+        }
+
+        fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<(A::Value, A::Value)>> + 's>> {
+            let len = self.get_level_len(level)?;
+            Ok(Box::new((0 .. (len + 1) / 2).map(move |pair| {
+                let left = self.get_value(level, pair * 2)?;
+                let right = self.get_value(level, pair * 2 + 1).iob_is_ok()?.unwrap_or_else(|| left.clone());
+                Ok((left, right))
+            })))
+        }
+
+        fn is_writeable(&self) -> bool {
+            true
+        }
+    }
+
+    /// Values are in the database, so `get_value_mut` is not supported, `set_value` is.
+    /// `commit` commits the transaction with both data blocks and nodes
+    impl <V, A> TreeStorage for SqliteCombinedStorage<V, A> where V: MTHash + ToSql + FromSql, A: MTAlgorithm {
+        fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            shared.connection.execute(&format!("DELETE FROM {}", shared.tree_table), NO_PARAMS)?;
+            shared.levels = vec![0; sizes.len()];
+            Ok(())
+        }
+
+        fn grow(&mut self) -> Result<()> {
+            self.lock()?.levels.push(0);
+            Ok(())
+        }
+
+        fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            let shared = &mut *shared;
+            let transaction = shared.connection.savepoint()?;
+            transaction.execute(&format!("DELETE FROM {} WHERE level >= ?", shared.tree_table), &[sizes.len() as i64])?;
+            for (level, &size) in sizes.iter().enumerate().take(shared.levels.len()) {
+                transaction.execute(
+                    &format!("DELETE FROM {} WHERE level = ? AND idx >= ?", shared.tree_table),
+                    &[level as i64, size as i64],
+                )?;
+            }
+            transaction.commit()?;
+            shared.levels.truncate(sizes.len());
+            for (len, &size) in shared.levels.iter_mut().zip(sizes) {
+                *len = size.min(*len);
+            }
+            Ok(())
+        }
+
+        fn commit(&mut self) -> Result<()> {
+            let mut shared = self.lock()?;
+            if shared.begun.is_some() {
+                shared.connection.execute_batch("COMMIT")?;
+                shared.begun = None;
+            }
+            Ok(())
+        }
+
+        /// Rolls back both data blocks and nodes, written since the last commit
+        fn rollback(&mut self) -> Result<()> {
+            let mut shared = self.lock()?;
+            if let Some((data_len, levels)) = shared.begun.take() {
+                shared.connection.execute_batch("ROLLBACK")?;
+                shared.data_len = data_len;
+                shared.levels = levels;
+            }
+            Ok(())
+        }
+
+        fn get_value_mut(&mut self, _level: usize, _index: usize) -> Result<&mut A::Value> {
+            Err(AccessError::NotSupported)?
+        }
+
+        fn set_value(&mut self, level: usize, index: usize, value: A::Value) -> Result<()> {
+            self.check_index(level, index)?;
+            let mut shared = self.lock()?;
+            shared.begin()?;
+            let bytes = value.as_bytes();
+            let mut statement = shared.connection.prepare_cached(&format!("UPDATE {} SET hash = ? WHERE level = ? AND idx = ?", shared.tree_table))?;
+            statement.execute(&[&&bytes[..] as &ToSql, &(level as i64), &(index as i64)])?;
+            Ok(())
+        }
+
+        fn push(&mut self, level: usize, value: A::Value) -> Result<()> {
+            let mut shared = self.lock()?;
+            let index = *shared.levels.get(level).ok_or(StateError::InconsistentState)?;
+            shared.begin()?;
+            let bytes = value.as_bytes();
+            {
+                let mut statement = shared.connection.prepare_cached(&format!("INSERT INTO {} (level, idx, hash) VALUES (?, ?, ?)", shared.tree_table))?;
+                statement.execute(&[&(level as i64) as &ToSql, &(index as i64), &&bytes[..]])?;
+            }
+            shared.levels[level] += 1;
+            Ok(())
+        }
+
+        fn extend<I>(&mut self, level: usize, other: I) -> Result<()> where I: IntoIterator<Item=Result<A::Value>> {
+            let mut shared = self.lock()?;
+            let mut index = *shared.levels.get(level).ok_or(StateError::InconsistentState)?;
+            shared.begin()?;
+            let shared = &mut *shared;
+            let transaction = shared.connection.savepoint()?;
+            {
+                let mut statement = transaction.prepare_cached(&format!("INSERT INTO {} (level, idx, hash) VALUES (?, ?, ?)", shared.tree_table))?;
+                for value in other.into_iter() {
+                    let bytes = value?.as_bytes().into_owned();
+                    statement.execute(&[&(level as i64) as &ToSql, &(index as i64), &bytes])?;
+                    index += 1;
+                }
+            }
+            transaction.commit()?;
+            shared.levels[level] = index;
+            Ok(())
+        }
+
+        fn extend_from_slice(&mut self, level: usize, slice: &[A::Value]) -> Result<()> {
+            TreeStorage::extend(self, level, slice.iter().cloned().map(Ok))
+        }
+    }
+
+    impl <V, A> MerkleTree<SqliteCombinedStorage<V, A>, SqliteCombinedStorage<V, A>>
+        where V: MTHash + ToSql + FromSql, A: MTAlgorithm
+    {
+        /// Creates an instance over both views of the storage and checks the data and the tree
+        pub fn with_sqlite(storage: SqliteCombinedStorage<V, A>) -> Result<Self> {
+            MerkleTree::new_and_check(storage.clone(), storage)
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use merkle_tree::Transaction;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    fn expected(data: Vec<u32>) -> Option<Sha256Value> {
+        let mt: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+        mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data), Default::default()).unwrap();
+        mt.get_root().unwrap()
+    }
+
+    #[test]
+    fn combined_storage() {
+        let mut mt = MerkleTree::with_combined(CombinedStorage::<u32, Sha256>::new()).unwrap();
+        for x in 0 .. 10 {
+            mt.push(x).unwrap();
+        }
+        mt.extend((10 .. 20).map(Ok)).unwrap();
+        let mut tx = Transaction::new();
+        tx.push(20).update(3, 30);
+        mt.apply(tx).unwrap();
+
+        let mut data = (0 .. 21).collect::<Vec<_>>();
+        data[3] = 30;
+        assert_eq!(mt.get_root().unwrap(), expected(data.clone()));
+
+        let root = mt.get_root().unwrap();
+        let storage = mt.into_combined();
+        assert_eq!(storage.data(), &data[..]);
+        assert_eq!(storage.layers()[0].len(), 21);
+        let mut mt = MerkleTree::with_combined(storage).unwrap();
+        assert_eq!(mt.get_root().unwrap(), root);
+        mt.rebuild().unwrap();
+        assert_eq!(mt.get_root().unwrap(), root);
+
+        // The tree can be moved to another thread
+        let mt = ::std::thread::spawn(move || mt).join().unwrap();
+
+        let mut storage = mt.into_combined();
+        storage.data[0] = 100;
+        assert!(MerkleTree::with_combined(storage).is_err());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn combined_storage_file() {
+        use tempfile;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.json");
+        let mut mt = MerkleTree::with_combined(CombinedStorage::<u32, Sha256>::new()).unwrap();
+        mt.extend((0 .. 10).map(Ok)).unwrap();
+        mt.save_combined(&path).unwrap();
+
+        let mt2 = MerkleTree::with_combined(CombinedStorage::<u32, Sha256>::open(&path).unwrap()).unwrap();
+        assert_eq!(mt.get_root().unwrap(), mt2.get_root().unwrap());
+        assert!(!dir.path().join("tree.json.tmp").exists());
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn sqlite_combined_storage() {
+        use data_storage::sqlite::SqliteDataStorage;
+        use tempfile;
+        use tree_storage::sqlite::SqliteTreeStorage;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.sqlite");
+        let open = || MerkleTree::with_sqlite(SqliteCombinedStorage::<u32, Sha256>::open(&path).unwrap()).unwrap();
+
+        let mut mt = open();
+        for x in 0 .. 10 {
+            mt.push(x).unwrap();
+        }
+        mt.extend((10 .. 20).map(Ok)).unwrap();
+        let mut tx = Transaction::new();
+        tx.push(20).update(3, 30);
+        mt.apply(tx).unwrap();
+        mt.rebuild().unwrap();
+
+        let mut data = (0 .. 21).collect::<Vec<_>>();
+        data[3] = 30;
+        let root = mt.get_root().unwrap();
+        assert_eq!(root, expected(data.clone()));
+
+        // The tree can be moved to another thread
+        let mut mt = ::std::thread::spawn(move || mt).join().unwrap();
+
+        // A block, written past the tree, is not committed without its hash
+        DataStorage::push(mt.data_mut(), 21).unwrap();
+        assert_eq!(SqliteDataStorage::<u32>::open(&path, "data").unwrap().len().unwrap(), 21);
+        drop(mt);
+
+        // The tables are the same, as of the separate storages
+        let separate = MerkleTree::new_and_check(
+            SqliteDataStorage::<u32>::open(&path, "data").unwrap(),
+            SqliteTreeStorage::<Sha256>::open(&path, "tree").unwrap(),
+        ).unwrap();
+        assert_eq!(separate.get_root().unwrap(), root);
+        drop(separate);
+
+        let mut mt = open();
+        assert_eq!(mt.get_root().unwrap(), root);
+        assert_eq!(mt.data().iter().unwrap().collect::<Result<Vec<_>>>().unwrap(), data);
+
+        // Both data blocks and nodes are rolled back at once
+        DataStorage::push(mt.data_mut(), 21).unwrap();
+        TreeStorage::push(mt.tree_mut(), 0, Sha256::eval_hash(&21u32)).unwrap();
+        mt.tree_mut().rollback().unwrap();
+        assert_eq!(DataStorageReadonly::len(mt.data()).unwrap(), 21);
+        mt.check_tree().unwrap();
+        mt.check_data().unwrap();
+    }
+}
@@ -27,6 +27,22 @@ pub trait DataStorageReadonly: fmt::Debug {
             .map( move |index| self.get(index) )
         ))
     }
+
+    /// Calls `f` with a reference to an item at index, or returns error, if index out of bounds.
+    /// Storages, which keep items in memory, should override it to lend the item without cloning
+    fn with_value<R, F: FnOnce(&Self::DataValue) -> Result<R>>(&self, index: usize, f: F) -> Result<R> {
+        f(&self.get(index)?)
+    }
+
+    /// Calls `f` with a reference to every item in order, stops at the first error.
+    /// Storages, which keep items in memory, should override it to lend items without cloning
+    fn for_each_value<F: FnMut(&Self::DataValue) -> Result<()>>(&self, mut f: F) -> Result<()> {
+        for value in self.iter()? {
+            f(&value?)?;
+        }
+        Ok(())
+    }
+
     /// Checks if a range is within bounds.
     /// Should be reused to check ranges by `Self::range()` implamentations
     fn check_range(&self, range: &ops::Range<usize>) -> Result<()> {
@@ -83,6 +99,18 @@ pub trait DataStorage: DataStorageReadonly {
     /// Appends items to the back of the collection
     fn extend<DD: IntoIterator<Item=Result<Self::DataValue>>>(&mut self, data: DD) -> Result<()>;
 
+    /// Replaces an item at index, returns the previous one.
+    /// Needed by `MerkleTree::update()` and transactions; append-only storages may keep the default
+    fn set(&mut self, _index: usize, _data: Self::DataValue) -> Result<Self::DataValue> {
+        Err(AccessError::NotSupported)?
+    }
+
+    /// Shortens the collection to `len` items; does nothing if it is not longer.
+    /// Needed to roll transactions back; append-only storages may keep the default
+    fn truncate(&mut self, _len: usize) -> Result<()> {
+        Err(AccessError::NotSupported)?
+    }
+
     /// Clears all data
     fn clear(&mut self) -> Result<()>;
 }
@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::ops;
+
+use cache::{CacheLimit, CacheStats, LruCache};
+use prelude::*;
+
+
+/// A wrapper over a slow data storage, which keeps recently read data blocks in memory.
+/// Writes go straight to the storage
+pub struct CachedDataStorage<D> where D: DataStorageReadonly {
+    inner: D,
+    cache: RefCell<LruCache<usize, D::DataValue>>,
+}
+
+impl <D> CachedDataStorage<D> where D: DataStorageReadonly {
+    /// Creates a wrapper; if bounded by bytes, data blocks are weighed by the length of their encoding
+    /// (streamed blocks are read whole for that, see `encoded_len`)
+    pub fn new(inner: D, limit: CacheLimit) -> Self {
+        CachedDataStorage { inner, cache: RefCell::new(LruCache::new(limit)) }
+    }
+
+    /// Creates a wrapper, which weighs data blocks with the function
+    pub fn with_weigher(inner: D, limit: CacheLimit, weigh: fn(&D::DataValue) -> usize) -> Self {
+        CachedDataStorage { inner, cache: RefCell::new(LruCache::with_weigher(limit, weigh)) }
+    }
+
+    /// Returns the wrapped storage
+    pub fn inner(&self) -> &D {
+        &self.inner
+    }
+
+    /// Returns the wrapped storage, dropping the cache
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    /// Returns counters of the cache
+    pub fn stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+}
+
+impl <D> fmt::Debug for CachedDataStorage<D> where D: DataStorageReadonly {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CachedDataStorage({:?}, {:?})", self.inner, self.cache.borrow())
+    }
+}
+
+/// Iterators go straight to the storage, not to spoil the cache
+impl <D> DataStorageReadonly for CachedDataStorage<D> where D: DataStorageReadonly {
+    type DataValue = D::DataValue;
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn get(&self, index: usize) -> Result<Self::DataValue> {
+        if let Some(value) = self.cache.borrow_mut().get(&index) {
+            return Ok(value);
+        }
+        let value = self.inner.get(index)?;
+        self.cache.borrow_mut().insert(index, value.clone());
+        Ok(value)
+    }
+
+    fn iter<'s: 'i, 'i>(&'s self) -> Result<Box<Iterator<Item=Result<Self::DataValue>> + 'i>> {
+        self.inner.iter()
+    }
+
+    fn for_each_value<F: FnMut(&Self::DataValue) -> Result<()>>(&self, f: F) -> Result<()> {
+        self.inner.for_each_value(f)
+    }
+
+    fn range<'s: 'i, 'i, R: Into<ops::Range<usize>>>(&'s self, range: R) -> Result<Box<Iterator<Item=Result<Self::DataValue>> + 'i>> {
+        self.inner.range(range)
+    }
+
+    fn is_writeable(&self) -> bool {
+        self.inner.is_writeable()
+    }
+}
+
+impl <D> DataStorage for CachedDataStorage<D> where D: DataStorage {
+    fn push(&mut self, data: Self::DataValue) -> Result<()> {
+        self.inner.push(data)
+    }
+
+    fn extend<DD: IntoIterator<Item=Result<Self::DataValue>>>(&mut self, data: DD) -> Result<()> {
+        self.inner.extend(data)
+    }
+
+    fn set(&mut self, index: usize, data: Self::DataValue) -> Result<Self::DataValue> {
+        self.cache.borrow_mut().remove(&index);
+        self.inner.set(index, data)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        self.cache.borrow_mut().retain(|&index| index < len);
+        self.inner.truncate(len)
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.cache.borrow_mut().clear();
+        self.inner.clear()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use super::*;
+
+    #[test]
+    fn cached_data_storage() {
+        let mut ds = CachedDataStorage::new(MemoryDataStorage::with_data(vec![1u32, 2, 3]), CacheLimit::Entries(2));
+        assert_eq!(ds.get(0).unwrap(), 1);
+        assert_eq!(ds.get(0).unwrap(), 1);
+        assert_eq!(ds.stats().hits, 1);
+        ds.set(0, 10).unwrap();
+        assert_eq!(ds.get(0).unwrap(), 10);
+        ds.truncate(0).unwrap();
+        ds.push(20).unwrap();
+        assert_eq!(ds.get(0).unwrap(), 20);
+        assert!(ds.get(1).is_err());
+        assert_eq!(ds.stats().len, 1);
+    }
+
+    #[test]
+    fn cached_data_storage_weighs_heap_blocks() {
+        let data = (0 .. 10u8).map(|x| vec![x; 1024]).collect::<Vec<_>>();
+        let ds = CachedDataStorage::new(MemoryDataStorage::with_data(data), CacheLimit::Bytes(4 * 1024 + 100));
+        for index in 0 .. 10 {
+            assert_eq!(ds.get(index).unwrap(), vec![index as u8; 1024]);
+        }
+        let stats = ds.stats();
+        assert_eq!(stats.len, 4);
+        assert!(stats.weight <= 4 * 1024 + 100);
+    }
+}
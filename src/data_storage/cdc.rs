@@ -0,0 +1,334 @@
+//! Content-defined chunking of files and byte streams.
+//!
+//! Chunk boundaries are chosen by the content (FastCDC with normalized chunking),
+//! not by fixed offsets, so an insertion or a deletion changes only the chunks around it,
+//! and two versions of a file share most of the leaves.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::ops;
+use std::path::Path;
+use std::rc::Rc;
+
+use fun::stream::ReadRegion;
+use prelude::*;
+
+
+/// Sizes of chunks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CdcParams {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+}
+
+impl CdcParams {
+    /// Creates parameters of chunking.
+    /// Any chunk but the last is at least `min_size` and at most `max_size` bytes long,
+    /// the average size tends to `avg_size` (rounded down to a power of 2)
+    ///
+    /// # Panics
+    ///
+    /// Panics if sizes are not ordered as `0 < min_size <= avg_size <= max_size`
+    pub fn new(min_size: usize, avg_size: usize, max_size: usize) -> Self {
+        assert!(0 < min_size && min_size <= avg_size && avg_size <= max_size, "Invalid chunk sizes");
+        CdcParams { min_size, avg_size, max_size }
+    }
+
+    pub fn min_size(&self) -> usize {
+        self.min_size
+    }
+
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+}
+
+/// 2 KiB / 8 KiB / 64 KiB
+impl Default for CdcParams {
+    fn default() -> Self {
+        CdcParams::new(2048, 8192, 65536)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Finds chunk boundaries in a buffer
+#[derive(Debug, Clone)]
+pub struct Chunker {
+    params: CdcParams,
+    // harder to match, used below the average size
+    mask_s: u64,
+    // easier to match, used above the average size
+    mask_l: u64,
+}
+
+impl Chunker {
+    pub fn new(params: CdcParams) -> Self {
+        let bits = (0usize.leading_zeros() - params.avg_size.leading_zeros() - 1) as u32;
+        Chunker {
+            params,
+            mask_s: high_bits_mask(bits + 1),
+            mask_l: high_bits_mask(bits.saturating_sub(1)),
+        }
+    }
+
+    pub fn params(&self) -> &CdcParams {
+        &self.params
+    }
+
+    /// Returns the length of the first chunk in `data`.
+    /// `data` has to contain at least `max_size` bytes, unless it is the tail of the stream
+    pub fn cut(&self, data: &[u8]) -> usize {
+        let len = data.len();
+        if len <= self.params.min_size {
+            return len;
+        }
+        let end = len.min(self.params.max_size);
+        let normal = end.min(self.params.avg_size);
+        let table = gear_table();
+
+        let mut hash = 0u64;
+        for i in self.params.min_size .. normal {
+            hash = (hash << 1).wrapping_add(table[data[i] as usize]);
+            if hash & self.mask_s == 0 {
+                return i + 1;
+            }
+        }
+        for i in normal .. end {
+            hash = (hash << 1).wrapping_add(table[data[i] as usize]);
+            if hash & self.mask_l == 0 {
+                return i + 1;
+            }
+        }
+        end
+    }
+
+    /// Reads the stream to the end and returns the end offsets of all chunks
+    pub fn boundaries<R: Read>(&self, reader: &mut R) -> Result<Vec<u64>> {
+        let max_size = self.params.max_size;
+        let mut boundaries = Vec::new();
+        let mut buf = vec![0u8; max_size * 2];
+        let mut start = 0;
+        let mut end = 0;
+        let mut offset = 0u64;
+        let mut eof = false;
+
+        loop {
+            // Keep at least `max_size` bytes ahead, unless the stream is over
+            if !eof && end - start < max_size {
+                buf.copy_within(start .. end, 0);
+                end -= start;
+                start = 0;
+                while end < buf.len() {
+                    match reader.read(&mut buf[end ..]) {
+                        Ok(0) => {
+                            eof = true;
+                            break;
+                        },
+                        Ok(n) => end += n,
+                        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        Err(e) => Err(e)?,
+                    }
+                }
+            }
+            if start == end {
+                return Ok(boundaries);
+            }
+            let len = self.cut(&buf[start .. end]);
+            start += len;
+            offset += len as u64;
+            boundaries.push(offset);
+        }
+    }
+}
+
+// A mask of `bits` highest bits; in the gear hash high bits depend on more preceding bytes
+fn high_bits_mask(bits: u32) -> u64 {
+    match bits {
+        0 => 0,
+        bits => !0u64 << (64 - bits.min(64)),
+    }
+}
+
+// Random values for the gear hash; generated by splitmix64, so they never change
+fn gear_table() -> &'static [u64; 256] {
+    static TABLE: [u64; 256] = {
+        let mut table = [0u64; 256];
+        let mut state = 0x6d74_2d72_735f_6364u64;
+        let mut i = 0;
+        while i < 256 {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            table[i] = z ^ (z >> 31);
+            i += 1;
+        }
+        table
+    };
+    &TABLE
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A data storage over a file or a seekable byte stream, split into content-defined chunks.
+/// Chunks are read from the source when they are hashed
+pub struct CdcDataStorage<R> where R: Read + Seek {
+    source: Rc<RefCell<R>>,
+    params: CdcParams,
+    boundaries: Vec<u64>,
+}
+
+impl <R> CdcDataStorage<R> where R: Read + Seek {
+    /// Reads the source once to find chunk boundaries
+    pub fn new(mut source: R, params: CdcParams) -> Result<Self> {
+        source.seek(io::SeekFrom::Start(0))?;
+        let boundaries = Chunker::new(params).boundaries(&mut source)?;
+        Ok(CdcDataStorage { source: Rc::new(RefCell::new(source)), params, boundaries })
+    }
+
+    pub fn params(&self) -> &CdcParams {
+        &self.params
+    }
+
+    /// Returns the end offsets of all chunks
+    pub fn boundaries(&self) -> &[u64] {
+        &self.boundaries
+    }
+
+    /// Returns the byte range of the chunk at index
+    pub fn chunk_range(&self, index: usize) -> Result<ops::Range<u64>> {
+        let end = *self.boundaries.get(index).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        let start = match index {
+            0 => 0,
+            index => self.boundaries[index - 1],
+        };
+        Ok(start .. end)
+    }
+
+    /// Returns the total length of the source in bytes
+    pub fn source_len(&self) -> u64 {
+        self.boundaries.last().cloned().unwrap_or(0)
+    }
+}
+
+impl CdcDataStorage<File> {
+    /// Opens a file and splits it into chunks
+    pub fn open<P: AsRef<Path>>(path: P, params: CdcParams) -> Result<Self> {
+        CdcDataStorage::new(File::open(path)?, params)
+    }
+}
+
+impl <R> fmt::Debug for CdcDataStorage<R> where R: Read + Seek {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CdcDataStorage{{params: {:?}, boundaries: {:?}}}", self.params, self.boundaries)
+    }
+}
+
+impl <R> DataStorageReadonly for CdcDataStorage<R> where R: Read + Seek {
+    type DataValue = ReadRegion<R>;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.boundaries.len())
+    }
+
+    fn get(&self, index: usize) -> Result<Self::DataValue> {
+        let range = self.chunk_range(index)?;
+        Ok(ReadRegion::new(self.source.clone(), range.start, range.end - range.start))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use fun::sha256::*;
+    use merkle_tree::MerkleTree;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    fn random_bytes(len: usize, mut seed: u64) -> Vec<u8> {
+        (0 .. len).map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed as u8
+        }).collect()
+    }
+
+    fn hashes(storage: &CdcDataStorage<Cursor<Vec<u8>>>) -> Vec<Sha256Value> {
+        storage.iter().unwrap().map(|chunk| Sha256::try_eval_hash(&chunk.unwrap()).unwrap()).collect()
+    }
+
+    #[test]
+    fn chunk_sizes_are_bounded() {
+        let params = CdcParams::new(256, 1024, 4096);
+        let data = random_bytes(200_000, 1);
+        let storage = CdcDataStorage::new(Cursor::new(data.clone()), params).unwrap();
+        assert_eq!(storage.source_len(), data.len() as u64);
+
+        let len = storage.len().unwrap();
+        assert!(len > 100 && len < 800, "{} chunks", len);
+        let mut joined = Vec::new();
+        for index in 0 .. len {
+            let range = storage.chunk_range(index).unwrap();
+            let size = (range.end - range.start) as usize;
+            assert!(size <= 4096);
+            assert!(size >= 256 || index == len - 1);
+            joined.extend(storage.get(index).unwrap().read_to_vec().unwrap());
+        }
+        assert_eq!(joined, data);
+    }
+
+    #[test]
+    fn insertion_keeps_most_chunks() {
+        let params = CdcParams::new(256, 1024, 4096);
+        let data = random_bytes(100_000, 2);
+        let mut changed = data.clone();
+        changed.splice(100 .. 100, b"inserted".iter().cloned());
+
+        let a = CdcDataStorage::new(Cursor::new(data), params).unwrap();
+        let b = CdcDataStorage::new(Cursor::new(changed), params).unwrap();
+        let a = hashes(&a);
+        let b = hashes(&b);
+        let shared = b.iter().filter(|hash| a.contains(hash)).count();
+        assert!(shared + 3 >= b.len(), "{} of {} chunks are shared", shared, b.len());
+    }
+
+    #[test]
+    fn empty_and_small_sources() {
+        let storage = CdcDataStorage::new(Cursor::new(Vec::new()), CdcParams::default()).unwrap();
+        assert!(storage.is_empty().unwrap());
+        let storage = CdcDataStorage::new(Cursor::new(vec![1, 2, 3]), CdcParams::default()).unwrap();
+        assert_eq!(storage.boundaries(), &[3]);
+        assert!(storage.get(1).is_err());
+    }
+
+    #[test]
+    fn merkle_tree_over_chunks() {
+        let data = random_bytes(50_000, 3);
+        let storage = CdcDataStorage::new(Cursor::new(data.clone()), CdcParams::new(64, 512, 2048)).unwrap();
+        let chunks: Vec<_> = (0 .. storage.len().unwrap())
+            .map(|index| storage.chunk_range(index).unwrap())
+            .map(|range| Sha256::eval_hash(&&data[range.start as usize .. range.end as usize]))
+            .collect();
+        let mt: MerkleTree<_, MemoryTreeStorage<Sha256>>;
+        mt = MerkleTree::new_and_rebuild(storage, Default::default()).unwrap();
+        mt.check_data().unwrap();
+        assert_eq!(mt.tree().iter_level(0).unwrap().map(Result::unwrap).collect::<Vec<_>>(), chunks);
+    }
+}
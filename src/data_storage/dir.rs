@@ -0,0 +1,394 @@
+//! A data storage over a directory tree, a data block per file.
+//!
+//! Files are ordered by their paths relative to the directory, as strings with `/` separators,
+//! so the same directory gives the same leaves on any platform. A file is hashed as its relative
+//! path followed by its contents, so renaming a file changes the root as well.
+//! Symbolic links are skipped.
+
+use std::fmt;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use fun::stream::hash_reader;
+use prelude::*;
+
+
+/// A file of a directory: the relative path and the contents,
+/// which are either in memory or read from the disk when hashed
+#[derive(Clone, PartialEq, Eq)]
+pub struct DirFile {
+    path: String,
+    contents: Contents,
+}
+
+#[derive(Clone, PartialEq, Eq)]
+enum Contents {
+    OnDisk(PathBuf),
+    InMemory(Vec<u8>),
+}
+
+impl DirFile {
+    /// Creates a file to be added to a directory; `path` is relative, with `/` separators
+    pub fn new<P: Into<String>, C: Into<Vec<u8>>>(path: P, contents: C) -> Self {
+        DirFile { path: path.into(), contents: Contents::InMemory(contents.into()) }
+    }
+
+    /// Returns the path relative to the directory
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns the contents
+    pub fn read(&self) -> Result<Vec<u8>> {
+        match self.contents {
+            Contents::OnDisk(ref full_path) => Ok(fs::read(full_path)?),
+            Contents::InMemory(ref contents) => Ok(contents.clone()),
+        }
+    }
+}
+
+impl MTHash for DirFile {
+    /// Panics on I/O errors; use `try_hash` instead
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        self.try_hash(state).expect("DirFile can not be read; use MTAlgorithm::try_eval_hash to handle errors")
+    }
+
+    fn try_hash<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        self.path.hash(state);
+        match self.contents {
+            Contents::OnDisk(ref full_path) => {
+                hash_reader(&mut File::open(full_path)?, state)?;
+            },
+            Contents::InMemory(ref contents) => state.update(contents),
+        }
+        Ok(())
+    }
+
+    fn hash_nested<H: MTContext>(&self, state: &mut H) {
+        self.try_hash_nested(state).expect("DirFile can not be read; use MTAlgorithm::try_eval_hash to handle errors")
+    }
+
+    /// Prefixes the contents with their length, so files nested into other values do not collide
+    fn try_hash_nested<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        self.path.hash(state);
+        match self.contents {
+            Contents::OnDisk(ref full_path) => {
+                let mut file = File::open(full_path)?;
+                let len = file.metadata()?.len();
+                len.hash(state);
+                let read = hash_reader(&mut (&mut file).take(len), state)?;
+                if read != len {
+                    Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The file has been truncated while hashed"))?;
+                }
+            },
+            Contents::InMemory(ref contents) => {
+                (contents.len() as u64).hash(state);
+                state.update(contents)
+            },
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DirFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.contents {
+            Contents::OnDisk(_) => write!(f, "DirFile({:?})", self.path),
+            Contents::InMemory(ref contents) => write!(f, "DirFile({:?}, len={})", self.path, contents.len()),
+        }
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A data storage over the files of a directory tree, read when the storage is opened
+pub struct DirReadonlyDataStorage {
+    root: PathBuf,
+    // Sorted relative paths
+    paths: Vec<String>,
+}
+
+impl DirReadonlyDataStorage {
+    /// Reads the directory tree
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        let root = root.as_ref().to_path_buf();
+        let mut paths = Vec::new();
+        walk(&root, "", &mut paths)?;
+        paths.sort();
+        Ok(DirReadonlyDataStorage { root, paths })
+    }
+
+    /// Returns the directory
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Returns the relative path of the file at index
+    pub fn path(&self, index: usize) -> Result<&str> {
+        self.paths.get(index).map(String::as_str).ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    /// Returns the full path of the file at index
+    pub fn full_path(&self, index: usize) -> Result<PathBuf> {
+        self.path(index).map(|path| full_path(&self.root, path))
+    }
+
+    /// Returns the index of the file with the relative path
+    pub fn index_of(&self, path: &str) -> Option<usize> {
+        self.paths.binary_search_by(|x| x.as_str().cmp(path)).ok()
+    }
+}
+
+// Collects relative paths of files under `dir`
+fn walk(dir: &Path, prefix: &str, paths: &mut Vec<String>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "The file name is not valid unicode"))?;
+        let path = if prefix.is_empty() { name } else { format!("{}/{}", prefix, name) };
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            walk(&entry.path(), &path, paths)?;
+        } else if file_type.is_file() {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn full_path(root: &Path, path: &str) -> PathBuf {
+    path.split('/').fold(root.to_path_buf(), |full_path, name| full_path.join(name))
+}
+
+impl fmt::Debug for DirReadonlyDataStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DirReadonlyDataStorage({:?}, len={})", self.root, self.paths.len())
+    }
+}
+
+impl DataStorageReadonly for DirReadonlyDataStorage {
+    type DataValue = DirFile;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.paths.len())
+    }
+
+    fn get(&self, index: usize) -> Result<DirFile> {
+        let path = self.path(index)?;
+        Ok(DirFile { path: path.to_string(), contents: Contents::OnDisk(full_path(&self.root, path)) })
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A writable data storage over the files of a directory tree.
+/// Files are added in the order of their paths, so the directory, read again,
+/// gives the same leaves. Truncation removes files from the disk.
+/// Files are returned with the contents in memory, so they can restore a rewritten file
+pub struct DirDataStorage {
+    inner: DirReadonlyDataStorage,
+}
+
+impl DirDataStorage {
+    /// Reads the directory tree; the directory is created, if it does not exist
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Self> {
+        fs::create_dir_all(root.as_ref())?;
+        Ok(DirDataStorage { inner: DirReadonlyDataStorage::open(root)? })
+    }
+
+    /// Returns the directory
+    pub fn root(&self) -> &Path {
+        self.inner.root()
+    }
+
+    /// Returns the relative path of the file at index
+    pub fn path(&self, index: usize) -> Result<&str> {
+        self.inner.path(index)
+    }
+
+    /// Returns the full path of the file at index
+    pub fn full_path(&self, index: usize) -> Result<PathBuf> {
+        self.inner.full_path(index)
+    }
+
+    /// Returns the index of the file with the relative path
+    pub fn index_of(&self, path: &str) -> Option<usize> {
+        self.inner.index_of(path)
+    }
+
+    fn write(&self, file: &DirFile) -> Result<()> {
+        let full_path = full_path(self.root(), &file.path);
+        if let Some(dir) = full_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let contents = file.read()?;
+        fs::write(full_path, contents)?;
+        Ok(())
+    }
+}
+
+// Checks that the path is relative and normalized
+fn check_path(path: &str) -> Result<()> {
+    if path.split('/').any(|name| name.is_empty() || name == "." || name == ".." || name.contains('\\')) {
+        Err(io::Error::new(io::ErrorKind::InvalidInput, "The path has to be relative, with `/` separators"))?;
+    }
+    Ok(())
+}
+
+impl fmt::Debug for DirDataStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DirDataStorage({:?}, len={})", self.inner.root, self.inner.paths.len())
+    }
+}
+
+impl DataStorageReadonly for DirDataStorage {
+    type DataValue = DirFile;
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn get(&self, index: usize) -> Result<DirFile> {
+        let file = self.inner.get(index)?;
+        let contents = file.read()?;
+        Ok(DirFile { path: file.path, contents: Contents::InMemory(contents) })
+    }
+
+    fn is_writeable(&self) -> bool {
+        true
+    }
+}
+
+impl DataStorage for DirDataStorage {
+    /// The path has to follow the path of the last file
+    fn push(&mut self, file: DirFile) -> Result<()> {
+        check_path(&file.path)?;
+        if self.inner.paths.last().map_or(false, |last| *last >= file.path) {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "Files have to be added in the order of paths"))?;
+        }
+        self.write(&file)?;
+        self.inner.paths.push(file.path);
+        Ok(())
+    }
+
+    fn extend<DD: IntoIterator<Item=Result<DirFile>>>(&mut self, data: DD) -> Result<()> {
+        for file in data.into_iter() {
+            self.push(file?)?;
+        }
+        Ok(())
+    }
+
+    /// Rewrites the contents of the file; the path has to be the same
+    fn set(&mut self, index: usize, file: DirFile) -> Result<DirFile> {
+        if self.path(index)? != file.path {
+            Err(io::Error::new(io::ErrorKind::InvalidInput, "The path of the file can not be changed"))?;
+        }
+        let previous = self.get(index)?;
+        self.write(&file)?;
+        Ok(previous)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        while self.inner.paths.len() > len {
+            fs::remove_file(full_path(self.root(), &self.inner.paths[self.inner.paths.len() - 1]))?;
+            self.inner.paths.pop();
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.truncate(0)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use cache::CacheLimit;
+    use data_storage::cached::CachedDataStorage;
+    use fun::sha256::*;
+    use merkle_tree::{MerkleTree, Transaction};
+    use tempfile;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    #[test]
+    fn dir_readonly_data_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("b/c")).unwrap();
+        fs::write(dir.path().join("b/c/d.txt"), b"d").unwrap();
+        fs::write(dir.path().join("b/a.txt"), b"a").unwrap();
+        fs::write(dir.path().join("z.txt"), b"z").unwrap();
+        fs::write(dir.path().join("b-1.txt"), b"b").unwrap();
+
+        let ds = DirReadonlyDataStorage::open(dir.path()).unwrap();
+        let paths = (0 .. 4).map(|index| ds.path(index).unwrap()).collect::<Vec<_>>();
+        assert_eq!(paths, vec!["b-1.txt", "b/a.txt", "b/c/d.txt", "z.txt"]);
+        assert_eq!(ds.index_of("b/c/d.txt"), Some(2));
+        assert_eq!(ds.full_path(2).unwrap(), dir.path().join("b").join("c").join("d.txt"));
+        assert!(ds.path(4).is_err());
+
+        // Files read from the disk are hashed the same way, as files in memory
+        let file = ds.get(1).unwrap();
+        assert_eq!(file.read().unwrap(), b"a");
+        assert_eq!(Sha256::try_eval_hash(&file).unwrap(), Sha256::eval_hash(&DirFile::new("b/a.txt", "a")));
+        assert!(Sha256::eval_hash(&DirFile::new("b/a.txt", "a")) != Sha256::eval_hash(&DirFile::new("b/b.txt", "a")));
+        assert_eq!(Sha256::try_eval_hash(&(&file,)).unwrap(), Sha256::eval_hash(&(DirFile::new("b/a.txt", "a"),)));
+        assert!(Sha256::eval_hash(&(DirFile::new("a", "bc"), DirFile::new("d", "e")))
+            != Sha256::eval_hash(&(DirFile::new("a", "b"), DirFile::new("c", "de"))));
+
+        fs::remove_file(dir.path().join("z.txt")).unwrap();
+        assert!(Sha256::try_eval_hash(&ds.get(3).unwrap()).unwrap_err().is_io_error());
+    }
+
+    #[test]
+    fn cached_dir_data_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join("a"), vec![0u8; 10]).unwrap();
+        fs::write(dir.path().join("b"), vec![0u8; 130]).unwrap();
+        let ds = CachedDataStorage::new(DirReadonlyDataStorage::open(dir.path()).unwrap(), CacheLimit::Bytes(150));
+        ds.get(0).unwrap();
+        // The cached file grows, the weight it was cached with is evicted
+        fs::write(dir.path().join("a"), vec![0u8; 200]).unwrap();
+        assert_eq!(ds.get(1).unwrap().read().unwrap(), vec![0u8; 130]);
+        let stats = ds.stats();
+        assert_eq!(stats.len, 1);
+        assert!(stats.weight <= 150);
+    }
+
+    #[test]
+    fn dir_data_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("data");
+        let mut mt = MerkleTree::<_, MemoryTreeStorage<Sha256>>::new_and_rebuild(DirDataStorage::open(&root).unwrap(), Default::default()).unwrap();
+        mt.push(DirFile::new("a.txt", "a")).unwrap();
+        mt.extend(vec![Ok(DirFile::new("b/a.txt", "ba")), Ok(DirFile::new("b/b.txt", "bb"))]).unwrap();
+        assert!(mt.push(DirFile::new("a.txt", "x")).is_err());
+        assert!(mt.push(DirFile::new("../c.txt", "x")).is_err());
+        mt.update(1, DirFile::new("b/a.txt", "BA")).unwrap();
+        assert!(mt.update(1, DirFile::new("b/x.txt", "BA")).is_err());
+        assert_eq!(fs::read(root.join("b").join("a.txt")).unwrap(), b"BA");
+
+        // The rewritten file is restored, if the transaction fails
+        let root_hash = mt.get_root().unwrap();
+        let mut tx = Transaction::new();
+        tx.update(0, DirFile::new("a.txt", "A")).push(DirFile::new("a.txt", "x"));
+        assert!(mt.apply(tx).is_err());
+        assert_eq!(fs::read(root.join("a.txt")).unwrap(), b"a");
+        assert_eq!(mt.get_root().unwrap(), root_hash);
+
+        let reopened = DirReadonlyDataStorage::open(&root).unwrap();
+        let mt2 = MerkleTree::<_, MemoryTreeStorage<Sha256>>::new_and_rebuild(reopened, Default::default()).unwrap();
+        assert_eq!(mt.get_root().unwrap(), mt2.get_root().unwrap());
+
+        mt.data_mut().truncate(1).unwrap();
+        assert!(!root.join("b").join("b.txt").exists());
+    }
+}
@@ -24,6 +24,23 @@ impl <'v, V> fmt::Debug for MemoryReadonlyDataStorage<'v, V> where V: MTHash + '
     }
 }
 
+/// Serialized as a sequence of data values
+#[cfg(feature = "serde")]
+impl <'v, V> ::serde::Serialize for MemoryReadonlyDataStorage<'v, V> where V: MTHash + ::serde::Serialize + 'v {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serde::Serialize::serialize(&self.data, serializer)
+    }
+}
+
+/// Deserialized into an owned copy of the data
+#[cfg(feature = "serde")]
+impl <'de, 'v, V> ::serde::Deserialize<'de> for MemoryReadonlyDataStorage<'v, V> where V: MTHash + ::serde::Deserialize<'de> + 'v {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let data: Vec<V> = ::serde::Deserialize::deserialize(deserializer)?;
+        Ok(MemoryReadonlyDataStorage::with_data(data))
+    }
+}
+
 impl <'v, V> DataStorageReadonly for MemoryReadonlyDataStorage<'v, V> where V: MTHash + 'v {
     type DataValue = V;
 
@@ -48,6 +65,14 @@ impl <'v, V> DataStorageReadonly for MemoryReadonlyDataStorage<'v, V> where V: M
         self.check_range(&range)?;
         Ok(Box::new(self.data[range].iter().cloned().map(Ok)))
     }
+
+    fn with_value<R, F: FnOnce(&Self::DataValue) -> Result<R>>(&self, index: usize, f: F) -> Result<R> {
+        f(self.data.get(index).ok_or(INDEX_IS_OUT_OF_BOUNDS)?)
+    }
+
+    fn for_each_value<F: FnMut(&Self::DataValue) -> Result<()>>(&self, f: F) -> Result<()> {
+        self.data.iter().map(f).collect()
+    }
 }
 
 
@@ -80,6 +105,16 @@ impl<V> MemoryDataStorage<V> where V: MTHash {
         }
     }
 
+    /// Returns all the items
+    pub fn data(&self) -> &[V] {
+        &self.data
+    }
+
+    /// Returns all the items, consuming the storage
+    pub fn into_data(self) -> Vec<V> {
+        self.data
+    }
+
     /// sets whether data storage can accept new values
     pub fn set_writable(&mut self, is_writable: bool) {
         self.is_writable = is_writable;
@@ -98,6 +133,22 @@ impl <V> fmt::Debug for MemoryDataStorage<V> where V: MTHash {
     }
 }
 
+/// Serialized as a sequence of data values; the writable mode is not preserved
+#[cfg(feature = "serde")]
+impl <V> ::serde::Serialize for MemoryDataStorage<V> where V: MTHash + ::serde::Serialize {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serde::Serialize::serialize(&self.data, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de, V> ::serde::Deserialize<'de> for MemoryDataStorage<V> where V: MTHash + ::serde::Deserialize<'de> {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let data: Vec<V> = ::serde::Deserialize::deserialize(deserializer)?;
+        Ok(MemoryDataStorage::with_data(data))
+    }
+}
+
 impl <V> DataStorageReadonly for MemoryDataStorage<V> where V: MTHash {
     type DataValue = V;
 
@@ -123,6 +174,14 @@ impl <V> DataStorageReadonly for MemoryDataStorage<V> where V: MTHash {
         Ok(Box::new(self.data[range].iter().cloned().map(Ok)))
     }
 
+    fn with_value<R, F: FnOnce(&Self::DataValue) -> Result<R>>(&self, index: usize, f: F) -> Result<R> {
+        f(self.data.get(index).ok_or(INDEX_IS_OUT_OF_BOUNDS)?)
+    }
+
+    fn for_each_value<F: FnMut(&Self::DataValue) -> Result<()>>(&self, f: F) -> Result<()> {
+        self.data.iter().map(f).collect()
+    }
+
     fn is_writeable(&self) -> bool {
         self.is_writable
     }
@@ -149,6 +208,21 @@ impl <V> DataStorage for MemoryDataStorage<V> where V: MTHash {
         }
         Ok(())
     }
+
+    fn set(&mut self, index: usize, data: Self::DataValue) -> Result<Self::DataValue> {
+        if !self.is_writeable() {
+            Err(Error::new_ro("The data storage is in read-only mode"))?;
+        }
+        let value = self.data.get_mut(index).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        Ok(::std::mem::replace(value, data))
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if !self.is_writeable() {
+            Err(Error::new_ro("The data storage is in read-only mode"))?;
+        }
+        Ok(self.data.truncate(len))
+    }
 }
 
 
@@ -166,6 +240,7 @@ mod tests {
         assert!(ds.push(&b"123"[..]).is_ok());
         ds.set_writable(false);
         assert!(ds.push(&b"123"[..]).is_err());
+        assert!(ds.truncate(0).is_err());
         ds.set_writable(true);
         assert!(ds.push(&b"123"[..]).is_ok());
         assert!(ds.len().unwrap() == 2);
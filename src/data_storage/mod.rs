@@ -1,4 +1,14 @@
 pub mod abc;
+pub mod cached;
+pub mod cdc;
+pub mod dir;
 pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
 
+pub use self::cached::*;
+pub use self::cdc::*;
+pub use self::dir::*;
 pub use self::memory::*;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::*;
@@ -0,0 +1,159 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use rusqlite::Connection;
+use rusqlite::NO_PARAMS;
+use rusqlite::OptionalExtension;
+use rusqlite::types::{FromSql, ToSql};
+
+use prelude::*;
+
+
+/// A storage for data blocks in a table of a SQLite database.
+/// Blocks are kept in a `BLOB` column, values are converted with `ToSql` / `FromSql`
+pub struct SqliteDataStorage<V> where V: MTHash + ToSql + FromSql {
+    connection: Connection,
+    // The quoted name of the table
+    table: String,
+    len: usize,
+    _value: PhantomData<V>,
+}
+
+impl <V> SqliteDataStorage<V> where V: MTHash + ToSql + FromSql {
+    /// Opens the database file and creates the table, if it does not exist
+    pub fn open<P: AsRef<Path>>(path: P, table: &str) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?, table)
+    }
+
+    /// Creates the table in the database, if it does not exist
+    pub fn with_connection(connection: Connection, table: &str) -> Result<Self> {
+        let (table, len) = open_data_table(&connection, table)?;
+        Ok(SqliteDataStorage { connection, table, len, _value: PhantomData })
+    }
+
+    /// Returns the connection to the database
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Returns the connection to the database, consuming the storage
+    pub fn into_connection(self) -> Connection {
+        self.connection
+    }
+}
+
+/// Creates the table for data blocks, if it does not exist.
+/// Returns the quoted name of the table and the number of blocks in it
+pub fn open_data_table(connection: &Connection, table: &str) -> Result<(String, usize)> {
+    let table = format!("\"{}\"", table.replace('"', "\"\""));
+    connection.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (idx INTEGER PRIMARY KEY, value BLOB NOT NULL)", table
+    ))?;
+    let (count, end): (i64, i64) = connection.query_row(
+        &format!("SELECT COUNT(*), COALESCE(MAX(idx) + 1, 0) FROM {}", table),
+        NO_PARAMS,
+        |row| Ok((row.get(0)?, row.get(1)?)),
+    )?;
+    // Blocks are numbered from 0 without gaps
+    if count != end {
+        Err(StateError::InconsistentState)?;
+    }
+    Ok((table, count as usize))
+}
+
+impl <V> fmt::Debug for SqliteDataStorage<V> where V: MTHash + ToSql + FromSql {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SqliteDataStorage(table={}, len={})", self.table, self.len)
+    }
+}
+
+impl <V> DataStorageReadonly for SqliteDataStorage<V> where V: MTHash + ToSql + FromSql {
+    type DataValue = V;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.len)
+    }
+
+    fn get(&self, index: usize) -> Result<V> {
+        let mut statement = self.connection.prepare_cached(&format!("SELECT value FROM {} WHERE idx = ?", self.table))?;
+        let value = statement.query_row(&[index as i64], |row| row.get(0)).optional()?;
+        value.ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn is_writeable(&self) -> bool {
+        true
+    }
+}
+
+impl <V> DataStorage for SqliteDataStorage<V> where V: MTHash + ToSql + FromSql {
+    fn push(&mut self, data: V) -> Result<()> {
+        let mut statement = self.connection.prepare_cached(&format!("INSERT INTO {} (idx, value) VALUES (?, ?)", self.table))?;
+        statement.execute(&[&(self.len as i64) as &ToSql, &data])?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// All the blocks are inserted in one transaction
+    fn extend<DD: IntoIterator<Item=Result<V>>>(&mut self, data: DD) -> Result<()> {
+        let mut len = self.len;
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement = transaction.prepare_cached(&format!("INSERT INTO {} (idx, value) VALUES (?, ?)", self.table))?;
+            for value in data.into_iter() {
+                statement.execute(&[&(len as i64) as &ToSql, &value?])?;
+                len += 1;
+            }
+        }
+        transaction.commit()?;
+        self.len = len;
+        Ok(())
+    }
+
+    fn set(&mut self, index: usize, data: V) -> Result<V> {
+        let previous = self.get(index)?;
+        let mut statement = self.connection.prepare_cached(&format!("UPDATE {} SET value = ? WHERE idx = ?", self.table))?;
+        statement.execute(&[&data as &ToSql, &(index as i64)])?;
+        Ok(previous)
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<()> {
+        if len < self.len {
+            self.connection.execute(&format!("DELETE FROM {} WHERE idx >= ?", self.table), &[len as i64])?;
+            self.len = len;
+        }
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.connection.execute(&format!("DELETE FROM {}", self.table), NO_PARAMS)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sqlite_data_storage() {
+        let mut ds = SqliteDataStorage::<Vec<u8>>::with_connection(Connection::open_in_memory().unwrap(), "data").unwrap();
+        ds.push(vec![1]).unwrap();
+        ds.extend(vec![Ok(vec![2]), Ok(vec![3, 3]), Ok(vec![4])]).unwrap();
+        assert_eq!(ds.len().unwrap(), 4);
+        assert_eq!(ds.get(2).unwrap(), vec![3, 3]);
+        assert!(ds.get(4).is_err());
+        assert_eq!(ds.set(0, vec![10]).unwrap(), vec![1]);
+        assert_eq!(ds.get(0).unwrap(), vec![10]);
+        ds.truncate(2).unwrap();
+        assert_eq!(ds.iter().unwrap().collect::<Result<Vec<_>>>().unwrap(), vec![vec![10], vec![2]]);
+
+        // A failed extend leaves the storage as is
+        assert!(ds.extend(vec![Ok(vec![5]), Err(StateError::InconsistentState.into())]).is_err());
+        assert_eq!(ds.len().unwrap(), 2);
+        let ds = SqliteDataStorage::<Vec<u8>>::with_connection(ds.into_connection(), "data").unwrap();
+        assert_eq!(ds.len().unwrap(), 2);
+    }
+}
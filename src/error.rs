@@ -6,6 +6,7 @@ use std::io;
 pub enum StateError {
     InconsistentState,
     DataDoesNotMatchTheChecksum,
+    AmountOverflow,
 }
 
 impl fmt::Display for StateError {
@@ -13,6 +14,7 @@ impl fmt::Display for StateError {
         write!(f, "::mt::StateError::{}", match *self {
             StateError::InconsistentState => "InconsistentState",
             StateError::DataDoesNotMatchTheChecksum => "DataDoesNotMatchTheChecksum",
+            StateError::AmountOverflow => "AmountOverflow",
         })
     }
 }
@@ -28,6 +30,7 @@ impl StateError {
         match *self {
             StateError::InconsistentState => "inconsistent state",
             StateError::DataDoesNotMatchTheChecksum => "data does not match the checksum",
+            StateError::AmountOverflow => "the sum of amounts overflows",
         }
     }
 }
@@ -39,12 +42,14 @@ impl StateError {
 #[derive(Debug)]
 pub enum AccessError {
     IndexIsOutOfBounds,
+    NotSupported,
 }
 
 impl fmt::Display for AccessError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "::mt::StateError::{}", match *self {
             AccessError::IndexIsOutOfBounds => "IndexIsOutOfBounds",
+            AccessError::NotSupported => "NotSupported",
         })
     }
 }
@@ -59,6 +64,41 @@ impl AccessError {
     pub fn as_str(&self) -> &'static str {
         match *self {
             AccessError::IndexIsOutOfBounds => "index is out of bounds",
+            AccessError::NotSupported => "the operation is not supported by the storage",
+        }
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+#[derive(Debug)]
+pub enum ParseError {
+    InvalidLength,
+    InvalidCharacter,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "::mt::ParseError::{}", match *self {
+            ParseError::InvalidLength => "InvalidLength",
+            ParseError::InvalidCharacter => "InvalidCharacter",
+        })
+    }
+}
+
+impl ::std::error::Error for ParseError {
+    fn description(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl ParseError {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ParseError::InvalidLength => "invalid length",
+            ParseError::InvalidCharacter => "invalid character",
         }
     }
 }
@@ -81,6 +121,10 @@ pub enum Error {
     Io(io::Error),
     State(StateError),
     Access(AccessError),
+    Parse(ParseError),
+    /// The error of an operation, which has not been rolled back because of the second error.
+    /// Storages may be left inconsistent
+    Rollback(Box<Error>, Box<Error>),
 }
 
 impl fmt::Display for Error {
@@ -90,6 +134,8 @@ impl fmt::Display for Error {
             Error::Io(ref e) => write!(f, "{}", e)?,
             Error::State(ref e) => write!(f, "{}", e)?,
             Error::Access(ref e) => write!(f, "{}", e)?,
+            Error::Parse(ref e) => write!(f, "{}", e)?,
+            Error::Rollback(ref e, ref rollback) => write!(f, "{}, rollback failed: {}", e, rollback)?,
         }
         write!(f, ")")
     }
@@ -99,6 +145,14 @@ impl Error {
     pub fn new_ro<M: Into<String>>(msg: M) -> Self {
         Error::Io(io::Error::new(io::ErrorKind::PermissionDenied, msg.into()))
     }
+
+    /// Attaches the error of the rollback, if any, to the error of the operation
+    pub fn with_rollback(self, rollback: Result<()>) -> Self {
+        match rollback {
+            Ok(()) => self,
+            Err(err) => Error::Rollback(Box::new(self), Box::new(err)),
+        }
+    }
 }
 
 impl From<io::Error> for Error {
@@ -119,12 +173,27 @@ impl From<AccessError> for Error {
     }
 }
 
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Error::Parse(err)
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<::rusqlite::Error> for Error {
+    fn from(err: ::rusqlite::Error) -> Self {
+        Error::Io(io::Error::new(io::ErrorKind::Other, err))
+    }
+}
+
 impl ::std::error::Error for Error {
     fn description(&self) -> &str {
         match *self {
             Error::Io(ref e) => e.description(),
             Error::State(ref e) => e.description(),
             Error::Access(ref e) => e.description(),
+            Error::Parse(ref e) => e.description(),
+            Error::Rollback(ref e, _) => e.description(),
         }
     }
 
@@ -133,6 +202,8 @@ impl ::std::error::Error for Error {
             Error::Io(ref e) => e.cause(),
             Error::State(ref e) => e.cause(),
             Error::Access(ref e) => e.cause(),
+            Error::Parse(ref e) => e.cause(),
+            Error::Rollback(ref e, _) => Some(&**e),
         }
     }
 }
@@ -179,6 +250,35 @@ impl Error {
             _ => None,
         }
     }
+
+    pub fn is_parse_error(&self) -> bool {
+        match *self {
+            Error::Parse(_) => true,
+             _ => false,
+        }
+    }
+
+    pub fn into_parse_error(self) -> Option<ParseError> {
+        match self {
+            Error::Parse(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    pub fn is_rollback_error(&self) -> bool {
+        match *self {
+            Error::Rollback(..) => true,
+             _ => false,
+        }
+    }
+
+    /// Returns the error of the operation, which has not been rolled back, and the error of the rollback
+    pub fn into_rollback_error(self) -> Option<(Error, Error)> {
+        match self {
+            Error::Rollback(err, rollback) => Some((*err, *rollback)),
+            _ => None,
+        }
+    }
 }
 
 pub trait MTResultExt<T> {
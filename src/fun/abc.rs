@@ -1,5 +1,11 @@
+use std::borrow::Cow;
 use std::fmt;
 
+use error::*;
+use util::base642buf;
+use util::buf2base64;
+use util::hex2buf;
+
 
 /// Represents a state of hashing
 pub trait MTContext {
@@ -14,49 +20,100 @@ pub trait MTContext {
 pub trait MTHash: Eq + Clone + fmt::Debug {
     fn hash<H: MTContext>(&self, state: &mut H);
 
+    /// Hashes a value, which can fail to be read (for example, streamed from a file).
+    /// Such values have to override this method; the default one never fails
+    fn try_hash<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        self.hash(state);
+        Ok(())
+    }
+
+    /// Hashes the value as a part of another value (a tuple element, a field, an item of `Vec`).
+    /// Values hashed as raw data (slices, streamed regions) prefix themselves with their length here
+    fn hash_nested<H: MTContext>(&self, state: &mut H) {
+        self.hash(state)
+    }
+
+    fn try_hash_nested<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        self.try_hash(state)
+    }
+
     fn hash_slice<H: MTContext>(data: &[Self], state: &mut H)
         where Self: Sized
     {
         for piece in data {
-            piece.hash(state);
+            piece.hash_nested(state);
         }
     }
+
+    fn try_hash_slice<H: MTContext>(data: &[Self], state: &mut H) -> Result<()>
+        where Self: Sized
+    {
+        for piece in data {
+            piece.try_hash_nested(state)?;
+        }
+        Ok(())
+    }
 }
 
-/// Represents a hashing algorithm
-pub trait MTAlgorithm {
-    type Value: MTHash;
-    type Context: MTContext<Out=Self::Value>;
+/// Represents a hash value, which can be converted to and from bytes
+/// (hex and base64 representations are derived from the bytes)
+pub trait MTValue: MTHash + Sized {
+    /// Returns the raw bytes of the value.
+    /// Values, which are not kept as bytes, return an owned copy
+    fn as_bytes(&self) -> Cow<[u8]>;
 
-    fn eval_hash<H>(data: &H) -> Self::Value where H: MTHash {
-        let mut context = Self::Context::new();
-        data.hash(&mut context);
-        context.finish()
+    /// Creates a value from raw bytes, or error, if the length does not match
+    fn from_bytes(bytes: &[u8]) -> Result<Self>;
+
+    /// Returns the value as a lowercase hex string
+    fn to_hex(&self) -> String {
+        self.as_bytes().iter().map(|x| format!("{:02x}", x)).collect()
     }
-}
 
+    /// Parses a value from a hex string
+    fn from_hex<S: AsRef<str>>(string: S) -> Result<Self> {
+        let string = string.as_ref();
+        if string.len() % 2 == 1 {
+            Err(ParseError::InvalidLength)?;
+        }
+        let mut buf = Vec::with_capacity(string.len() / 2);
+        hex2buf(&mut buf, string).map_err(|_| ParseError::InvalidCharacter)?;
+        Self::from_bytes(&buf)
+    }
 
-impl <'a> MTHash for &'a [u8] {
-    fn hash<S: MTContext>(&self, state: &mut S) {
-        state.update(self)
+    /// Returns the value as a base64 string
+    fn to_base64(&self) -> String {
+        buf2base64(self.as_bytes())
     }
-}
 
-impl <'a, H> MTHash for &'a H where H: MTHash {
-    fn hash<S: MTContext>(&self, state: &mut S) {
-        (*self).hash(state)
+    /// Parses a value from a base64 string
+    fn from_base64<S: AsRef<str>>(string: S) -> Result<Self> {
+        let string = string.as_ref();
+        if string.len() % 4 != 0 {
+            Err(ParseError::InvalidLength)?;
+        }
+        let mut buf = Vec::with_capacity(string.len() / 4 * 3);
+        base642buf(&mut buf, string).map_err(|_| ParseError::InvalidCharacter)?;
+        Self::from_bytes(&buf)
     }
 }
 
-impl <'a, H> MTHash for &'a [H] where H: MTHash {
-    fn hash<S: MTContext>(&self, state: &mut S) {
-        H::hash_slice(self, state)
+/// Represents a hashing algorithm
+pub trait MTAlgorithm {
+    type Value: MTValue;
+    type Context: MTContext<Out=Self::Value>;
+
+    fn eval_hash<H>(data: &H) -> Self::Value where H: MTHash {
+        let mut context = Self::Context::new();
+        data.hash(&mut context);
+        context.finish()
     }
-}
 
-impl <H> MTHash for (H, H) where H: MTHash {
-    fn hash<S: MTContext>(&self, state: &mut S) {
-        H::hash(&self.0, state);
-        H::hash(&self.1, state);
+    /// The same as `eval_hash`, but returns an error if the data can not be read
+    fn try_eval_hash<H>(data: &H) -> Result<Self::Value> where H: MTHash {
+        let mut context = Self::Context::new();
+        data.try_hash(&mut context)?;
+        Ok(context.finish())
     }
 }
+
@@ -1,4 +1,6 @@
+use std::borrow::Cow;
 use std::fmt;
+use std::str;
 
 use crc::crc32::Hasher32;
 use crc::crc32::Digest;
@@ -185,13 +187,58 @@ impl MTHash for Crc32Value {
     }
 }
 
+impl MTValue for Crc32Value {
+    fn as_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0[..])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 4 {
+            Err(ParseError::InvalidLength)?;
+        }
+        let mut value: [u8; 4] = Default::default();
+        value.clone_from_slice(bytes);
+        Ok(Crc32Value(value))
+    }
+}
+
+impl fmt::Display for Crc32Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_slice2hex(f, &self.0[..])
+    }
+}
+
+impl str::FromStr for Crc32Value {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Crc32Value::from_hex(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Crc32Value {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serialization::serialize_value(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de> ::serde::Deserialize<'de> for Crc32Value {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        ::serialization::deserialize_value(deserializer)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use abc::MTAlgorithm;
+    use abc::MTValue;
     use super::Crc32Ieee;
     use super::Crc32Castagnoli;
     use super::Crc32Koopman;
+    use super::Crc32Value;
 
     #[test]
     fn crc32_works() {
@@ -225,4 +272,19 @@ mod tests {
         let sample = "CRC32:e05b34cd";
         assert_eq!(as_string, sample);
     }
+
+    #[test]
+    fn crc32_value_conversions() {
+        let result = Crc32Ieee::eval_hash(&b"123".as_ref());
+        assert_eq!(result.to_string(), "884863d2");
+        assert_eq!(result.to_base64(), "iEhj0g==");
+        assert_eq!("884863d2".parse::<Crc32Value>().unwrap(), result);
+        assert_eq!(Crc32Value::from_base64("iEhj0g==").unwrap(), result);
+        assert_eq!(Crc32Value::from_bytes(&result.as_bytes()).unwrap(), result);
+
+        assert!(Crc32Value::from_bytes(&[0; 5]).is_err());
+        assert!("884863d".parse::<Crc32Value>().is_err());
+        assert!("884863d2d2".parse::<Crc32Value>().is_err());
+        assert!(Crc32Value::from_base64("iEhj0g").is_err());
+    }
 }
@@ -1,12 +1,17 @@
+use std::borrow::Cow;
+use std::fmt;
 use std::hash::Hasher;
 use std::collections::hash_map::DefaultHasher;
+use std::str;
 
 use prelude::*;
+use util::fmt_slice2hex;
 
 
 #[derive(Debug, Default, Clone, Copy)]
 pub struct DefaultHash();
 
+/// The 64-bit hash; its bytes are little-endian
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct DefaultHashValue(pub u64);
 
@@ -39,9 +44,70 @@ impl MTContext for DefaultHashContext {
 
 impl MTHash for DefaultHashValue {
     fn hash<H: MTContext>(&self, state: &mut H) {
-        let buf: [u8; 8] = unsafe {
-            ::std::mem::transmute(self.0.to_le())
-        };
-        state.update(&buf)
+        state.update(&self.0.to_le_bytes())
+    }
+}
+
+impl MTValue for DefaultHashValue {
+    fn as_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(self.0.to_le_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 8 {
+            Err(ParseError::InvalidLength)?;
+        }
+        let mut value: [u8; 8] = Default::default();
+        value.clone_from_slice(bytes);
+        Ok(DefaultHashValue(u64::from_le_bytes(value)))
+    }
+}
+
+impl fmt::Display for DefaultHashValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_slice2hex(f, &self.0.to_le_bytes())
+    }
+}
+
+impl str::FromStr for DefaultHashValue {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        DefaultHashValue::from_hex(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for DefaultHashValue {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serialization::serialize_value(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de> ::serde::Deserialize<'de> for DefaultHashValue {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        ::serialization::deserialize_value(deserializer)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use abc::MTAlgorithm;
+    use abc::MTValue;
+    use super::DefaultHash;
+    use super::DefaultHashValue;
+
+    #[test]
+    fn default_hash_value_conversions() {
+        let result = DefaultHash::eval_hash(&b"123".as_ref());
+        let as_string = result.to_string();
+        assert_eq!(as_string.len(), 16);
+        assert_eq!(as_string.parse::<DefaultHashValue>().unwrap(), result);
+        assert_eq!(DefaultHashValue::from_base64(result.to_base64()).unwrap(), result);
+        assert!(DefaultHashValue::from_bytes(&result.as_bytes()[.. 4]).is_err());
+        assert_eq!(DefaultHashValue(1).to_hex(), "0100000000000000");
+        assert_eq!(DefaultHashValue::from_hex("0100000000000000").unwrap(), DefaultHashValue(1));
     }
 }
@@ -34,5 +34,14 @@ impl <H> MTAlgorithm for DoubleHash<H> where H: MTAlgorithm {
         value.hash(&mut context);
         context.finish()
     }
-}
 
+    fn try_eval_hash<D>(data: &D) -> Result<Self::Value> where D: MTHash {
+        let mut context = Self::Context::new();
+        data.try_hash(&mut context)?;
+        let value = context.finish();
+
+        let mut context = Self::Context::new();
+        value.hash(&mut context);
+        Ok(context.finish())
+    }
+}
@@ -0,0 +1,273 @@
+//! `MTHash` implementations for primitive and std types.
+//!
+//! Every value is fed to the hashing context with an unambiguous encoding,
+//! so values of the same type never produce the same stream of bytes:
+//!
+//! * integers - fixed width, little-endian; `usize` and `isize` are encoded as 64-bit integers
+//! * `bool` - one byte, `0` or `1`
+//! * `char` - as `u32`
+//! * `&str`, `String` - the length in bytes as `u64`, then UTF-8 bytes
+//! * `Vec<T>` - the number of elements as `u64`, then the elements
+//! * `[T; N]` - the elements only, as the length is fixed by the type
+//! * `Option<T>` - `0` for `None`, `1` followed by the value for `Some`
+//! * tuples - the elements in order
+//! * `&[T]` - the number of elements as `u64`, then the elements
+//!
+//! The only exception is a borrowed slice passed directly to `MTAlgorithm::eval_hash`
+//! (a leaf data block or a group of child hashes): it is hashed as raw data without
+//! the length prefix, the same way as leaves and nodes have always been hashed.
+//! As soon as a slice is nested into a tuple, `Vec`, `Option`, array or derived struct,
+//! it is hashed with `hash_nested` and gets the prefix.
+
+use prelude::*;
+
+
+impl MTHash for u8 {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        state.update(&[*self])
+    }
+
+    fn hash_slice<H: MTContext>(data: &[Self], state: &mut H) {
+        state.update(data)
+    }
+
+    fn try_hash_slice<H: MTContext>(data: &[Self], state: &mut H) -> Result<()> {
+        state.update(data);
+        Ok(())
+    }
+}
+
+macro_rules! impl_mthash_for_int {
+    ($($t: ty),*) => {$(
+        impl MTHash for $t {
+            fn hash<H: MTContext>(&self, state: &mut H) {
+                state.update(&self.to_le_bytes())
+            }
+        }
+    )*};
+}
+
+impl_mthash_for_int!(u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+impl MTHash for usize {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        (*self as u64).hash(state)
+    }
+}
+
+impl MTHash for isize {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        (*self as i64).hash(state)
+    }
+}
+
+impl MTHash for bool {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        state.update(&[*self as u8])
+    }
+}
+
+impl MTHash for char {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        (*self as u32).hash(state)
+    }
+}
+
+impl <'a> MTHash for &'a str {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        (self.len() as u64).hash(state);
+        state.update(self.as_bytes())
+    }
+}
+
+impl MTHash for String {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        self.as_str().hash(state)
+    }
+}
+
+impl <T> MTHash for Vec<T> where T: MTHash {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        (self.len() as u64).hash(state);
+        T::hash_slice(self, state)
+    }
+
+    fn try_hash<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        (self.len() as u64).hash(state);
+        T::try_hash_slice(self, state)
+    }
+}
+
+impl <T, const N: usize> MTHash for [T; N] where T: MTHash {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        T::hash_slice(self, state)
+    }
+
+    fn try_hash<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        T::try_hash_slice(self, state)
+    }
+}
+
+impl <T> MTHash for Option<T> where T: MTHash {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        match *self {
+            None => state.update(&[0]),
+            Some(ref value) => {
+                state.update(&[1]);
+                value.hash_nested(state)
+            }
+        }
+    }
+
+    fn try_hash<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        match *self {
+            None => Ok(state.update(&[0])),
+            Some(ref value) => {
+                state.update(&[1]);
+                value.try_hash_nested(state)
+            }
+        }
+    }
+}
+
+impl <'a, H> MTHash for &'a H where H: MTHash {
+    fn hash<S: MTContext>(&self, state: &mut S) {
+        (*self).hash(state)
+    }
+
+    fn try_hash<S: MTContext>(&self, state: &mut S) -> Result<()> {
+        (*self).try_hash(state)
+    }
+
+    fn hash_nested<S: MTContext>(&self, state: &mut S) {
+        (*self).hash_nested(state)
+    }
+
+    fn try_hash_nested<S: MTContext>(&self, state: &mut S) -> Result<()> {
+        (*self).try_hash_nested(state)
+    }
+}
+
+impl <'a, H> MTHash for &'a [H] where H: MTHash {
+    fn hash<S: MTContext>(&self, state: &mut S) {
+        H::hash_slice(self, state)
+    }
+
+    fn try_hash<S: MTContext>(&self, state: &mut S) -> Result<()> {
+        H::try_hash_slice(self, state)
+    }
+
+    fn hash_nested<S: MTContext>(&self, state: &mut S) {
+        (self.len() as u64).hash(state);
+        H::hash_slice(self, state)
+    }
+
+    fn try_hash_nested<S: MTContext>(&self, state: &mut S) -> Result<()> {
+        (self.len() as u64).hash(state);
+        H::try_hash_slice(self, state)
+    }
+}
+
+macro_rules! impl_mthash_for_tuple {
+    ($($name: ident)+) => {
+        impl <$($name),+> MTHash for ($($name,)+) where $($name: MTHash),+ {
+            #[allow(non_snake_case)]
+            fn hash<S: MTContext>(&self, state: &mut S) {
+                let ($(ref $name,)+) = *self;
+                $($name.hash_nested(state);)+
+            }
+
+            #[allow(non_snake_case)]
+            fn try_hash<S: MTContext>(&self, state: &mut S) -> Result<()> {
+                let ($(ref $name,)+) = *self;
+                $($name.try_hash_nested(state)?;)+
+                Ok(())
+            }
+        }
+    };
+}
+
+impl_mthash_for_tuple!(A);
+impl_mthash_for_tuple!(A B);
+impl_mthash_for_tuple!(A B C);
+impl_mthash_for_tuple!(A B C D);
+impl_mthash_for_tuple!(A B C D E);
+impl_mthash_for_tuple!(A B C D E F);
+impl_mthash_for_tuple!(A B C D E F G);
+impl_mthash_for_tuple!(A B C D E F G I);
+impl_mthash_for_tuple!(A B C D E F G I J);
+impl_mthash_for_tuple!(A B C D E F G I J K);
+impl_mthash_for_tuple!(A B C D E F G I J K L);
+impl_mthash_for_tuple!(A B C D E F G I J K L M);
+
+
+#[cfg(test)]
+mod tests {
+    use prelude::*;
+
+    /// Collects all bytes fed to the context
+    struct Bytes(Vec<u8>);
+
+    impl MTContext for Bytes {
+        type Out = Vec<u8>;
+
+        fn new() -> Self {
+            Bytes(Vec::new())
+        }
+
+        fn update(&mut self, msg: &[u8]) {
+            self.0.extend_from_slice(msg)
+        }
+
+        fn finish(self) -> Self::Out {
+            self.0
+        }
+    }
+
+    fn bytes<V: MTHash>(value: V) -> Vec<u8> {
+        let mut context = Bytes::new();
+        value.hash(&mut context);
+        context.finish()
+    }
+
+    #[test]
+    fn primitives_encoding() {
+        assert_eq!(bytes(0x0102u16), [2, 1]);
+        assert_eq!(bytes(-2i32), [0xfe, 0xff, 0xff, 0xff]);
+        assert_eq!(bytes(1usize), bytes(1u64));
+        assert_eq!(bytes(-1isize), bytes(-1i64));
+        assert_eq!(bytes(true), [1]);
+        assert_eq!(bytes(false), [0]);
+        assert_eq!(bytes('a'), [0x61, 0, 0, 0]);
+        assert_eq!(bytes("ab"), [2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        assert_eq!(bytes("ab".to_string()), bytes("ab"));
+        assert_eq!(bytes(vec![1u8, 2]), [2, 0, 0, 0, 0, 0, 0, 0, 1, 2]);
+        assert_eq!(bytes(vec![1u16]), [1, 0, 0, 0, 0, 0, 0, 0, 1, 0]);
+        assert_eq!(bytes([1u8, 2]), [1, 2]);
+        assert_eq!(bytes(Some(7u8)), [1, 7]);
+        assert_eq!(bytes(None::<u8>), [0]);
+        assert_eq!(bytes((1u8, true, "c")), [1, 1, 1, 0, 0, 0, 0, 0, 0, 0, b'c']);
+    }
+
+    #[test]
+    fn only_top_level_slices_are_raw() {
+        assert_eq!(bytes(&b"abc"[..]), b"abc");
+        assert_eq!(bytes(&[1u16, 2][..]), [1, 0, 2, 0]);
+        assert_eq!(bytes((&b"ab"[..],)), [2, 0, 0, 0, 0, 0, 0, 0, b'a', b'b']);
+        assert_eq!(bytes(Some(&b"ab"[..])), bytes(Some(vec![b'a', b'b'])));
+        assert_eq!(bytes(vec![&b"ab"[..]]), bytes(vec![vec![b'a', b'b']]));
+    }
+
+    #[test]
+    fn different_values_do_not_collide() {
+        assert!(bytes(("a", "bc")) != bytes(("ab", "c")));
+        assert!(bytes(vec![vec![1u8], vec![2, 3]]) != bytes(vec![vec![1u8, 2], vec![3]]));
+        assert!(bytes(vec!["", "a"]) != bytes(vec!["a", ""]));
+        assert!(bytes(Some(0u8)) != bytes(None::<u8>));
+        assert!(bytes((Some(0u8), None::<u8>)) != bytes((None::<u8>, Some(0u8))));
+        assert!(bytes((&b"ab"[..], &b"c"[..])) != bytes((&b"a"[..], &b"bc"[..])));
+        assert!(bytes((&b"ab"[..], &b"c"[..])) != bytes(&b"abc"[..]));
+        assert!(bytes(vec![&b"ab"[..], &b"c"[..]]) != bytes(vec![&b"a"[..], &b"bc"[..]]));
+        assert!(bytes((&[1u16][..], &[2u16][..])) != bytes((&[1u16, 2][..], &[0u16; 0][..])));
+    }
+}
@@ -1,5 +1,9 @@
 pub mod abc;
 pub mod double;
+mod casefold;
+mod impls;
+pub mod normalize;
+pub mod stream;
 
 pub mod defaulthash;
 pub mod crc32;
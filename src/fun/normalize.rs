@@ -0,0 +1,404 @@
+//! Wrappers, normalizing data before hashing.
+//!
+//! Text wrappers are hashed exactly like the normalized `&str` would be
+//! (the length in bytes, then UTF-8 bytes), so `Lowercase("ABC")` and `"abc"` have the same hash.
+//! The text is normalized on the fly and fed to the context through a small buffer,
+//! without allocating a normalized copy. Wrappers can be nested, e.g. `Lowercase(Whitespace(s))`.
+//!
+//! `Nfc` and `Nfkc` require the `unicode-normalization` feature, `CanonicalJson` requires `json`.
+
+#[cfg(feature = "json")]
+use std::io;
+use std::iter::Peekable;
+
+use prelude::*;
+use fun::casefold;
+#[cfg(feature = "json")]
+use fun::stream::ContextWriter;
+
+
+/// Represents a text, which can be normalized before hashing
+pub trait MTText: MTHash {
+    /// Returns an iterator over the chars of the text
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a>;
+}
+
+impl <'a> MTText for &'a str {
+    fn text_chars<'s>(&'s self) -> Box<Iterator<Item=char> + 's> {
+        Box::new(str::chars(self))
+    }
+}
+
+impl MTText for String {
+    fn text_chars<'s>(&'s self) -> Box<Iterator<Item=char> + 's> {
+        Box::new(self.as_str().chars())
+    }
+}
+
+// Hashes a text the same way as `&str` is hashed
+fn hash_text<T: MTText, H: MTContext>(text: &T, state: &mut H) {
+    let len: usize = text.text_chars().map(char::len_utf8).sum();
+    (len as u64).hash(state);
+
+    let mut buf = [0u8; 256];
+    let mut pos = 0;
+    for ch in text.text_chars() {
+        if pos + 4 > buf.len() {
+            state.update(&buf[.. pos]);
+            pos = 0;
+        }
+        pos += ch.encode_utf8(&mut buf[pos ..]).len();
+    }
+    state.update(&buf[.. pos]);
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Converts the text to lower case (full Unicode mapping, without context).
+/// It is not case folding: `Lowercase("STRASSE")` and `Lowercase("straße")` differ, use `CaseFold`
+/// to hash texts, which differ only in case, the same way
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Lowercase<T>(pub T) where T: MTText;
+
+impl <T> MTText for Lowercase<T> where T: MTText {
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a> {
+        Box::new(self.0.text_chars().flat_map(char::to_lowercase))
+    }
+}
+
+impl <T> MTHash for Lowercase<T> where T: MTText {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        hash_text(self, state)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Applies the full Unicode case folding: `CaseFold("Straße")` is hashed as `"strasse"`,
+/// the final sigma as the ordinary one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaseFold<T>(pub T) where T: MTText;
+
+impl <T> MTText for CaseFold<T> where T: MTText {
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a> {
+        Box::new(self.0.text_chars().flat_map(casefold::fold))
+    }
+}
+
+impl <T> MTHash for CaseFold<T> where T: MTText {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        hash_text(self, state)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Collapses every run of whitespace into a single space and trims the text from both ends
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Whitespace<T>(pub T) where T: MTText;
+
+impl <T> MTText for Whitespace<T> where T: MTText {
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a> {
+        Box::new(CollapseWhitespace { chars: self.0.text_chars(), started: false, next: None })
+    }
+}
+
+impl <T> MTHash for Whitespace<T> where T: MTText {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        hash_text(self, state)
+    }
+}
+
+struct CollapseWhitespace<I> where I: Iterator<Item=char> {
+    chars: I,
+    // a non-whitespace char was already emitted
+    started: bool,
+    // a char, delayed by the emitted space
+    next: Option<char>,
+}
+
+impl <I> Iterator for CollapseWhitespace<I> where I: Iterator<Item=char> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        if let Some(ch) = self.next.take() {
+            return Some(ch);
+        }
+        let mut space = false;
+        loop {
+            let ch = self.chars.next()?;
+            if ch.is_whitespace() {
+                space = self.started;
+            } else if space {
+                self.next = Some(ch);
+                return Some(' ');
+            } else {
+                self.started = true;
+                return Some(ch);
+            }
+        }
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Converts `\r\n` and `\r` line endings to `\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineEndings<T>(pub T) where T: MTText;
+
+impl <T> MTText for LineEndings<T> where T: MTText {
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a> {
+        Box::new(NormalizeLineEndings { chars: self.0.text_chars().peekable() })
+    }
+}
+
+impl <T> MTHash for LineEndings<T> where T: MTText {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        hash_text(self, state)
+    }
+}
+
+struct NormalizeLineEndings<I> where I: Iterator<Item=char> {
+    chars: Peekable<I>,
+}
+
+impl <I> Iterator for NormalizeLineEndings<I> where I: Iterator<Item=char> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        match self.chars.next()? {
+            '\r' => {
+                if self.chars.peek() == Some(&'\n') {
+                    self.chars.next();
+                }
+                Some('\n')
+            },
+            ch => Some(ch),
+        }
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Converts the text to the Unicode Normalization Form C
+#[cfg(feature = "unicode-normalization")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nfc<T>(pub T) where T: MTText;
+
+#[cfg(feature = "unicode-normalization")]
+impl <T> MTText for Nfc<T> where T: MTText {
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a> {
+        use unicode_normalization::UnicodeNormalization;
+        Box::new(self.0.text_chars().nfc())
+    }
+}
+
+#[cfg(feature = "unicode-normalization")]
+impl <T> MTHash for Nfc<T> where T: MTText {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        hash_text(self, state)
+    }
+}
+
+/// Converts the text to the Unicode Normalization Form KC
+#[cfg(feature = "unicode-normalization")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nfkc<T>(pub T) where T: MTText;
+
+#[cfg(feature = "unicode-normalization")]
+impl <T> MTText for Nfkc<T> where T: MTText {
+    fn text_chars<'a>(&'a self) -> Box<Iterator<Item=char> + 'a> {
+        use unicode_normalization::UnicodeNormalization;
+        Box::new(self.0.text_chars().nfkc())
+    }
+}
+
+#[cfg(feature = "unicode-normalization")]
+impl <T> MTHash for Nfkc<T> where T: MTText {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        hash_text(self, state)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A JSON document, hashed in the canonical form:
+/// object keys are sorted, no insignificant whitespace is written,
+/// numbers with an integral value are written as integers (`1.0` as `1`, `-0.0` as `0`),
+/// other numbers in the shortest form, which reads back to the same `f64`.
+/// Integral values are only normalized within ±2^53, where `f64` keeps them exact.
+///
+/// Hashed like the canonical JSON string would be hashed as `&str`.
+#[cfg(feature = "json")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CanonicalJson(pub ::serde_json::Value);
+
+#[cfg(feature = "json")]
+impl CanonicalJson {
+    pub fn new<T: ::serde::Serialize>(value: &T) -> Result<Self> {
+        let value = ::serde_json::to_value(value).map_err(io::Error::from)?;
+        Ok(CanonicalJson(value))
+    }
+
+    /// Writes the canonical JSON form
+    pub fn write_to<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        write_canonical_json(&self.0, out)
+    }
+}
+
+#[cfg(feature = "json")]
+impl MTHash for CanonicalJson {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        let mut counter = CountingWriter(0);
+        self.write_to(&mut counter).expect("Writing JSON into a counter never fails");
+        counter.0.hash(state);
+        self.write_to(&mut ContextWriter(state)).expect("Writing JSON into a context never fails");
+    }
+}
+
+#[cfg(feature = "json")]
+fn write_canonical_json<W: io::Write>(value: &::serde_json::Value, out: &mut W) -> io::Result<()> {
+    use serde_json::Value;
+    match *value {
+        Value::Array(ref items) => {
+            out.write_all(b"[")?;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.write_all(b",")?;
+                }
+                write_canonical_json(item, out)?;
+            }
+            out.write_all(b"]")
+        },
+        Value::Object(ref map) => {
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(b.0));
+            out.write_all(b"{")?;
+            for (i, (key, item)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.write_all(b",")?;
+                }
+                ::serde_json::to_writer(&mut *out, key).map_err(io::Error::from)?;
+                out.write_all(b":")?;
+                write_canonical_json(item, out)?;
+            }
+            out.write_all(b"}")
+        },
+        Value::Number(ref number) => match number.as_f64() {
+            Some(x) if number.is_f64() && x.fract() == 0.0 && x.abs() <= MAX_EXACT_INTEGER => {
+                write!(out, "{}", x as i64)
+            },
+            _ => ::serde_json::to_writer(out, number).map_err(io::Error::from),
+        },
+        ref scalar => ::serde_json::to_writer(out, scalar).map_err(io::Error::from),
+    }
+}
+
+// Integers up to this value are exactly represented by `f64`
+#[cfg(feature = "json")]
+const MAX_EXACT_INTEGER: f64 = 9007199254740992.0;
+
+// Counts written bytes
+#[cfg(feature = "json")]
+struct CountingWriter(u64);
+
+#[cfg(feature = "json")]
+impl io::Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use fun::sha256::*;
+    use super::*;
+
+    fn hash<V: MTHash>(value: &V) -> Sha256Value {
+        Sha256::eval_hash(value)
+    }
+
+    #[test]
+    fn lowercase() {
+        assert_eq!(hash(&Lowercase("HeLLo, Wörld")), hash(&"hello, wörld"));
+        assert_eq!(hash(&Lowercase("İ".to_string())), hash(&"i\u{307}"));
+    }
+
+    #[test]
+    fn case_fold() {
+        assert_eq!(hash(&CaseFold("Straße")), hash(&"strasse"));
+        assert_eq!(hash(&CaseFold("STRASSE")), hash(&"strasse"));
+        assert_eq!(hash(&CaseFold("ΣΊΣΥΦΟΣ")), hash(&CaseFold("σίσυφος")));
+        assert_eq!(hash(&CaseFold("\u{fb01}")), hash(&"fi"));
+        assert_eq!(hash(&CaseFold("\u{13f8}\u{ab70}")), hash(&"\u{13f0}\u{13a0}"));
+        assert!(hash(&Lowercase("Straße")) != hash(&Lowercase("STRASSE")));
+    }
+
+    #[test]
+    fn whitespace() {
+        assert_eq!(hash(&Whitespace("  a \t\n b  c\n")), hash(&"a b c"));
+        assert_eq!(hash(&Whitespace(" \n ")), hash(&""));
+        assert_eq!(hash(&Whitespace("abc")), hash(&"abc"));
+    }
+
+    #[test]
+    fn line_endings() {
+        assert_eq!(hash(&LineEndings("a\r\nb\rc\n\r\n")), hash(&"a\nb\nc\n\n"));
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(hash(&Lowercase(Whitespace(LineEndings(" A\r\n B ")))), hash(&"a b"));
+    }
+
+    #[test]
+    fn long_text() {
+        let text = "Ab Ё ".repeat(1000);
+        assert_eq!(hash(&Lowercase(text.as_str())), hash(&text.to_lowercase()));
+    }
+
+    #[cfg(feature = "unicode-normalization")]
+    #[test]
+    fn unicode_normalization() {
+        assert_eq!(hash(&Nfc("e\u{301}")), hash(&"\u{e9}"));
+        assert_eq!(hash(&Nfc("\u{e9}")), hash(&"\u{e9}"));
+        assert_eq!(hash(&Nfkc("\u{fb01}")), hash(&"fi"));
+        assert!(hash(&Nfc("\u{fb01}")) != hash(&"fi"));
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn canonical_json() {
+        let a = CanonicalJson(::serde_json::json!({"b": 1, "a": [true, null, {"y": "z", "x": 1.5}]}));
+        assert_eq!(hash(&a), hash(&r#"{"a":[true,null,{"x":1.5,"y":"z"}],"b":1}"#));
+
+        let mut map = ::std::collections::HashMap::new();
+        map.insert("b", 1);
+        map.insert("a", 2);
+        assert_eq!(hash(&CanonicalJson::new(&map).unwrap()), hash(&r#"{"a":2,"b":1}"#));
+
+        let numbers = CanonicalJson(::serde_json::json!([1.0, -0.0, 1.5, 1e300, 2, -3, 0.1]));
+        assert_eq!(hash(&numbers), hash(&"[1,0,1.5,1e+300,2,-3,0.1]"));
+    }
+}
@@ -1,4 +1,6 @@
+use std::borrow::Cow;
 use std::fmt;
+use std::str;
 
 use ring::digest::Context;
 use ring::digest::SHA256;
@@ -70,11 +72,56 @@ impl MTHash for Sha256Value {
     }
 }
 
+impl MTValue for Sha256Value {
+    fn as_bytes(&self) -> Cow<[u8]> {
+        Cow::Borrowed(&self.0[..])
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() != 32 {
+            Err(ParseError::InvalidLength)?;
+        }
+        let mut value: [u8; 32] = Default::default();
+        value.clone_from_slice(bytes);
+        Ok(Sha256Value(value))
+    }
+}
+
+impl fmt::Display for Sha256Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt_slice2hex(f, &self.0[..])
+    }
+}
+
+impl str::FromStr for Sha256Value {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Sha256Value::from_hex(s)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for Sha256Value {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serialization::serialize_value(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de> ::serde::Deserialize<'de> for Sha256Value {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        ::serialization::deserialize_value(deserializer)
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
     use abc::MTAlgorithm;
+    use abc::MTValue;
     use super::Sha256;
+    use super::Sha256Value;
 
     #[test]
     fn sha256_works() {
@@ -88,4 +135,19 @@ mod tests {
         let sample = "SHA256:5a77d1e9612d350b3734f6282259b7ff0a3f87d62cfef5f35e91a5604c0490a3";
         assert_eq!(as_string, sample);
     }
+
+    #[test]
+    fn sha256_value_conversions() {
+        let sample = "a665a45920422f9d417e4867efdc4fb8a04a1f3fff1fa07e998e86f7f7a27ae3";
+        let result = Sha256::eval_hash(&b"123".as_ref());
+        assert_eq!(result.to_string(), sample);
+        assert_eq!(sample.parse::<Sha256Value>().unwrap(), result);
+        assert_eq!(Sha256Value::from_bytes(&result.as_bytes()).unwrap(), result);
+        assert_eq!(Sha256Value::from_base64(result.to_base64()).unwrap(), result);
+
+        assert!(Sha256Value::from_bytes(&result.as_bytes()[1..]).unwrap_err().is_parse_error());
+        assert!(sample[1..].parse::<Sha256Value>().is_err());
+        assert!(sample[2..].parse::<Sha256Value>().is_err());
+        assert!(sample.replace("a", "x").parse::<Sha256Value>().is_err());
+    }
 }
@@ -0,0 +1,212 @@
+//! Streaming of data from `io::Read` sources into a hashing context.
+//!
+//! Data is read through a fixed buffer, so a large file region can be hashed
+//! without being loaded into memory. Streamed data is hashed as raw bytes,
+//! the same way as `&[u8]`, so a region and a slice with the same content have the same hash.
+//!
+//! Reading can fail, so streamed values have to be hashed with `MTHash::try_hash`
+//! (or `MTAlgorithm::try_eval_hash`); I/O errors are returned as `Error::Io`.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::rc::Rc;
+
+use prelude::*;
+
+
+/// The size of the buffer, used to stream data into a context
+pub const STREAM_BUFFER_SIZE: usize = 8192;
+
+
+/// Feeds everything written into a hashing context
+pub struct ContextWriter<'a, H: 'a + MTContext>(pub &'a mut H);
+
+impl <'a, H> io::Write for ContextWriter<'a, H> where H: 'a + MTContext {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+
+/// Reads the reader to the end, feeding the data into the context.
+/// Returns the number of bytes read
+pub fn hash_reader<R: Read, H: MTContext>(reader: &mut R, state: &mut H) -> Result<u64> {
+    let mut buf = [0u8; STREAM_BUFFER_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => Err(e)?,
+        };
+        state.update(&buf[.. n]);
+        total += n as u64;
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A region of a seekable source (usually a file), hashed by streaming
+///
+/// Regions of the same source share it, so they are cheap to clone.
+/// The source is borrowed only while a region is being read.
+pub struct ReadRegion<R> where R: Read + Seek {
+    source: Rc<RefCell<R>>,
+    offset: u64,
+    len: u64,
+}
+
+impl <R> ReadRegion<R> where R: Read + Seek {
+    pub fn new(source: Rc<RefCell<R>>, offset: u64, len: u64) -> Self {
+        ReadRegion { source, offset, len }
+    }
+
+    /// Returns the shared source
+    pub fn source(&self) -> &Rc<RefCell<R>> {
+        &self.source
+    }
+
+    /// Returns the offset of the region in the source
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Returns the length of the region in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Returns true if the region is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Reads the whole region into memory
+    pub fn read_to_vec(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.len as usize);
+        let mut source = self.source.borrow_mut();
+        source.seek(SeekFrom::Start(self.offset))?;
+        (&mut *source).take(self.len).read_to_end(&mut buf)?;
+        check_region_len(buf.len() as u64, self.len)?;
+        Ok(buf)
+    }
+}
+
+// Checks that the whole region has been read
+fn check_region_len(read: u64, len: u64) -> Result<()> {
+    if read != len {
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "The source is shorter than the region"))?;
+    }
+    Ok(())
+}
+
+impl <R> MTHash for ReadRegion<R> where R: Read + Seek {
+    /// Panics on I/O errors; use `try_hash` instead
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        self.try_hash(state).expect("ReadRegion can not be read; use MTAlgorithm::try_eval_hash to handle errors")
+    }
+
+    fn try_hash<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        let mut source = self.source.borrow_mut();
+        source.seek(SeekFrom::Start(self.offset))?;
+        let read = hash_reader(&mut (&mut *source).take(self.len), state)?;
+        check_region_len(read, self.len)
+    }
+
+    fn hash_nested<H: MTContext>(&self, state: &mut H) {
+        self.try_hash_nested(state).expect("ReadRegion can not be read; use MTAlgorithm::try_eval_hash to handle errors")
+    }
+
+    fn try_hash_nested<H: MTContext>(&self, state: &mut H) -> Result<()> {
+        self.len.hash(state);
+        self.try_hash(state)
+    }
+}
+
+impl <R> Clone for ReadRegion<R> where R: Read + Seek {
+    fn clone(&self) -> Self {
+        ReadRegion { source: self.source.clone(), offset: self.offset, len: self.len }
+    }
+}
+
+/// Regions are equal if they cover the same range of the same source
+impl <R> PartialEq for ReadRegion<R> where R: Read + Seek {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.source, &other.source) && self.offset == other.offset && self.len == other.len
+    }
+}
+
+impl <R> Eq for ReadRegion<R> where R: Read + Seek {}
+
+impl <R> fmt::Debug for ReadRegion<R> where R: Read + Seek {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReadRegion{{offset: {}, len: {}}}", self.offset, self.len)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::io::Write;
+
+    use fun::sha256::*;
+    use super::*;
+
+    fn source(data: Vec<u8>) -> Rc<RefCell<Cursor<Vec<u8>>>> {
+        Rc::new(RefCell::new(Cursor::new(data)))
+    }
+
+    #[test]
+    fn hash_reader_matches_slice() {
+        let data: Vec<u8> = (0 .. STREAM_BUFFER_SIZE * 3 + 17).map(|x| x as u8).collect();
+        let mut context = Sha256Context::new();
+        assert_eq!(hash_reader(&mut &data[..], &mut context).unwrap(), data.len() as u64);
+        assert_eq!(context.finish(), Sha256::eval_hash(&&data[..]));
+    }
+
+    #[test]
+    fn context_writer() {
+        let mut context = Sha256Context::new();
+        write!(ContextWriter(&mut context), "abc{}", 1).unwrap();
+        assert_eq!(context.finish(), Sha256::eval_hash(&&b"abc1"[..]));
+    }
+
+    #[test]
+    fn read_region() {
+        let data: Vec<u8> = (0 .. 20000).map(|x| (x % 251) as u8).collect();
+        let source = source(data.clone());
+        let a = ReadRegion::new(source.clone(), 100, 10000);
+        let b = ReadRegion::new(source.clone(), 10100, 9900);
+        assert_eq!(Sha256::try_eval_hash(&a).unwrap(), Sha256::eval_hash(&&data[100 .. 10100]));
+        assert_eq!(Sha256::try_eval_hash(&b).unwrap(), Sha256::eval_hash(&&data[10100 ..]));
+        assert_eq!(a.read_to_vec().unwrap(), &data[100 .. 10100]);
+        assert_eq!(Sha256::try_eval_hash(&(a.clone(), b.clone())).unwrap(), Sha256::eval_hash(&(&data[100 .. 10100], &data[10100 ..])));
+        assert!(Sha256::try_eval_hash(&(a.clone(), b.clone())).unwrap() != Sha256::eval_hash(&&data[100 ..]));
+        assert_eq!(a, a.clone());
+        assert!(a != b);
+    }
+
+    #[test]
+    fn read_region_errors() {
+        let region = ReadRegion::new(source(vec![1, 2, 3]), 1, 10);
+        assert!(Sha256::try_eval_hash(&region).unwrap_err().is_io_error());
+        assert!(region.read_to_vec().unwrap_err().is_io_error());
+        assert!(Sha256::try_eval_hash(&vec![region]).is_err());
+    }
+}
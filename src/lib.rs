@@ -1,11 +1,28 @@
 extern crate crc;
 extern crate ring;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(any(feature = "serde_json", all(test, feature = "serde")))]
+extern crate serde_json;
+#[cfg(feature = "unicode-normalization")]
+extern crate unicode_normalization;
+
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_cbor;
+#[cfg(test)]
+extern crate tempfile;
 
 pub mod abc;
+pub mod cache;
+pub mod combined;
 pub mod data_storage;
 pub mod error;
 pub mod fun;
 pub mod merkle_tree;
+#[cfg(feature = "serde")]
+pub mod serialization;
 pub mod tree_storage;
 pub mod util;
 
@@ -1 +1,7 @@
-// use abc::*;
+use fun::abc::MTHash;
+
+
+/// Represents a data block, which carries an amount (summed up by `MerkleSumTree`)
+pub trait MTAmount: MTHash {
+    fn amount(&self) -> u64;
+}
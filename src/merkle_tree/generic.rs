@@ -1,32 +1,66 @@
+use std::cmp;
+use std::collections::BTreeMap;
+
+use merkle_tree::journal::{Journal, JournalRecord};
+use merkle_tree::proof::InclusionProof;
+use merkle_tree::transaction::{Operation, Transaction};
 use prelude::*;
+use tree_storage::buffered::BufferedTreeStorage;
+
+
+/// The number of children of a node, unless specified
+pub const DEFAULT_ARITY: usize = 2;
 
 
-#[derive(Debug, Default)]
-pub struct MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
+#[derive(Debug)]
+pub struct MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorageReadonly {
     data: D,
     tree: T,
+    // The number of children of a node; the last node of a level may have fewer children,
+    // then the last child is repeated to fill the group
+    arity: usize,
 }
 
-impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
+impl <D, T> Default for MerkleTree<D, T> where D: DataStorageReadonly + Default, T: TreeStorageReadonly + Default {
+    fn default() -> Self {
+        MerkleTree::new_unchecked(D::default(), T::default())
+    }
+}
+
+impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorageReadonly {
     /// Creates an instance without checking of data integrity
     pub fn new_unchecked(data: D, tree: T) -> Self {
-        MerkleTree { data, tree }
+        MerkleTree::new_unchecked_with_arity(data, tree, DEFAULT_ARITY)
+    }
+
+    /// Creates an instance with the number of children of a node (2 by default)
+    /// without checking of data integrity: the tree has to be built with the same arity
+    ///
+    /// # Panics
+    ///
+    /// Panics if arity is less than 2
+    pub fn new_unchecked_with_arity(data: D, tree: T, arity: usize) -> Self {
+        assert!(arity >= 2, "Arity must be at least 2");
+        MerkleTree { data, tree, arity }
+    }
+
+    /// Returns the number of children of a node
+    pub fn arity(&self) -> usize {
+        self.arity
     }
 
     /// Creates an instance and checks both the data and the tree.
     /// The same as to call `new_unchecked` and then `check_tree` and `check_data`
     pub fn new_and_check(data: D, tree: T) -> Result<Self> {
-        let mt = MerkleTree::new_unchecked(data, tree);
-        mt.check_tree()?;
-        mt.check_data()?;
-        Ok(mt)
+        MerkleTree::new_and_check_with_arity(data, tree, DEFAULT_ARITY)
     }
 
-    /// Creates an instance and rebuilds the tree.
-    /// The same as to call `new_unchecked` and then `rebuild`
-    pub fn new_and_rebuild(data: D, tree: T) -> Result<Self> {
-        let mut mt = MerkleTree { data, tree };
-        mt.rebuild()?;
+    /// Creates an instance with the arity and checks both the data and the tree,
+    /// a tree built with another arity does not pass the check
+    pub fn new_and_check_with_arity(data: D, tree: T, arity: usize) -> Result<Self> {
+        let mt = MerkleTree::new_unchecked_with_arity(data, tree, arity);
+        mt.check_tree()?;
+        mt.check_data()?;
         Ok(mt)
     }
 
@@ -39,6 +73,11 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
         &self.tree
     }
 
+    /// Returns the data storage and the tree storage
+    pub fn into_parts(self) -> (D, T) {
+        (self.data, self.tree)
+    }
+
     /// For corruption tests only
     #[cfg(test)]
     pub fn data_mut(&mut self) -> &mut D {
@@ -50,48 +89,6 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
         &mut self.tree
     }
 
-    /// Rebuilds full tree from scratch, using the current state of the data
-    /// Will take a long time for a large dataset.
-    pub fn rebuild(&mut self) -> Result<()> {
-        if self.data.is_empty()? {
-            return self.tree.clear_and_reserve(&[]);
-        }
-
-        let mut sizes = Vec::<usize>::new();
-        let mut len = self.data.len()?;
-        let mut layer_buffer = Vec::with_capacity(len);
-
-        loop {
-            sizes.push(len);
-            if len == 1 {
-                break;
-            }
-            len = len / 2 + len % 2;
-        }
-        self.tree.clear_and_reserve(&sizes)?;
-
-        for block in self.data.iter()? {
-            let hash = T::Algorithm::eval_hash(&block?);
-            layer_buffer.push(hash);
-        }
-        self.tree.extend_from_slice(0, &layer_buffer)?;
-
-        if sizes.len() < 2 {
-            return Ok(());
-        }
-
-        for level in 0 .. sizes.len() - 1 {
-            layer_buffer.clear();
-            for chunk in self.tree.iter_level_by_pair(level)? {
-                let hash = T::Algorithm::eval_hash(&chunk?);
-                layer_buffer.push(hash);
-            }
-            self.tree.extend_from_slice(level + 1, &layer_buffer)?;
-        }
-
-        Ok(())
-    }
-
     /// Checks if the data corresponds to the checksum.
     /// Will take a long time for a large dataset.
     pub fn check_data(&self) -> Result<()> {
@@ -102,12 +99,14 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
         } else if self.data.len()? != self.tree.get_level_len(0)? {
             Err(StateError::InconsistentState)?;
         }
-        for (block, cs) in self.data.iter()?.zip(self.tree.iter_level(0)?) {
-            if T::Algorithm::eval_hash(&block?) != cs? {
+        let mut checksums = self.tree.iter_level(0)?;
+        self.data.for_each_value(|block| {
+            let cs = checksums.next().ok_or(StateError::InconsistentState)??;
+            if T::Algorithm::try_eval_hash(block)? != cs {
                 Err(StateError::DataDoesNotMatchTheChecksum)?;
             }
-        }
-        Ok(())
+            Ok(())
+        })
     }
 
     /// Checks data integrity of the tree.
@@ -120,18 +119,19 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
         }
         for level in 0 .. self.tree.len()? - 1 {
             let source_len = self.tree.get_level_len(level)?;
-            if self.tree.get_level_len(level + 1)? != source_len / 2 + source_len % 2 {
+            if self.tree.get_level_len(level + 1)? != (source_len + self.arity - 1) / self.arity {
                 Err(StateError::InconsistentState)?;
             }
         }
         for level in (0 .. self.tree.len()? - 1).rev() {
-            let source = self.tree.iter_level_by_pair(level)?;
-            let derived = self.tree.iter_level(level + 1)?;
-            for (chunk, cs) in source.zip(derived) {
-                if T::Algorithm::eval_hash(&chunk?) != cs? {
+            let mut derived = self.tree.iter_level(level + 1)?;
+            self.tree.for_each_group(level, self.arity, |group| {
+                let cs = derived.next().ok_or(StateError::InconsistentState)??;
+                if T::Algorithm::eval_hash(&group) != cs {
                     Err(StateError::DataDoesNotMatchTheChecksum)?;
                 }
-            }
+                Ok(())
+            })?;
         }
         Ok(())
     }
@@ -139,7 +139,7 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
     /// Checks the proof for a chain from a data block to the root
     /// Returns found chain
     pub fn audit_proof(&self, mut index: usize) -> Result<Vec<<T::Algorithm as MTAlgorithm>::Value>> {
-        let data_hash = T::Algorithm::eval_hash(&self.data.get(index)?);
+        let data_hash = self.data.with_value(index, |block| T::Algorithm::try_eval_hash(block))?;
         let mut hash = self.tree.get_value(0, index)?;
         if hash != data_hash {
             Err(StateError::DataDoesNotMatchTheChecksum)?;
@@ -147,19 +147,10 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
         let mut path = Vec::with_capacity(self.tree.len()?);
         path.push(hash.clone());
         for level in 0 .. self.tree.len()? - 1 {
-            //let source = self.tree.get_value(level, index).unwrap();
-            let index2 = index + (index + 1) % 2 - index % 2;
-            let hash2 = self.tree.get_value(level, index2).iob_is_ok()?;
-            let pair = match index < index2 {
-                false => (hash2.ok_or(INDEX_IS_OUT_OF_BOUNDS)?, hash),
-                true => {
-                    let hash2 = hash2.unwrap_or_else(|| hash.clone());
-                    (hash, hash2)
-                },
-            };
-            index /= 2;
+            let group = self.get_group(level, index)?;
+            index /= self.arity;
             hash = self.tree.get_value(level + 1, index)?;
-            if hash != T::Algorithm::eval_hash(&pair) {
+            if hash != T::Algorithm::eval_hash(&&group[..]) {
                 Err(StateError::DataDoesNotMatchTheChecksum)?;
             }
             path.push(hash.clone());
@@ -173,13 +164,89 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
         if self.data.len()? <= index {
             Err(AccessError::IndexIsOutOfBounds)?;
         }
+        let arity = self.arity;
         Ok(Box::new((0 .. self.tree.len()?)
             .map(move |level| {
-                index = index / 2;
+                index = index / arity;
                 (level, index)
             })))
     }
 
+    /// Returns a proof of inclusion of the data block, which can be verified without the tree
+    pub fn inclusion_proof(&self, index: usize) -> Result<InclusionProof<<T::Algorithm as MTAlgorithm>::Value>> {
+        InclusionProof::from_tree(&self.tree, self.arity, index)
+    }
+
+    /// Returns the root hash of the first `size` data blocks, or None if `size` is 0.
+    /// Nodes, which cover only these blocks, are taken from the tree, others are recomputed:
+    /// that takes O(arity * log n) hashes
+    pub fn root_of_prefix(&self, size: usize) -> Result<Option<<T::Algorithm as MTAlgorithm>::Value>> {
+        let sizes = self.prefix_sizes(size)?;
+        match sizes.len() {
+            0 => Ok(None),
+            len => self.prefix_node(&sizes, len - 1, 0).map(Some),
+        }
+    }
+
+    /// Returns a proof of inclusion of the data block into the first `size` data blocks,
+    /// which can be verified against the root, returned by `root_of_prefix(size)`
+    pub fn inclusion_proof_of_prefix(&self, index: usize, size: usize) -> Result<InclusionProof<<T::Algorithm as MTAlgorithm>::Value>> {
+        let sizes = self.prefix_sizes(size)?;
+        if index >= size {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        let mut siblings = Vec::with_capacity(sizes.len() * (self.arity - 1));
+        let mut index2 = index;
+        for level in 0 .. sizes.len() - 1 {
+            let start = index2 - index2 % self.arity;
+            for sibling in (start .. sizes[level].min(start + self.arity)).filter(|&i| i != index2) {
+                siblings.push(self.prefix_node(&sizes, level, sibling)?);
+            }
+            index2 /= self.arity;
+        }
+        Ok(InclusionProof { index, size, arity: self.arity, siblings })
+    }
+
+    // Returns widths of levels of the tree of the first `size` data blocks
+    fn prefix_sizes(&self, mut size: usize) -> Result<Vec<usize>> {
+        let current = match self.tree.is_empty()? {
+            true => 0,
+            false => self.tree.get_level_len(0)?,
+        };
+        if size > current {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        let mut sizes = Vec::new();
+        while size > 0 {
+            sizes.push(size);
+            if size == 1 {
+                break;
+            }
+            size = (size + self.arity - 1) / self.arity;
+        }
+        Ok(sizes)
+    }
+
+    // Returns the node of the tree of the first `sizes[0]` data blocks
+    fn prefix_node(&self, sizes: &[usize], level: usize, index: usize) -> Result<<T::Algorithm as MTAlgorithm>::Value> {
+        // The node does not depend on blocks after the prefix
+        let covered = self.arity.checked_pow(level as u32)
+            .and_then(|span| span.checked_mul(index + 1))
+            .map_or(false, |end| end <= sizes[0]);
+        if covered || self.tree.get_level_len(0)? == sizes[0] {
+            return self.tree.get_value(level, index);
+        }
+        let start = index * self.arity;
+        let end = sizes[level - 1].min(start + self.arity);
+        let group = collect_group(start, end, self.arity, |i| self.prefix_node(sizes, level - 1, i))?;
+        Ok(T::Algorithm::eval_hash(&&group[..]))
+    }
+
+    // Returns the group of values, containing the index, padded by the last value
+    fn get_group(&self, level: usize, index: usize) -> Result<Vec<<T::Algorithm as MTAlgorithm>::Value>> {
+        level_group(&self.tree, level, index, self.arity)
+    }
+
     /// Returns the root hash, or None if tree is empty.
     pub fn get_root(&self) -> Result<Option<<T::Algorithm as MTAlgorithm>::Value>> {
         self.tree.get_root()
@@ -187,8 +254,155 @@ impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
 }
 
 
+impl <D, T> MerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage {
+    fn check_if_tree_is_writable(&self) -> Result<()> {
+        if self.tree.is_writeable() {
+            Ok(())
+        } else {
+            Err(Error::new_ro("Tree storage is not writable"))
+        }
+    }
+
+    /// Creates an instance and rebuilds the tree.
+    /// The same as to call `new_unchecked` and then `rebuild`
+    pub fn new_and_rebuild(data: D, tree: T) -> Result<Self> {
+        MerkleTree::new_and_rebuild_with_arity(data, tree, DEFAULT_ARITY)
+    }
+
+    /// Creates an instance with the arity and rebuilds the tree
+    pub fn new_and_rebuild_with_arity(data: D, tree: T, arity: usize) -> Result<Self> {
+        let mut mt = MerkleTree::new_unchecked_with_arity(data, tree, arity);
+        mt.rebuild()?;
+        Ok(mt)
+    }
+
+    /// Rebuilds full tree from scratch, using the current state of the data
+    /// Will take a long time for a large dataset. If it fails, the tree storage is rolled back
+    pub fn rebuild(&mut self) -> Result<()> {
+        self.check_if_tree_is_writable()?;
+        match self.rebuild_tree() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.with_rollback(self.tree.rollback())),
+        }
+    }
+
+    /// Rebuilds full tree from scratch, reading the data once.
+    /// Unlike `rebuild`, keeps in memory only the groups of nodes, which are not complete yet,
+    /// at most `arity` values per level, and writes every node as soon as it is computed
+    pub fn rebuild_streaming(&mut self) -> Result<()> {
+        self.check_if_tree_is_writable()?;
+        match self.rebuild_tree_streaming() {
+            Ok(()) => Ok(()),
+            Err(e) => Err(e.with_rollback(self.tree.rollback())),
+        }
+    }
+
+    fn rebuild_tree(&mut self) -> Result<()> {
+        if self.data.is_empty()? {
+            self.tree.clear_and_reserve(&[])?;
+            return self.tree.commit();
+        }
+
+        let sizes = level_sizes(self.data.len()?, self.arity);
+        let mut layer_buffer = Vec::with_capacity(sizes[0]);
+        self.tree.clear_and_reserve(&sizes)?;
+
+        self.data.for_each_value(|block| {
+            layer_buffer.push(T::Algorithm::try_eval_hash(block)?);
+            Ok(())
+        })?;
+        self.tree.extend_from_slice(0, &layer_buffer)?;
+
+        if sizes.len() < 2 {
+            return self.tree.commit();
+        }
+
+        for level in 0 .. sizes.len() - 1 {
+            layer_buffer.clear();
+            self.tree.for_each_group(level, self.arity, |group| {
+                layer_buffer.push(T::Algorithm::eval_hash(&group));
+                Ok(())
+            })?;
+            self.tree.extend_from_slice(level + 1, &layer_buffer)?;
+        }
+
+        self.tree.commit()
+    }
+
+    fn rebuild_tree_streaming(&mut self) -> Result<()> {
+        if self.data.is_empty()? {
+            self.tree.clear_and_reserve(&[])?;
+            return self.tree.commit();
+        }
+
+        let arity = self.arity;
+        let sizes = level_sizes(self.data.len()?, arity);
+        let mut pending = vec![Vec::with_capacity(arity); sizes.len()];
+        self.tree.clear_and_reserve(&sizes)?;
+        {
+            let tree = &mut self.tree;
+            self.data.for_each_value(|block| {
+                push_streamed(tree, &mut pending, 0, T::Algorithm::try_eval_hash(block)?, arity)
+            })?;
+        }
+
+        // The last group of a level is padded by its last value
+        for level in 0 .. sizes.len() - 1 {
+            if let Some(last) = pending[level].last().cloned() {
+                pending[level].resize(arity, last);
+                let hash = T::Algorithm::eval_hash(&&pending[level][..]);
+                pending[level].clear();
+                push_streamed(&mut self.tree, &mut pending, level + 1, hash, arity)?;
+            }
+        }
+        if self.tree.get_level_len(0)? != sizes[0] {
+            Err(StateError::InconsistentState)?;
+        }
+
+        self.tree.commit()
+    }
+}
+
+
+impl <D, T> MerkleTree<D, BufferedTreeStorage<T>> where D: DataStorageReadonly, T: TreeStorage {
+    /// Writes all the buffered changes of the tree
+    pub fn flush(&mut self) -> Result<()> {
+        self.tree.flush()
+    }
+}
+
+
+/// Serialized as a tuple of the data storage, the tree storage and the arity
+#[cfg(feature = "serde")]
+impl <D, T> ::serde::Serialize for MerkleTree<D, T>
+    where D: DataStorageReadonly + ::serde::Serialize, T: TreeStorageReadonly + ::serde::Serialize
+{
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serde::Serialize::serialize(&(&self.data, &self.tree, self.arity), serializer)
+    }
+}
+
+/// Both the data and the tree are checked after deserialization
+#[cfg(feature = "serde")]
+impl <'de, D, T> ::serde::Deserialize<'de> for MerkleTree<D, T>
+    where D: DataStorageReadonly + ::serde::Deserialize<'de>, T: TreeStorageReadonly + ::serde::Deserialize<'de>
+{
+    fn deserialize<DE: ::serde::Deserializer<'de>>(deserializer: DE) -> ::std::result::Result<Self, DE::Error> {
+        let (data, tree, arity) = ::serde::Deserialize::deserialize(deserializer)?;
+        if arity < 2 {
+            return Err(::serde::de::Error::custom("Arity must be at least 2"));
+        }
+        let mt = MerkleTree::new_unchecked_with_arity(data, tree, arity);
+        mt.check_tree().map_err(::serde::de::Error::custom)?;
+        mt.check_data().map_err(::serde::de::Error::custom)?;
+        Ok(mt)
+    }
+}
+
+
 impl <D, T> MerkleTree<D, T> where D: DataStorage, T: TreeStorage {
-    fn check_if_data_is_writable(&self) -> Result<()> {
+    fn check_if_writable(&self) -> Result<()> {
+        self.check_if_tree_is_writable()?;
         if self.data.is_writeable() {
             Ok(())
         } else {
@@ -196,19 +410,196 @@ impl <D, T> MerkleTree<D, T> where D: DataStorage, T: TreeStorage {
         }
     }
 
+    /// Creates an instance, completes a transaction, left in the journal, if any,
+    /// and checks the data and the tree
+    pub fn new_and_recover<J>(data: D, tree: T, journal: &mut J) -> Result<Self> where J: Journal<Value=D::DataValue> {
+        MerkleTree::new_and_recover_with_arity(data, tree, journal, DEFAULT_ARITY)
+    }
+
+    /// The same as `new_and_recover` for a tree built with the arity
+    pub fn new_and_recover_with_arity<J>(data: D, tree: T, journal: &mut J, arity: usize) -> Result<Self>
+        where J: Journal<Value=D::DataValue>
+    {
+        let mut mt = MerkleTree::new_unchecked_with_arity(data, tree, arity);
+        mt.recover(journal)?;
+        mt.check_tree()?;
+        mt.check_data()?;
+        Ok(mt)
+    }
+
     /// Clears all data
     pub fn clear(&mut self) -> Result<()> {
+        self.check_if_writable()?;
         self.data.clear()?;
-        self.tree.clear()
+        self.tree.clear()?;
+        self.tree.commit()
     }
 
     /// Appends a new data block at the back of data chain
     pub fn push(&mut self, data: D::DataValue) -> Result<()> {
-        // TODO ensure that tree storage is writable
-        self.check_if_data_is_writable()?;
-        let hash = T::Algorithm::eval_hash(&data);
-        self.data.push(data).unwrap();
-        self.push_hash(0, hash)
+        let mut transaction = Transaction::new();
+        transaction.push(data);
+        self.apply(transaction)
+    }
+
+    /// Replaces the data block at the index
+    pub fn update(&mut self, index: usize, data: D::DataValue) -> Result<()> {
+        let mut transaction = Transaction::new();
+        transaction.update(index, data);
+        self.apply(transaction)
+    }
+
+    /// Applies all the operations of the transaction to both storages.
+    /// If any of them fails, both storages are rolled back to the previous state
+    pub fn apply(&mut self, transaction: Transaction<D::DataValue>) -> Result<()> {
+        let (record, hashes) = self.begin(transaction)?;
+        match self.apply_operations(record.operations.clone(), hashes) {
+            Ok(()) => self.tree.commit(),
+            Err(e) => Err(e.with_rollback(self.rollback(&record))),
+        }
+    }
+
+    /// The same as `apply`, but the transaction is written to the journal before any change,
+    /// and the journal is cleared after. If the process is interrupted in between,
+    /// the transaction is completed by `recover` on the next start
+    pub fn apply_journaled<J>(&mut self, journal: &mut J, transaction: Transaction<D::DataValue>) -> Result<()>
+        where J: Journal<Value=D::DataValue>
+    {
+        let (record, hashes) = self.begin(transaction)?;
+        journal.write(&record)?;
+        match self.apply_operations(record.operations.clone(), hashes) {
+            Ok(()) => self.tree.commit()?,
+            Err(e) => return Err(e.with_rollback(self.rollback(&record).and_then(|()| journal.clear()))),
+        }
+        journal.clear()
+    }
+
+    /// Completes a transaction, left in the journal: both storages are rolled back
+    /// to the state before the transaction, and the transaction is applied again.
+    /// Returns false if the journal is empty
+    pub fn recover<J>(&mut self, journal: &mut J) -> Result<bool> where J: Journal<Value=D::DataValue> {
+        let record = match journal.read()? {
+            Some(record) => record,
+            None => return Ok(false),
+        };
+        self.check_if_writable()?;
+        self.rollback(&record)?;
+        let hashes = hash_operations::<T::Algorithm, _>(&record.operations)?;
+        match self.apply_operations(record.operations.clone(), hashes) {
+            Ok(()) => self.tree.commit()?,
+            Err(e) => return Err(e.with_rollback(self.rollback(&record).and_then(|()| journal.clear()))),
+        }
+        journal.clear()?;
+        Ok(true)
+    }
+
+    // Hashes data blocks and records the state, needed to roll the transaction back
+    fn begin(&self, transaction: Transaction<D::DataValue>)
+        -> Result<(JournalRecord<D::DataValue>, Vec<<T::Algorithm as MTAlgorithm>::Value>)>
+    {
+        self.check_if_writable()?;
+        let operations = transaction.into_operations();
+        let hashes = hash_operations::<T::Algorithm, _>(&operations)?;
+        let data_len = self.data.len()?;
+        let tree_sizes = (0 .. self.tree.len()?)
+            .map(|level| self.tree.get_level_len(level))
+            .collect::<Result<Vec<_>>>()?;
+        let mut replaced = BTreeMap::new();
+        for operation in &operations {
+            let (from, to) = match *operation {
+                Operation::Push(_) => continue,
+                Operation::Update(index, _) => (index, index + 1),
+                Operation::Truncate(len) => (len, data_len),
+            };
+            for index in from .. cmp::min(to, data_len) {
+                if !replaced.contains_key(&index) {
+                    replaced.insert(index, self.data.get(index)?);
+                }
+            }
+        }
+        let replaced = replaced.into_iter().collect();
+        Ok((JournalRecord { data_len, tree_sizes, replaced, operations }, hashes))
+    }
+
+    // Applies operations one by one
+    fn apply_operations(
+        &mut self,
+        operations: Vec<Operation<D::DataValue>>,
+        hashes: Vec<<T::Algorithm as MTAlgorithm>::Value>
+    ) -> Result<()> {
+        let mut hashes = hashes.into_iter();
+        for operation in operations {
+            match operation {
+                Operation::Push(data) => {
+                    let hash = hashes.next().ok_or(StateError::InconsistentState)?;
+                    self.data.push(data)?;
+                    self.push_hash(0, hash)?;
+                },
+                Operation::Update(index, data) => {
+                    let hash = hashes.next().ok_or(StateError::InconsistentState)?;
+                    self.data.set(index, data)?;
+                    self.tree.set_value(0, index, hash)?;
+                    self.update_path(index)?;
+                },
+                Operation::Truncate(len) => {
+                    if len < self.data.len()? {
+                        self.data.truncate(len)?;
+                        self.truncate_tree(len)?;
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    // Restores data blocks and recomputes changed nodes: replaced branches and the last one.
+    // Blocks, cut off by the transaction, are pushed back
+    fn rollback(&mut self, record: &JournalRecord<D::DataValue>) -> Result<()> {
+        self.data.truncate(record.data_len)?;
+        let len = self.data.len()?;
+        if len == record.data_len {
+            self.tree.truncate(&record.tree_sizes)?;
+        } else {
+            self.truncate_tree(len)?;
+        }
+        let mut indexes = Vec::with_capacity(record.replaced.len() + 1);
+        for &(index, ref data) in &record.replaced {
+            if index < len {
+                self.data.set(index, data.clone())?;
+                indexes.push(index);
+            } else {
+                self.data.push(data.clone())?;
+                self.push_hash(0, T::Algorithm::try_eval_hash(data)?)?;
+            }
+        }
+        if len == record.data_len && len > 0 {
+            indexes.push(len - 1);
+        }
+        for index in indexes {
+            let hash = self.data.with_value(index, |block| T::Algorithm::try_eval_hash(block))?;
+            self.tree.set_value(0, index, hash)?;
+            self.update_path(index)?;
+        }
+        Ok(())
+    }
+
+    // Cuts the tree down to `len` leaves and recomputes the last branch
+    fn truncate_tree(&mut self, len: usize) -> Result<()> {
+        if len == 0 {
+            return self.tree.clear();
+        }
+        self.tree.truncate(&level_sizes(len, self.arity))?;
+        self.update_path(len - 1)
+    }
+
+    // Recomputes all the nodes above the leaf
+    fn update_path(&mut self, mut index: usize) -> Result<()> {
+        for level in 0 .. self.tree.len()? - 1 {
+            let hash = T::Algorithm::eval_hash(&&self.get_group(level, index)?[..]);
+            index /= self.arity;
+            self.tree.set_value(level + 1, index, hash)?;
+        }
+        Ok(())
     }
 
     // Appends new hash to the level, creating a level if it did not exist
@@ -230,35 +621,42 @@ impl <D, T> MerkleTree<D, T> where D: DataStorage, T: TreeStorage {
         if len == 1 {
             return Ok(());
         }
-        let hash = {
-            let layer = &self.tree.get_level(level)?;
-            let last = len - 1;
-            let pair = ( layer.get(last - last % 2)?, layer.get(last)? );
-            T::Algorithm::eval_hash(&pair)
-        };
+        let hash = T::Algorithm::eval_hash(&&self.get_group(level, len - 1)?[..]);
         let next_level = level + 1;
-        if layer_is_last || pushed && len % 2 == 1 {
+        if layer_is_last || pushed && (len - 1) % self.arity == 0 {
             self.push_hash(next_level, hash)
         } else {
             let next_len = self.tree.get_level_len(next_level)?;
             debug_assert!(next_len > 0);
-            *self.tree.get_value_mut(next_level, next_len - 1)? = hash;
+            self.tree.set_value(next_level, next_len - 1, hash)?;
             self.update_branch(next_level, false)
         }
     }
 
-    /// Appends a new data block at the back of data chain
+    /// Appends new data blocks at the back of data chain.
+    /// If any of them fails, both storages are rolled back to the previous state
     pub fn extend<DD: IntoIterator<Item=Result<D::DataValue>>>(&mut self, data: DD) -> Result<()> {
+        let (record, _) = self.begin(Transaction::new())?;
+        match self.extend_unchecked(data) {
+            Ok(true) => self.tree.commit(),
+            Ok(false) => Ok(()),
+            Err(e) => Err(e.with_rollback(self.rollback(&record))),
+        }
+    }
+
+    // Appends data blocks and their hashes; returns false if there were no blocks
+    fn extend_unchecked<DD: IntoIterator<Item=Result<D::DataValue>>>(&mut self, data: DD) -> Result<bool> {
         let len = self.data.len()?;
         self.data.extend(data.into_iter())?;
         let new_len = self.data.len()?;
         if new_len - len == 0 {
-            return Ok(());
+            return Ok(false);
         }
-        let hashes: Vec<_> = self.data.range(len..new_len)?
-            .map(|data| Ok(<T::Algorithm as MTAlgorithm>::eval_hash(&data?)))
+        let hashes: Vec<_> = (len .. new_len)
+            .map(|index| self.data.with_value(index, |block| <T::Algorithm as MTAlgorithm>::try_eval_hash(block)))
             .collect();
-        self.push_hashes_bulk(0, hashes)
+        self.push_hashes_bulk(0, hashes)?;
+        Ok(true)
     }
 
     // Appends new hashes to the level, creating a level if it did not exist
@@ -281,35 +679,91 @@ impl <D, T> MerkleTree<D, T> where D: DataStorage, T: TreeStorage {
         if len - from == 1 {
             return self.update_branch(level, pushed)
         }
-        let mut hashes = self.tree.iter_level_by_pair(level)?.skip(from / 2)
-            .map(|chunk| Ok(<T::Algorithm as MTAlgorithm>::eval_hash(&chunk?)))
-            .collect::<Vec<_>>()
-            .into_iter();
+        let skip = from / self.arity;
+        let mut hashes = Vec::with_capacity((len + self.arity - 1) / self.arity - skip);
+        let mut group_index = 0;
+        self.tree.for_each_group(level, self.arity, |group| {
+            if group_index >= skip {
+                hashes.push(Ok(<T::Algorithm as MTAlgorithm>::eval_hash(&group)));
+            }
+            group_index += 1;
+            Ok(())
+        })?;
+        let mut hashes = hashes.into_iter();
 
         let layer_is_last = self.tree.len()? == level + 1;
         let next_level = level + 1;
 
-        if layer_is_last || pushed && from % 2 == 0 {
+        if layer_is_last || pushed && from % self.arity == 0 {
             self.push_hashes_bulk(next_level, hashes)
         } else {
             let next_len = self.tree.get_level_len(next_level)?;
             debug_assert!(next_len > 0);
-            *self.tree.get_value_mut(next_level, next_len - 1)? = hashes.next().unwrap()?;
+            self.tree.set_value(next_level, next_len - 1, hashes.next().unwrap()?)?;
             self.tree.extend(next_level, hashes)?;
-            self.update_branch_bulk(next_level, from / 2, false)
+            self.update_branch_bulk(next_level, from / self.arity, false)
         }
     }
 }
 
 
+// -------------------------------------------------------------------------------------------------
+
+
+// Hashes data blocks of the operations, which have them
+fn hash_operations<A, V>(operations: &[Operation<V>]) -> Result<Vec<A::Value>> where A: MTAlgorithm, V: MTHash {
+    operations.iter()
+        .filter_map(|operation| operation.value())
+        .map(|value| A::try_eval_hash(value))
+        .collect()
+}
+
+// The widths of levels of the tree of `len` leaves, from the bottom level to the root
+fn level_sizes(mut len: usize, arity: usize) -> Vec<usize> {
+    let mut sizes = vec![len];
+    while len > 1 {
+        len = (len + arity - 1) / arity;
+        sizes.push(len);
+    }
+    sizes
+}
+
+// Writes a node and, if it completes a group, the parent node of the group.
+// `pending` keeps the nodes of the incomplete group of every level
+fn push_streamed<T>(
+    tree: &mut T,
+    pending: &mut [Vec<<T::Algorithm as MTAlgorithm>::Value>],
+    level: usize,
+    hash: <T::Algorithm as MTAlgorithm>::Value,
+    arity: usize,
+) -> Result<()> where T: TreeStorage {
+    tree.push(level, hash.clone())?;
+    pending[level].push(hash);
+    if pending[level].len() < arity {
+        return Ok(());
+    }
+    // The root level can not have a complete group: there are more data blocks, than expected
+    if level + 1 == pending.len() {
+        Err(StateError::InconsistentState)?;
+    }
+    let parent = T::Algorithm::eval_hash(&&pending[level][..]);
+    pending[level].clear();
+    push_streamed(tree, pending, level + 1, parent, arity)
+}
+
+
 #[cfg(test)]
 mod tests {
+    use abc::DataStorageReadonly;
+    use abc::MTAlgorithm;
+    use abc::TreeStorageReadonly;
     use super::MerkleTree;
     use fun::double::DoubleHash;
     use fun::sha256::Sha256;
     use fun::sha256::Sha256Value;
     use data_storage::memory::MemoryReadonlyDataStorage;
     use data_storage::memory::MemoryDataStorage;
+    use tree_storage::memory::MemoryReadonlyTreeStorage;
     use tree_storage::memory::MemoryTreeStorage;
     use util::hex2buf;
 
@@ -434,4 +888,207 @@ mod tests {
         assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
         assert_eq!(a.get_root().unwrap(), c.get_root().unwrap());
     }
+
+    #[test]
+    fn merkle_tree_rebuilds_streaming() {
+        type Tree = MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+
+        let mut a = Tree::default();
+        a.rebuild_streaming().unwrap();
+        assert_eq!(a.get_root().unwrap(), None);
+
+        for &arity in &[2, 3, 4, 16] {
+            for len in 1 .. 70u32 {
+                let data = (0 .. len).collect::<Vec<_>>();
+                let a = Tree::new_and_rebuild_with_arity(MemoryDataStorage::with_data(data.clone()), Default::default(), arity).unwrap();
+                let mut b = Tree::new_unchecked_with_arity(MemoryDataStorage::with_data(data), Default::default(), arity);
+                b.rebuild_streaming().unwrap();
+                assert_eq!(a.tree().levels(), b.tree().levels());
+            }
+        }
+    }
+
+    #[test]
+    fn merkle_tree_readonly_storages() {
+        let a = sample_rw_tree();
+        let b: MerkleTree<MemoryReadonlyDataStorage<&[u8]>, MemoryReadonlyTreeStorage<DoubleHash<Sha256>>>;
+        b = MerkleTree::new_and_check(
+            MemoryReadonlyDataStorage::with_data(&DATA[..]),
+            MemoryReadonlyTreeStorage::with_levels(a.tree().levels()),
+        ).unwrap();
+        assert_eq!(b.get_root().unwrap().unwrap(), sha256(H20));
+        assert!(cmp_proof(&[H02, H11, H20], &b.audit_proof(2).unwrap()));
+
+        // Both storages have to be writable to change the tree
+        let mut c = sample_rw_tree();
+        c.tree_mut().set_writable(false);
+        assert!(c.push(DATA[0]).is_err());
+        assert!(c.rebuild().is_err());
+        assert_eq!(c.data().len().unwrap(), 3);
+        c.tree_mut().set_writable(true);
+        assert!(c.push(DATA[0]).is_ok());
+        c.data_mut().set_writable(false);
+        assert!(c.push(DATA[0]).is_err());
+        assert_eq!(c.tree().get_level_len(0).unwrap(), 4);
+    }
+
+    #[test]
+    fn merkle_tree_borrows_blocks() {
+        use std::cell::Cell;
+        use abc::{MTContext, MTHash};
+
+        thread_local!(static CLONES: Cell<usize> = Cell::new(0));
+
+        #[derive(Debug, PartialEq, Eq)]
+        struct Block(Vec<u8>);
+
+        impl Clone for Block {
+            fn clone(&self) -> Self {
+                CLONES.with(|clones| clones.set(clones.get() + 1));
+                Block(self.0.clone())
+            }
+        }
+
+        impl MTHash for Block {
+            fn hash<H: MTContext>(&self, state: &mut H) {
+                self.0.hash(state)
+            }
+        }
+
+        let data: Vec<_> = (0 .. 10u8).map(|x| Block(vec![x; 4096])).collect();
+        let mut mt: MerkleTree<MemoryDataStorage<Block>, MemoryTreeStorage<Sha256>>;
+        mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data), Default::default()).unwrap();
+        mt.check_data().unwrap();
+        mt.check_tree().unwrap();
+        mt.audit_proof(7).unwrap();
+        mt.extend((10 .. 12u8).map(|x| Ok(Block(vec![x; 4096])))).unwrap();
+        assert_eq!(CLONES.with(Cell::get), 0);
+    }
+
+    #[test]
+    fn merkle_tree_history() {
+        type Tree = MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+        for &arity in &[2, 3, 4] {
+            let mut a = Tree::new_unchecked_with_arity(Default::default(), Default::default(), arity);
+            let mut roots = vec![None];
+            for x in 0 .. 30u32 {
+                a.push(x).unwrap();
+                roots.push(a.get_root().unwrap());
+            }
+            for (size, root) in roots.iter().enumerate() {
+                assert_eq!(a.root_of_prefix(size).unwrap(), *root);
+                for index in 0 .. size {
+                    let proof = a.inclusion_proof_of_prefix(index, size).unwrap();
+                    assert!(proof.verify::<Sha256, _>(&(index as u32), root.as_ref().unwrap()).unwrap());
+                }
+                assert!(a.inclusion_proof_of_prefix(size, size).is_err());
+            }
+            assert!(a.root_of_prefix(31).is_err());
+        }
+    }
+
+    #[test]
+    fn merkle_tree_arity() {
+        type Tree = MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+
+        // Binary trees have the same roots, as before
+        let a = sample_ro_tree();
+        let data = DATA.iter().cloned().collect::<Vec<_>>();
+        let b = MerkleTree::new_and_rebuild_with_arity(MemoryReadonlyDataStorage::with_data(data), MemoryTreeStorage::<DoubleHash<Sha256>>::new(), 2)
+            .unwrap();
+        assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+
+        for &arity in &[3, 4, 16] {
+            for len in 1 .. 40u32 {
+                let mut a = Tree::new_unchecked_with_arity(Default::default(), Default::default(), arity);
+                for x in 0 .. len {
+                    a.push(x).unwrap();
+                }
+                let mut b = Tree::new_unchecked_with_arity(Default::default(), Default::default(), arity);
+                b.extend((0 .. len / 2).map(Ok)).unwrap();
+                b.extend((len / 2 .. len).map(Ok)).unwrap();
+                let c = Tree::new_and_rebuild_with_arity(MemoryDataStorage::with_data((0 .. len).collect::<Vec<_>>()), Default::default(), arity)
+                    .unwrap();
+                assert_eq!(a.get_root().unwrap(), c.get_root().unwrap());
+                assert_eq!(b.get_root().unwrap(), c.get_root().unwrap());
+                c.check_tree().unwrap();
+                c.check_data().unwrap();
+
+                let root = c.get_root().unwrap().unwrap();
+                for index in 0 .. len {
+                    assert_eq!(c.audit_proof(index as usize).unwrap().last(), Some(&root));
+                    let proof = c.inclusion_proof(index as usize).unwrap();
+                    assert!(proof.verify::<Sha256, _>(&index, &root).unwrap());
+                }
+            }
+        }
+
+        // Four leaves of a 4-ary tree are hashed at once
+        let mut d = Tree::new_unchecked_with_arity(Default::default(), Default::default(), 4);
+        d.extend((0 .. 4).map(Ok)).unwrap();
+        let leaves: Vec<_> = (0 .. 4u32).map(|x| Sha256::eval_hash(&x)).collect();
+        assert_eq!(d.get_root().unwrap(), Some(Sha256::eval_hash(&&leaves[..])));
+        assert_eq!(d.tree().len().unwrap(), 2);
+
+        // A tree is checked with the arity it was built with
+        let (data, tree) = d.into_parts();
+        let d = Tree::new_and_check_with_arity(data, tree, 4).unwrap();
+        let (data, tree) = d.into_parts();
+        assert!(Tree::new_and_check_with_arity(data, tree, 2).is_err());
+    }
+
+    #[test]
+    fn merkle_tree_streams_leaves() {
+        use std::cell::RefCell;
+        use std::io::Cursor;
+        use std::rc::Rc;
+        use abc::DataStorageReadonly;
+        use fun::stream::ReadRegion;
+
+        let bytes: Vec<u8> = (0 .. 10000).map(|x| x as u8).collect();
+        let source = Rc::new(RefCell::new(Cursor::new(bytes.clone())));
+        let regions: Vec<_> = (0 .. 5).map(|i| ReadRegion::new(source.clone(), i * 2000, 2000)).collect();
+        let slices: Vec<_> = bytes.chunks(2000).collect();
+
+        let mut a: MerkleTree<MemoryDataStorage<ReadRegion<Cursor<Vec<u8>>>>, MemoryTreeStorage<Sha256>>;
+        a = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(regions), Default::default()).unwrap();
+        let b: MerkleTree<MemoryReadonlyDataStorage<&[u8]>, MemoryTreeStorage<Sha256>>;
+        b = MerkleTree::new_and_rebuild(MemoryReadonlyDataStorage::with_data(slices), Default::default()).unwrap();
+        assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+        a.check_data().unwrap();
+
+        // A region beyond the end of the source can not be read
+        let root = a.get_root().unwrap();
+        assert!(a.push(ReadRegion::new(source.clone(), 9000, 2000)).unwrap_err().is_io_error());
+        assert_eq!(a.data().len().unwrap(), 5);
+        assert_eq!(a.get_root().unwrap(), root);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn merkle_tree_serde() {
+        use serde_cbor;
+        use serde_json;
+
+        let data: Vec<_> = (0 .. 3).map(|x| sha256(&format!("{:064x}", x))).collect();
+        let a: MerkleTree<MemoryDataStorage<Sha256Value>, MemoryTreeStorage<Sha256>>;
+        a = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data), Default::default()).unwrap();
+
+        let json = serde_json::to_string(&a).unwrap();
+        let root = format!("{}", a.get_root().unwrap().unwrap());
+        assert!(json.contains(&format!("[\"{}\"]", root)));
+        let b: MerkleTree<MemoryDataStorage<Sha256Value>, MemoryTreeStorage<Sha256>>;
+        b = serde_json::from_str(&json).unwrap();
+        assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+        assert_eq!(a.audit_proof(1).unwrap(), b.audit_proof(1).unwrap());
+
+        let cbor = serde_cbor::to_vec(&a).unwrap();
+        let c: MerkleTree<MemoryDataStorage<Sha256Value>, MemoryTreeStorage<Sha256>>;
+        c = serde_cbor::from_slice(&cbor).unwrap();
+        assert_eq!(a.get_root().unwrap(), c.get_root().unwrap());
+
+        // Damaged tree is rejected
+        let json = json.replace(&root, &"0".repeat(64));
+        assert!(serde_json::from_str::<MerkleTree<MemoryDataStorage<Sha256Value>, MemoryTreeStorage<Sha256>>>(&json).is_err());
+    }
 }
@@ -0,0 +1,463 @@
+//! A write-ahead journal for transactions of `MerkleTree`.
+//!
+//! A transaction is written to the journal before the storages are changed, together with
+//! everything needed to roll the storages back: lengths and replaced data blocks.
+//! The journal is cleared once both storages are updated. If the process is interrupted between,
+//! `MerkleTree::recover()` finds the transaction in the journal, rolls both storages back
+//! and applies it again; `MerkleTree::new_and_recover()` does that when the tree is opened.
+//!
+//! A journal has to write a record atomically: a record, which is partially written,
+//! should not be read back at all (then nothing has been changed yet).
+
+use std::fmt;
+
+use merkle_tree::transaction::Operation;
+use prelude::*;
+
+
+/// A transaction with the state of storages before it
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct JournalRecord<V> {
+    /// The number of data blocks
+    pub data_len: usize,
+    /// Widths of levels of the tree
+    pub tree_sizes: Vec<usize>,
+    /// Data blocks, which are replaced or cut off by the transaction, by index
+    pub replaced: Vec<(usize, V)>,
+    /// Operations of the transaction
+    pub operations: Vec<Operation<V>>,
+}
+
+
+/// Any journal backend should implement this trait
+pub trait Journal: fmt::Debug {
+    type Value;
+
+    /// Writes the record atomically and durably, replacing the previous one
+    fn write(&mut self, record: &JournalRecord<Self::Value>) -> Result<()>;
+
+    /// Returns the record, if there is one
+    fn read(&self) -> Result<Option<JournalRecord<Self::Value>>>;
+
+    /// Removes the record
+    fn clear(&mut self) -> Result<()>;
+}
+
+
+/// An inmemory journal, for testing purposes and for storages, which do not survive the process
+#[derive(Debug, Clone)]
+pub struct MemoryJournal<V> {
+    record: Option<JournalRecord<V>>,
+}
+
+impl <V> Default for MemoryJournal<V> {
+    fn default() -> Self {
+        MemoryJournal { record: None }
+    }
+}
+
+impl <V> MemoryJournal<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl <V> Journal for MemoryJournal<V> where V: Clone + fmt::Debug {
+    type Value = V;
+
+    fn write(&mut self, record: &JournalRecord<V>) -> Result<()> {
+        self.record = Some(record.clone());
+        Ok(())
+    }
+
+    fn read(&self) -> Result<Option<JournalRecord<V>>> {
+        Ok(self.record.clone())
+    }
+
+    fn clear(&mut self) -> Result<()> {
+        self.record = None;
+        Ok(())
+    }
+}
+
+
+#[cfg(feature = "json")]
+pub use self::file::FileJournal;
+
+#[cfg(feature = "json")]
+mod file {
+    use std::fmt;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::marker::PhantomData;
+    use std::path::{Path, PathBuf};
+
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use serde_json;
+
+    use super::*;
+
+    /// A journal in a file; the record is kept as JSON.
+    /// A record is written to a temporary file, which then replaces the journal,
+    /// so the journal either has the whole record or nothing
+    pub struct FileJournal<V> {
+        path: PathBuf,
+        _value: PhantomData<V>,
+    }
+
+    impl <V> FileJournal<V> {
+        /// Opens the journal at the path; the file is created by the first write
+        pub fn new<P: AsRef<Path>>(path: P) -> Self {
+            FileJournal { path: path.as_ref().to_path_buf(), _value: PhantomData }
+        }
+
+        /// Returns the path to the journal
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        fn temporary_path(&self) -> PathBuf {
+            let mut name = self.path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+            name.push(".tmp");
+            self.path.with_file_name(name)
+        }
+
+        // Makes renaming and removing of the journal durable
+        #[cfg(unix)]
+        fn sync_directory(&self) -> Result<()> {
+            let directory = match self.path.parent() {
+                Some(parent) if parent != Path::new("") => parent,
+                _ => Path::new("."),
+            };
+            fs::File::open(directory)?.sync_all()?;
+            Ok(())
+        }
+
+        // Directories can not be opened as files on other platforms
+        #[cfg(not(unix))]
+        fn sync_directory(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    impl <V> fmt::Debug for FileJournal<V> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "FileJournal({:?})", self.path)
+        }
+    }
+
+    impl <V> Journal for FileJournal<V> where V: Serialize + DeserializeOwned {
+        type Value = V;
+
+        fn write(&mut self, record: &JournalRecord<V>) -> Result<()> {
+            let temporary = self.temporary_path();
+            {
+                let mut file = fs::File::create(&temporary)?;
+                serde_json::to_writer(&mut file, record).map_err(io::Error::from)?;
+                file.flush()?;
+                file.sync_all()?;
+            }
+            fs::rename(&temporary, &self.path)?;
+            self.sync_directory()
+        }
+
+        fn read(&self) -> Result<Option<JournalRecord<V>>> {
+            let file = match fs::File::open(&self.path) {
+                Ok(file) => file,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => Err(e)?,
+            };
+            let record = serde_json::from_reader(io::BufReader::new(file)).map_err(io::Error::from)?;
+            Ok(Some(record))
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            match fs::remove_file(&self.path) {
+                Ok(()) => self.sync_directory(),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+                Err(e) => Err(e)?,
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io;
+    use std::rc::Rc;
+
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use merkle_tree::MerkleTree;
+    use merkle_tree::transaction::Transaction;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    // The number of writes left before the crash; after the crash every write fails,
+    // including those of the rollback, just like the process has stopped
+    #[derive(Debug, Clone)]
+    struct Crash(Rc<Cell<usize>>);
+
+    impl Crash {
+        fn step(&self) -> Result<()> {
+            match self.0.get() {
+                0 => Err(io::Error::new(io::ErrorKind::Other, "crash"))?,
+                left => Ok(self.0.set(left - 1)),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct CrashingData(MemoryDataStorage<u32>, Crash);
+
+    impl DataStorageReadonly for CrashingData {
+        type DataValue = u32;
+
+        fn len(&self) -> Result<usize> {
+            self.0.len()
+        }
+
+        fn get(&self, index: usize) -> Result<u32> {
+            self.0.get(index)
+        }
+
+        fn is_writeable(&self) -> bool {
+            true
+        }
+    }
+
+    impl DataStorage for CrashingData {
+        fn push(&mut self, data: u32) -> Result<()> {
+            self.1.step()?;
+            self.0.push(data)
+        }
+
+        fn extend<DD: IntoIterator<Item=Result<u32>>>(&mut self, data: DD) -> Result<()> {
+            for v in data {
+                self.push(v?)?;
+            }
+            Ok(())
+        }
+
+        fn set(&mut self, index: usize, data: u32) -> Result<u32> {
+            self.1.step()?;
+            self.0.set(index, data)
+        }
+
+        fn truncate(&mut self, len: usize) -> Result<()> {
+            self.1.step()?;
+            self.0.truncate(len)
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            self.1.step()?;
+            self.0.clear()
+        }
+    }
+
+    #[derive(Debug)]
+    struct CrashingTree(MemoryTreeStorage<Sha256>, Crash);
+
+    impl TreeStorageReadonly for CrashingTree {
+        type Algorithm = Sha256;
+
+        fn len(&self) -> Result<usize> {
+            self.0.len()
+        }
+
+        fn get_level_len(&self, level: usize) -> Result<usize> {
+            self.0.get_level_len(level)
+        }
+
+        fn get_value(&self, level: usize, index: usize) -> Result<Sha256Value> {
+            self.0.get_value(level, index)
+        }
+
+        fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<Sha256Value>> + 's>> {
+            self.0.iter_level(level)
+        }
+
+        fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<(Sha256Value, Sha256Value)>> + 's>> {
+            self.0.iter_level_by_pair(level)
+        }
+
+        fn is_writeable(&self) -> bool {
+            true
+        }
+    }
+
+    impl TreeStorage for CrashingTree {
+        fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+            self.1.step()?;
+            self.0.clear_and_reserve(sizes)
+        }
+
+        fn grow(&mut self) -> Result<()> {
+            self.1.step()?;
+            self.0.grow()
+        }
+
+        fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+            self.1.step()?;
+            self.0.truncate(sizes)
+        }
+
+        fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut Sha256Value> {
+            self.1.step()?;
+            self.0.get_value_mut(level, index)
+        }
+
+        fn push(&mut self, level: usize, value: Sha256Value) -> Result<()> {
+            self.1.step()?;
+            self.0.push(level, value)
+        }
+
+        fn extend<I>(&mut self, level: usize, other: I) -> Result<()> where I: IntoIterator<Item=Result<Sha256Value>> {
+            for v in other {
+                self.push(level, v?)?;
+            }
+            Ok(())
+        }
+
+        fn extend_from_slice(&mut self, level: usize, slice: &[Sha256Value]) -> Result<()> {
+            self.extend(level, slice.iter().cloned().map(Ok))
+        }
+    }
+
+    #[derive(Debug)]
+    struct CrashingJournal(MemoryJournal<u32>, Crash);
+
+    impl Journal for CrashingJournal {
+        type Value = u32;
+
+        fn write(&mut self, record: &JournalRecord<u32>) -> Result<()> {
+            self.1.step()?;
+            self.0.write(record)
+        }
+
+        fn read(&self) -> Result<Option<JournalRecord<u32>>> {
+            self.0.read()
+        }
+
+        fn clear(&mut self) -> Result<()> {
+            self.1.step()?;
+            self.0.clear()
+        }
+    }
+
+    fn root_of(data: Vec<u32>) -> Option<Sha256Value> {
+        let mt: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+        mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data), Default::default()).unwrap();
+        mt.get_root().unwrap()
+    }
+
+    #[test]
+    fn journal_recovers_after_crash_at_every_step() {
+        for len in 0 .. 9u32 {
+            let data = (0 .. len).collect::<Vec<_>>();
+            let mut changed = data.clone();
+            changed.extend(vec![100, 101, 102]);
+            changed[len as usize + 1] = 201;
+            changed[0] = 200;
+            let before = root_of(data.clone());
+            let after = root_of(changed.clone());
+
+            let mut steps = 0;
+            loop {
+                let crash = Crash(Rc::new(Cell::new(!0)));
+                let mut mt = MerkleTree::new_and_rebuild(
+                    CrashingData(MemoryDataStorage::with_data(data.clone()), crash.clone()),
+                    CrashingTree(MemoryTreeStorage::new(), crash.clone()),
+                ).unwrap();
+                let mut journal = CrashingJournal(MemoryJournal::new(), crash.clone());
+                let mut tx = Transaction::new();
+                tx.push(100).push(101).push(102).update(len as usize + 1, 201).update(0, 200);
+
+                crash.0.set(steps);
+                let result = mt.apply_journaled(&mut journal, tx);
+
+                // The process is started again
+                crash.0.set(!0);
+                let recovered = mt.recover(&mut journal).unwrap();
+                assert!(journal.read().unwrap().is_none());
+                mt.check_data().unwrap();
+                mt.check_tree().unwrap();
+                match steps {
+                    0 => assert_eq!(mt.get_root().unwrap(), before),
+                    _ => assert_eq!(mt.get_root().unwrap(), after),
+                }
+                if result.is_ok() {
+                    assert!(!recovered);
+                    break;
+                }
+                steps += 1;
+            }
+            assert!(steps > 5);
+        }
+    }
+
+    #[test]
+    fn journal_is_recovered_on_open() {
+        let crash = Crash(Rc::new(Cell::new(!0)));
+        let mut mt = MerkleTree::new_and_rebuild(
+            CrashingData(MemoryDataStorage::with_data(vec![1, 2, 3]), crash.clone()),
+            CrashingTree(MemoryTreeStorage::new(), crash.clone()),
+        ).unwrap();
+        let mut journal = CrashingJournal(MemoryJournal::new(), crash.clone());
+        let mut tx = Transaction::new();
+        tx.push(4).update(0, 10);
+        crash.0.set(3);
+        assert!(mt.apply_journaled(&mut journal, tx).is_err());
+
+        crash.0.set(!0);
+        let (data, tree) = mt.into_parts();
+        let mt = MerkleTree::new_and_recover(data, tree, &mut journal).unwrap();
+        assert!(journal.read().unwrap().is_none());
+        assert_eq!(mt.get_root().unwrap(), root_of(vec![10, 2, 3, 4]));
+    }
+
+    #[test]
+    fn journal_is_cleared_after_rollback() {
+        let mut mt: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+        mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(vec![1, 2, 3]), Default::default()).unwrap();
+        let root = mt.get_root().unwrap();
+        let mut journal = MemoryJournal::new();
+        let mut tx = Transaction::new();
+        tx.push(4).update(10, 0);
+        assert!(mt.apply_journaled(&mut journal, tx).is_err());
+        assert!(journal.read().unwrap().is_none());
+        assert_eq!(mt.get_root().unwrap(), root);
+        assert!(!mt.recover(&mut journal).unwrap());
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn file_journal() {
+        use std::fs;
+        use tempfile;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut journal = FileJournal::new(dir.path().join("journal"));
+        assert_eq!(journal.read().unwrap(), None);
+        journal.clear().unwrap();
+
+        let record = JournalRecord {
+            data_len: 3,
+            tree_sizes: vec![3, 2, 1],
+            replaced: vec![(1, "b".to_string())],
+            operations: vec![Operation::Update(1, "x".to_string()), Operation::Push("d".to_string())],
+        };
+        journal.write(&record).unwrap();
+        assert_eq!(journal.read().unwrap(), Some(record));
+        journal.clear().unwrap();
+        assert_eq!(journal.read().unwrap(), None);
+
+        // A partially written record is never read
+        fs::write(dir.path().join("journal.tmp"), b"{\"data_len\":").unwrap();
+        assert_eq!(journal.read().unwrap(), None);
+    }
+}
@@ -0,0 +1,359 @@
+//! Merkle Mountain Range - an append-only accumulator.
+//!
+//! The range of `n` leaves is a forest of perfect binary trees, one for every bit set in `n`,
+//! ordered from the highest to the lowest. Roots of these trees (peaks) are bagged
+//! from right to left into the single root: `H(p0, H(p1, ... H(pk-1, pk)))`.
+//!
+//! Nodes are kept in a `TreeStorage` by levels: the level `h` holds `n >> h` roots of complete
+//! subtrees of height `h`. Appending never changes existing nodes, so the root and proofs
+//! for any former size can be evaluated from the current storage.
+
+use prelude::*;
+
+
+#[derive(Debug, Default)]
+pub struct MerkleMountainRange<D, T> where D: DataStorageReadonly, T: TreeStorage {
+    data: D,
+    tree: T,
+}
+
+/// A proof of inclusion of a leaf in the range of `size` leaves
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct MmrProof<V> {
+    /// The index of the leaf
+    pub index: usize,
+    /// The number of leaves in the range, the root of which is proved
+    pub size: usize,
+    /// Siblings on the path from the leaf to its peak, from the bottom
+    pub siblings: Vec<V>,
+    /// All other peaks of the range, from left to right
+    pub peaks: Vec<V>,
+}
+
+impl <D, T> MerkleMountainRange<D, T> where D: DataStorageReadonly, T: TreeStorage {
+    /// Creates an instance without checking of data integrity
+    pub fn new_unchecked(data: D, tree: T) -> Self {
+        MerkleMountainRange { data, tree }
+    }
+
+    /// Creates an instance and checks both the data and the tree
+    pub fn new_and_check(data: D, tree: T) -> Result<Self> {
+        let mmr = MerkleMountainRange::new_unchecked(data, tree);
+        mmr.check_tree()?;
+        mmr.check_data()?;
+        Ok(mmr)
+    }
+
+    /// Creates an instance and rebuilds the tree
+    pub fn new_and_rebuild(data: D, tree: T) -> Result<Self> {
+        let mut mmr = MerkleMountainRange::new_unchecked(data, tree);
+        mmr.rebuild()?;
+        Ok(mmr)
+    }
+
+    /// Returns a reference to the data storage
+    pub fn data(&self) -> &D {
+        &self.data
+    }
+    /// Returns a reference to the tree storage
+    pub fn tree(&self) -> &T {
+        &self.tree
+    }
+
+    /// Returns the number of leaves
+    pub fn size(&self) -> Result<usize> {
+        match self.tree.is_empty()? {
+            true => Ok(0),
+            false => self.tree.get_level_len(0),
+        }
+    }
+
+    /// Rebuilds the tree from scratch, using the current state of the data
+    pub fn rebuild(&mut self) -> Result<()> {
+        let len = self.data.len()?;
+        let sizes: Vec<usize> = (0 .. levels_count(len)).map(|level| len >> level).collect();
+        self.tree.clear_and_reserve(&sizes)?;
+        let tree = &mut self.tree;
+        self.data.for_each_value(|block| push_hash(tree, T::Algorithm::try_eval_hash(block)?))
+    }
+
+    /// Checks if the data corresponds to the leaves
+    pub fn check_data(&self) -> Result<()> {
+        if self.data.len()? != self.size()? {
+            Err(StateError::InconsistentState)?;
+        }
+        if self.tree.is_empty()? {
+            return Ok(());
+        }
+        let mut checksums = self.tree.iter_level(0)?;
+        self.data.for_each_value(|block| {
+            let cs = checksums.next().ok_or(StateError::InconsistentState)??;
+            if T::Algorithm::try_eval_hash(block)? != cs {
+                Err(StateError::DataDoesNotMatchTheChecksum)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Checks data integrity of the tree
+    pub fn check_tree(&self) -> Result<()> {
+        let size = self.size()?;
+        if self.tree.len()? != levels_count(size) {
+            Err(StateError::InconsistentState)?;
+        }
+        for level in 1 .. self.tree.len()? {
+            if self.tree.get_level_len(level)? != size >> level {
+                Err(StateError::InconsistentState)?;
+            }
+            let source = self.tree.iter_level_by_pair(level - 1)?;
+            let derived = self.tree.iter_level(level)?;
+            for (chunk, cs) in source.zip(derived) {
+                if T::Algorithm::eval_hash(&chunk?) != cs? {
+                    Err(StateError::DataDoesNotMatchTheChecksum)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the peaks of the range of first `size` leaves, from left to right
+    pub fn get_peaks_at(&self, size: usize) -> Result<Vec<<T::Algorithm as MTAlgorithm>::Value>> {
+        if size > self.size()? {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        peak_positions(size).into_iter()
+            .map(|(level, index)| self.tree.get_value(level, index))
+            .collect()
+    }
+
+    /// Returns the root, or None if the range is empty
+    pub fn get_root(&self) -> Result<Option<<T::Algorithm as MTAlgorithm>::Value>> {
+        let size = self.size()?;
+        self.get_root_at(size)
+    }
+
+    /// Returns the root of the range of first `size` leaves, or None if `size` is 0
+    pub fn get_root_at(&self, size: usize) -> Result<Option<<T::Algorithm as MTAlgorithm>::Value>> {
+        Ok(bag_peaks::<T::Algorithm>(self.get_peaks_at(size)?))
+    }
+
+    /// Returns a proof of inclusion of the leaf against the current root
+    pub fn proof(&self, index: usize) -> Result<MmrProof<<T::Algorithm as MTAlgorithm>::Value>> {
+        let size = self.size()?;
+        self.proof_at(index, size)
+    }
+
+    /// Returns a proof of inclusion of the leaf against the root of first `size` leaves
+    pub fn proof_at(&self, index: usize, size: usize) -> Result<MmrProof<<T::Algorithm as MTAlgorithm>::Value>> {
+        if index >= size || size > self.size()? {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        let (height, _) = find_peak(index, size);
+        let mut siblings = Vec::with_capacity(height);
+        for level in 0 .. height {
+            siblings.push(self.tree.get_value(level, (index >> level) ^ 1)?);
+        }
+        let own_peak = (height, index >> height);
+        let mut peaks = Vec::new();
+        for (level, i) in peak_positions(size) {
+            if (level, i) != own_peak {
+                peaks.push(self.tree.get_value(level, i)?);
+            }
+        }
+        Ok(MmrProof { index, size, siblings, peaks })
+    }
+}
+
+impl <D, T> MerkleMountainRange<D, T> where D: DataStorage, T: TreeStorage {
+    /// Appends a new data block; takes O(log n) hash evaluations
+    pub fn push(&mut self, data: D::DataValue) -> Result<()> {
+        if !self.data.is_writeable() {
+            Err(Error::new_ro("Data storage is not writable"))?;
+        }
+        let hash = T::Algorithm::try_eval_hash(&data)?;
+        self.data.push(data)?;
+        push_hash(&mut self.tree, hash)
+    }
+
+    /// Clears all data
+    pub fn clear(&mut self) -> Result<()> {
+        self.data.clear()?;
+        self.tree.clear()
+    }
+}
+
+impl <V> MmrProof<V> where V: MTValue {
+    /// Checks the proof for the hash of a leaf against a root
+    pub fn verify_hash<A>(&self, leaf: V, root: &V) -> bool where A: MTAlgorithm<Value=V> {
+        if self.index >= self.size {
+            return false;
+        }
+        let (height, position) = find_peak(self.index, self.size);
+        if self.siblings.len() != height || self.peaks.len() + 1 != self.size.count_ones() as usize {
+            return false;
+        }
+        let mut hash = leaf;
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            hash = match (self.index >> level) % 2 {
+                0 => A::eval_hash(&(hash, sibling.clone())),
+                _ => A::eval_hash(&(sibling.clone(), hash)),
+            };
+        }
+        let mut peaks = self.peaks.clone();
+        peaks.insert(position, hash);
+        bag_peaks::<A>(peaks).as_ref() == Some(root)
+    }
+
+    /// Checks the proof for a data block against a root
+    pub fn verify<A, H>(&self, data: &H, root: &V) -> Result<bool> where A: MTAlgorithm<Value=V>, H: MTHash {
+        Ok(self.verify_hash::<A>(A::try_eval_hash(data)?, root))
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+// The number of levels for the range of `size` leaves
+fn levels_count(size: usize) -> usize {
+    (0usize.leading_zeros() - size.leading_zeros()) as usize
+}
+
+// Positions (level, index) of peaks for the range of `size` leaves, from left to right
+fn peak_positions(size: usize) -> Vec<(usize, usize)> {
+    (0 .. levels_count(size)).rev()
+        .filter(|&level| (size >> level) % 2 == 1)
+        .map(|level| (level, (size >> level) - 1))
+        .collect()
+}
+
+// Returns the height of the peak, covering the leaf, and the position of the peak from the left
+fn find_peak(index: usize, size: usize) -> (usize, usize) {
+    debug_assert!(index < size);
+    let mut start = 0;
+    let mut position = 0;
+    for level in (0 .. levels_count(size)).rev() {
+        if (size >> level) % 2 == 1 {
+            if index < start + (1 << level) {
+                return (level, position);
+            }
+            start += 1 << level;
+            position += 1;
+        }
+    }
+    unreachable!()
+}
+
+// Appends a leaf hash, completing all subtrees ending with it
+fn push_hash<T>(tree: &mut T, mut hash: <T::Algorithm as MTAlgorithm>::Value) -> Result<()> where T: TreeStorage {
+    let mut level = 0;
+    loop {
+        if tree.len()? == level {
+            tree.grow()?;
+        }
+        tree.push(level, hash)?;
+        let len = tree.get_level_len(level)?;
+        if len % 2 == 1 {
+            return Ok(());
+        }
+        let pair = (tree.get_value(level, len - 2)?, tree.get_value(level, len - 1)?);
+        hash = T::Algorithm::eval_hash(&pair);
+        level += 1;
+    }
+}
+
+// Bags peaks from right to left
+fn bag_peaks<A>(peaks: Vec<A::Value>) -> Option<A::Value> where A: MTAlgorithm {
+    peaks.into_iter().rev()
+        .fold(None, |root, peak| match root {
+            None => Some(peak),
+            Some(root) => Some(A::eval_hash(&(peak, root))),
+        })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    type Mmr = MerkleMountainRange<MemoryDataStorage<u64>, MemoryTreeStorage<Sha256>>;
+
+    fn hash(x: u64) -> Sha256Value {
+        Sha256::eval_hash(&x)
+    }
+
+    fn pair(a: Sha256Value, b: Sha256Value) -> Sha256Value {
+        Sha256::eval_hash(&(a, b))
+    }
+
+    fn sample(size: u64) -> Mmr {
+        let mut mmr = Mmr::default();
+        for x in 0 .. size {
+            mmr.push(x).unwrap();
+        }
+        mmr
+    }
+
+    #[test]
+    fn mmr_root() {
+        let mmr = sample(7);
+        let p0 = pair(pair(hash(0), hash(1)), pair(hash(2), hash(3)));
+        let p1 = pair(hash(4), hash(5));
+        let p2 = hash(6);
+        assert_eq!(mmr.get_peaks_at(7).unwrap(), vec![p0, p1, p2]);
+        assert_eq!(mmr.get_root().unwrap(), Some(pair(p0, pair(p1, p2))));
+        assert_eq!(mmr.get_root_at(4).unwrap(), Some(p0));
+        assert_eq!(mmr.get_root_at(1).unwrap(), Some(hash(0)));
+        assert_eq!(mmr.get_root_at(0).unwrap(), None);
+        assert!(mmr.get_root_at(8).is_err());
+        assert_eq!(mmr.tree().len().unwrap(), 3);
+    }
+
+    #[test]
+    fn mmr_rebuild_and_check() {
+        let a = sample(13);
+        a.check_tree().unwrap();
+        a.check_data().unwrap();
+        let b = Mmr::new_and_rebuild(MemoryDataStorage::with_data((0 .. 13).collect::<Vec<_>>()), Default::default()).unwrap();
+        assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+
+        let mut c = Mmr::new_unchecked(MemoryDataStorage::with_data((0 .. 13).collect::<Vec<_>>()), Default::default());
+        assert!(c.check_data().is_err());
+        c.rebuild().unwrap();
+        c.check_data().unwrap();
+    }
+
+    #[test]
+    fn mmr_proofs() {
+        let mmr = sample(11);
+        for size in 1 .. 12 {
+            let root = mmr.get_root_at(size).unwrap().unwrap();
+            for index in 0 .. size {
+                let proof = mmr.proof_at(index, size).unwrap();
+                assert!(proof.verify::<Sha256, _>(&(index as u64), &root).unwrap());
+                assert!(!proof.verify::<Sha256, _>(&(index as u64 + 1), &root).unwrap());
+            }
+        }
+        let proof = mmr.proof(3).unwrap();
+        let old_root = mmr.get_root_at(10).unwrap().unwrap();
+        assert!(!proof.verify::<Sha256, _>(&3u64, &old_root).unwrap());
+        assert!(mmr.proof_at(5, 5).is_err());
+        assert!(mmr.proof_at(5, 12).is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn mmr_proof_serde() {
+        use serde_json;
+
+        let mmr = sample(6);
+        let proof = mmr.proof(2).unwrap();
+        let json = serde_json::to_string(&proof).unwrap();
+        let decoded: MmrProof<Sha256Value> = serde_json::from_str(&json).unwrap();
+        assert_eq!(proof, decoded);
+    }
+}
@@ -1,6 +1,20 @@
 pub mod abc;
 pub mod simple;
 pub mod generic;
+pub mod journal;
+pub mod mmr;
+pub mod proof;
+pub mod sorted;
+pub mod sparse;
+pub mod sum;
+pub mod transaction;
 
 pub use self::generic::*;
+pub use self::journal::*;
+pub use self::mmr::*;
+pub use self::proof::*;
 pub use self::simple::*;
+pub use self::sorted::*;
+pub use self::sparse::*;
+pub use self::sum::*;
+pub use self::transaction::*;
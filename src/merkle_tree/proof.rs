@@ -0,0 +1,118 @@
+//! Self-contained proofs for `MerkleTree`, which can be verified without access to the tree.
+
+use prelude::*;
+
+
+/// A proof of inclusion of a data block into the tree of `size` blocks.
+///
+/// Contains the siblings on the path from the leaf to the root, from the bottom;
+/// siblings of a node are listed in order, skipping the node itself.
+/// The last group of a level is padded by repeating its last node, padding is not included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct InclusionProof<V> {
+    /// The index of the data block
+    pub index: usize,
+    /// The number of data blocks in the tree
+    pub size: usize,
+    /// The number of children of a node
+    pub arity: usize,
+    /// Siblings on the path, from the bottom
+    pub siblings: Vec<V>,
+}
+
+impl <V> InclusionProof<V> where V: MTValue {
+    /// Collects a proof from the tree storage of a tree with the arity
+    pub fn from_tree<T>(tree: &T, arity: usize, index: usize) -> Result<Self> where T: TreeStorageReadonly, T::Algorithm: MTAlgorithm<Value=V> {
+        let size = match tree.is_empty()? {
+            true => 0,
+            false => tree.get_level_len(0)?,
+        };
+        if index >= size {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        let mut siblings = Vec::with_capacity(tree.len()? * (arity - 1));
+        let mut index2 = index;
+        for level in 0 .. tree.len()? - 1 {
+            let start = index2 - index2 % arity;
+            let end = tree.get_level_len(level)?.min(start + arity);
+            for sibling in (start .. end).filter(|&i| i != index2) {
+                siblings.push(tree.get_value(level, sibling)?);
+            }
+            index2 /= arity;
+        }
+        Ok(InclusionProof { index, size, arity, siblings })
+    }
+
+    /// Evaluates the root from the hash of the data block,
+    /// or returns None, if the proof is malformed
+    pub fn eval_root<A>(&self, leaf: V) -> Option<V> where A: MTAlgorithm<Value=V> {
+        if self.index >= self.size || self.arity < 2 {
+            return None;
+        }
+        let arity = self.arity;
+        let mut siblings = self.siblings.iter().cloned();
+        let mut hash = leaf;
+        let mut index = self.index;
+        let mut len = self.size;
+        let mut group = Vec::with_capacity(arity);
+        while len > 1 {
+            let start = index - index % arity;
+            group.clear();
+            for i in start .. len.min(start + arity) {
+                group.push(match i == index {
+                    true => hash.clone(),
+                    false => siblings.next()?,
+                });
+            }
+            while group.len() < arity {
+                let last = group[group.len() - 1].clone();
+                group.push(last);
+            }
+            hash = A::eval_hash(&&group[..]);
+            index /= arity;
+            len = (len + arity - 1) / arity;
+        }
+        match siblings.next() {
+            None => Some(hash),
+            Some(_) => None,
+        }
+    }
+
+    /// Checks the proof for the hash of a data block against a root
+    pub fn verify_hash<A>(&self, leaf: V, root: &V) -> bool where A: MTAlgorithm<Value=V> {
+        self.eval_root::<A>(leaf).as_ref() == Some(root)
+    }
+
+    /// Checks the proof for a data block against a root
+    pub fn verify<A, H>(&self, data: &H, root: &V) -> Result<bool> where A: MTAlgorithm<Value=V>, H: MTHash {
+        Ok(self.verify_hash::<A>(A::try_eval_hash(data)?, root))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use merkle_tree::MerkleTree;
+    use tree_storage::memory::MemoryTreeStorage;
+
+    #[test]
+    fn inclusion_proofs() {
+        for size in 1 .. 12u32 {
+            let mt: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+            mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data((0 .. size).collect::<Vec<_>>()), Default::default()).unwrap();
+            let root = mt.get_root().unwrap().unwrap();
+            for index in 0 .. size {
+                let proof = mt.inclusion_proof(index as usize).unwrap();
+                assert!(proof.verify::<Sha256, _>(&index, &root).unwrap());
+                assert!(!proof.verify::<Sha256, _>(&(index + 1), &root).unwrap());
+                let mut wrong = proof.clone();
+                wrong.siblings.push(root);
+                assert!(!wrong.verify::<Sha256, _>(&index, &root).unwrap());
+            }
+            assert!(mt.inclusion_proof(size as usize).is_err());
+        }
+    }
+}
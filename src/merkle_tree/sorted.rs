@@ -0,0 +1,268 @@
+//! A Merkle tree over data blocks, kept in strictly ascending order.
+//!
+//! The order makes it possible to prove that a key is absent:
+//! two adjacent leaves, the key falls between, are proved to be included.
+
+use merkle_tree::MerkleTree;
+use merkle_tree::proof::InclusionProof;
+use merkle_tree::transaction::Transaction;
+use prelude::*;
+
+
+/// A proof of membership or non-membership of a key in a sorted tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum SortedProof<K, V> {
+    /// The key is present
+    Member(InclusionProof<V>),
+    /// The key is absent and falls between two adjacent leaves.
+    /// There is no left leaf, if the key is less than all the keys, and no right one, if greater
+    NonMember {
+        left: Option<(K, InclusionProof<V>)>,
+        right: Option<(K, InclusionProof<V>)>,
+    },
+}
+
+impl <K, V> SortedProof<K, V> where K: MTHash + Ord, V: MTValue {
+    /// Returns true if the proof claims the key is present
+    pub fn is_member(&self) -> bool {
+        match *self {
+            SortedProof::Member(_) => true,
+            SortedProof::NonMember { .. } => false,
+        }
+    }
+
+    /// Checks the claim of the proof about the key against a root
+    pub fn verify<A>(&self, key: &K, root: &V) -> Result<bool> where A: MTAlgorithm<Value=V> {
+        let (left, right) = match *self {
+            SortedProof::Member(ref proof) => return proof.verify::<A, _>(key, root),
+            SortedProof::NonMember { ref left, ref right } => (left, right),
+        };
+        if let Some((ref l, ref proof)) = *left {
+            if !(l < key && proof.verify::<A, _>(l, root)?) {
+                return Ok(false);
+            }
+        }
+        if let Some((ref r, ref proof)) = *right {
+            if !(key < r && proof.verify::<A, _>(r, root)?) {
+                return Ok(false);
+            }
+        }
+        Ok(match (left, right) {
+            (&Some((_, ref l)), &Some((_, ref r))) => l.size == r.size && l.index + 1 == r.index,
+            (&Some((_, ref l)), &None) => l.index + 1 == l.size,
+            (&None, &Some((_, ref r))) => r.index == 0,
+            (&None, &None) => false,
+        })
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A Merkle tree, which keeps data blocks sorted and unique
+#[derive(Debug, Default)]
+pub struct SortedMerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage, D::DataValue: Ord {
+    inner: MerkleTree<D, T>,
+}
+
+impl <D, T> SortedMerkleTree<D, T> where D: DataStorageReadonly, T: TreeStorage, D::DataValue: Ord {
+    /// Creates an instance, checks the order of data blocks and rebuilds the tree
+    pub fn new_and_rebuild(data: D, tree: T) -> Result<Self> {
+        let inner = MerkleTree::new_unchecked(data, tree);
+        check_order(&inner)?;
+        let mut sorted = SortedMerkleTree { inner };
+        sorted.inner.rebuild()?;
+        Ok(sorted)
+    }
+
+    /// Creates an instance and checks the order of data blocks, the data and the tree
+    pub fn new_and_check(data: D, tree: T) -> Result<Self> {
+        let inner = MerkleTree::new_and_check(data, tree)?;
+        check_order(&inner)?;
+        Ok(SortedMerkleTree { inner })
+    }
+
+    /// Returns the underlying tree
+    pub fn inner(&self) -> &MerkleTree<D, T> {
+        &self.inner
+    }
+
+    /// Returns the root hash, or None if tree is empty.
+    pub fn get_root(&self) -> Result<Option<<T::Algorithm as MTAlgorithm>::Value>> {
+        self.inner.get_root()
+    }
+
+    /// Searches for the key: returns `Ok(index)` of the key,
+    /// or `Err(index)`, where the key could be inserted
+    pub fn find(&self, key: &D::DataValue) -> Result<::std::result::Result<usize, usize>> {
+        let mut low = 0;
+        let mut high = self.inner.data().len()?;
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let value = self.inner.data().get(middle)?;
+            if value == *key {
+                return Ok(Ok(middle));
+            } else if value < *key {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        Ok(Err(low))
+    }
+
+    /// Returns a proof of membership, if the key is present, or non-membership otherwise
+    pub fn proof(&self, key: &D::DataValue) -> Result<SortedProof<D::DataValue, <T::Algorithm as MTAlgorithm>::Value>> {
+        match self.find(key)? {
+            Ok(index) => Ok(SortedProof::Member(self.inner.inclusion_proof(index)?)),
+            Err(index) => {
+                let len = self.inner.data().len()?;
+                let left = match index {
+                    0 => None,
+                    index => Some((self.inner.data().get(index - 1)?, self.inner.inclusion_proof(index - 1)?)),
+                };
+                let right = match index < len {
+                    true => Some((self.inner.data().get(index)?, self.inner.inclusion_proof(index)?)),
+                    false => None,
+                };
+                Ok(SortedProof::NonMember { left, right })
+            },
+        }
+    }
+}
+
+impl <D, T> SortedMerkleTree<D, T> where D: DataStorage, T: TreeStorage, D::DataValue: Ord {
+    /// Inserts the key, if it is absent; returns false if the key is already present.
+    /// The following keys are shifted by one transaction, so appending takes O(log n)
+    /// and inserting before k keys takes O(k log n)
+    pub fn insert(&mut self, key: D::DataValue) -> Result<bool> {
+        match self.find(&key)? {
+            Ok(_) => Ok(false),
+            Err(index) => {
+                let len = self.inner.data().len()?;
+                let mut transaction = Transaction::new();
+                if index == len {
+                    transaction.push(key);
+                } else {
+                    transaction.push(self.inner.data().get(len - 1)?);
+                    for i in (index + 1 .. len).rev() {
+                        transaction.update(i, self.inner.data().get(i - 1)?);
+                    }
+                    transaction.update(index, key);
+                }
+                self.inner.apply(transaction)?;
+                Ok(true)
+            },
+        }
+    }
+
+    /// Removes the key; returns false if the key is absent.
+    /// The following keys are shifted by one transaction, which takes O(k log n) for k keys
+    pub fn remove(&mut self, key: &D::DataValue) -> Result<bool> {
+        match self.find(key)? {
+            Err(_) => Ok(false),
+            Ok(index) => {
+                let len = self.inner.data().len()?;
+                let mut transaction = Transaction::new();
+                for i in index .. len - 1 {
+                    transaction.update(i, self.inner.data().get(i + 1)?);
+                }
+                transaction.truncate(len - 1);
+                self.inner.apply(transaction)?;
+                Ok(true)
+            },
+        }
+    }
+}
+
+// Checks that data blocks are strictly ascending
+fn check_order<D, T>(mt: &MerkleTree<D, T>) -> Result<()>
+    where D: DataStorageReadonly, T: TreeStorage, D::DataValue: Ord
+{
+    let mut previous = None;
+    for value in mt.data().iter()? {
+        let value = value?;
+        if let Some(previous) = previous {
+            if previous >= value {
+                Err(StateError::InconsistentState)?;
+            }
+        }
+        previous = Some(value);
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    type Sorted = SortedMerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+
+    fn sample() -> Sorted {
+        Sorted::new_and_rebuild(MemoryDataStorage::with_data(vec![10, 20, 30, 40, 50]), Default::default()).unwrap()
+    }
+
+    #[test]
+    fn sorted_tree_order() {
+        assert!(Sorted::new_and_rebuild(MemoryDataStorage::with_data(vec![1, 3, 2]), Default::default()).is_err());
+        assert!(Sorted::new_and_rebuild(MemoryDataStorage::with_data(vec![1, 1]), Default::default()).is_err());
+
+        let mut a = sample();
+        assert!(a.insert(35).unwrap());
+        assert!(a.insert(60).unwrap());
+        assert!(a.insert(5).unwrap());
+        assert!(!a.insert(20).unwrap());
+        assert!(a.remove(&40).unwrap());
+        assert!(!a.remove(&40).unwrap());
+        let b = Sorted::new_and_rebuild(MemoryDataStorage::with_data(vec![5, 10, 20, 30, 35, 50, 60]), Default::default()).unwrap();
+        assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+        a.inner().check_data().unwrap();
+        a.inner().check_tree().unwrap();
+    }
+
+    #[test]
+    fn sorted_tree_shifts() {
+        for key in 0 .. 12 {
+            let keys = (1 .. 6).map(|key| key * 2).collect::<Vec<u32>>();
+            let mut a = Sorted::new_and_rebuild(MemoryDataStorage::with_data(keys.clone()), Default::default()).unwrap();
+            let mut expected = keys.clone();
+            match expected.binary_search(&key) {
+                Ok(index) => { expected.remove(index); assert!(a.remove(&key).unwrap()); },
+                Err(index) => { expected.insert(index, key); assert!(a.insert(key).unwrap()); },
+            }
+            let b = Sorted::new_and_rebuild(MemoryDataStorage::with_data(expected), Default::default()).unwrap();
+            assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+            a.inner().check_data().unwrap();
+            a.inner().check_tree().unwrap();
+        }
+    }
+
+    #[test]
+    fn sorted_tree_proofs() {
+        let a = sample();
+        let root = a.get_root().unwrap().unwrap();
+        for key in 0 .. 60 {
+            let proof = a.proof(&key).unwrap();
+            assert_eq!(proof.is_member(), key % 10 == 0 && key > 0 && key <= 50);
+            assert!(proof.verify::<Sha256>(&key, &root).unwrap());
+        }
+
+        // A non-membership proof can not be reused for another key
+        let proof = a.proof(&25).unwrap();
+        assert!(!proof.verify::<Sha256>(&15, &root).unwrap());
+        assert!(!proof.verify::<Sha256>(&20, &root).unwrap());
+
+        // Leaves, which are not adjacent, do not prove anything
+        let forged: SortedProof<u32, Sha256Value> = SortedProof::NonMember {
+            left: Some((10, a.inner().inclusion_proof(0).unwrap())),
+            right: Some((30, a.inner().inclusion_proof(2).unwrap())),
+        };
+        assert!(!forged.verify::<Sha256>(&20, &root).unwrap());
+    }
+}
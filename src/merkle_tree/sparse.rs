@@ -0,0 +1,416 @@
+//! Sparse Merkle tree - a commitment to a key-value map.
+//!
+//! Every key is a path in the complete binary tree of height 256, values are kept in leaves.
+//! Hashes of empty subtrees are precomputed for every height, so only non-empty nodes are stored,
+//! and an update recomputes hashes along a single path.
+//!
+//! A present leaf is hashed as `H((key, value))`, an empty leaf as `H(&[])`,
+//! a node as `H((left, right))`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use prelude::*;
+use util::fmt_slice2hex;
+
+
+/// The number of levels above leaves
+pub const SPARSE_TREE_HEIGHT: usize = 256;
+
+/// A 256-bit key, which defines the path to a leaf from the root (the most significant bit first)
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct SparseKey(pub [u8; 32]);
+
+impl SparseKey {
+    /// Derives a key from any hashable value as its hash by the algorithm, fails if the value
+    /// can not be read. Hashes shorter than 256 bits are continued by hashes of `(n, key)` for `n` = 1, 2, ...
+    pub fn new<A: MTAlgorithm, K: MTHash>(key: &K) -> Result<Self> {
+        let mut bytes = [0u8; 32];
+        let mut filled = 0;
+        let mut n = 0u32;
+        while filled < bytes.len() {
+            let hash = match n {
+                0 => A::try_eval_hash(key)?,
+                n => A::try_eval_hash(&(n, key))?,
+            };
+            let hash = hash.as_bytes();
+            assert!(!hash.is_empty(), "Hash values are never empty");
+            let len = hash.len().min(bytes.len() - filled);
+            bytes[filled .. filled + len].copy_from_slice(&hash[.. len]);
+            filled += len;
+            n += 1;
+        }
+        Ok(SparseKey(bytes))
+    }
+
+    // Returns the bit, which chooses between children of the node at height `height + 1`
+    fn bit(&self, height: usize) -> bool {
+        self.0[31 - height / 8] & (1 << (height % 8)) != 0
+    }
+
+    // Returns the key with the bit inverted
+    fn flip(mut self, height: usize) -> Self {
+        self.0[31 - height / 8] ^= 1 << (height % 8);
+        self
+    }
+
+    // Returns the key with the bit cleared: the prefix of the node at `height` turns
+    // into the prefix of its parent
+    fn clear(mut self, height: usize) -> Self {
+        self.0[31 - height / 8] &= !(1 << (height % 8));
+        self
+    }
+}
+
+impl MTHash for SparseKey {
+    fn hash<H: MTContext>(&self, state: &mut H) {
+        state.update(&self.0)
+    }
+}
+
+impl fmt::Debug for SparseKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "KEY:")?;
+        fmt_slice2hex(f, &self.0[..])
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A proof of membership or non-membership of a key.
+/// Empty siblings are omitted and marked by zero bits in the bitmap
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct SparseProof<V> {
+    /// A bit per height (the lowest bit of the last byte is the height 0), set if the sibling is not empty
+    pub bitmap: [u8; 32],
+    /// Non-empty siblings on the path, from the bottom
+    pub siblings: Vec<V>,
+}
+
+impl <V> SparseProof<V> where V: MTValue {
+    /// Checks that the key has the value (`Some`) or is absent (`None`) in the tree with the root.
+    /// Hashes of empty subtrees are computed once, see `SparseEmptyHashes::new`
+    pub fn verify<A, D>(&self, empty: &SparseEmptyHashes<A>, key: &SparseKey, value: Option<&D>, root: &V) -> Result<bool>
+        where A: MTAlgorithm<Value=V>, D: MTHash
+    {
+        let empty = &empty.0;
+        let mut hash = match value {
+            Some(value) => A::try_eval_hash(&(key, value))?,
+            None => empty[0].clone(),
+        };
+        let mut siblings = self.siblings.iter();
+        for height in 0 .. SPARSE_TREE_HEIGHT {
+            let sibling = match SparseKey(self.bitmap).bit(height) {
+                true => match siblings.next() {
+                    Some(sibling) => sibling.clone(),
+                    None => return Ok(false),
+                },
+                false => empty[height].clone(),
+            };
+            hash = match key.bit(height) {
+                false => A::eval_hash(&(hash, sibling)),
+                true => A::eval_hash(&(sibling, hash)),
+            };
+        }
+        Ok(siblings.next().is_none() && hash == *root)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// Hashes of empty subtrees for every height, from leaves to the root
+pub struct SparseEmptyHashes<A>(Vec<A::Value>) where A: MTAlgorithm;
+
+impl <A> SparseEmptyHashes<A> where A: MTAlgorithm {
+    /// Computes the hashes (takes `SPARSE_TREE_HEIGHT` hash evaluations)
+    pub fn new() -> Self {
+        let mut empty = Vec::with_capacity(SPARSE_TREE_HEIGHT + 1);
+        empty.push(A::eval_hash(&&b""[..]));
+        for height in 0 .. SPARSE_TREE_HEIGHT {
+            let hash = A::eval_hash(&(&empty[height], &empty[height]));
+            empty.push(hash);
+        }
+        SparseEmptyHashes(empty)
+    }
+
+    /// Returns the hash of an empty subtree at the height
+    pub fn get(&self, height: usize) -> &A::Value {
+        &self.0[height]
+    }
+}
+
+impl <A> Default for SparseEmptyHashes<A> where A: MTAlgorithm {
+    fn default() -> Self {
+        SparseEmptyHashes::new()
+    }
+}
+
+impl <A> Clone for SparseEmptyHashes<A> where A: MTAlgorithm {
+    fn clone(&self) -> Self {
+        SparseEmptyHashes(self.0.clone())
+    }
+}
+
+impl <A> fmt::Debug for SparseEmptyHashes<A> where A: MTAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SparseEmptyHashes(root={:?})", self.0[SPARSE_TREE_HEIGHT])
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// An in-memory sparse Merkle tree
+pub struct SparseMerkleTree<A, V> where A: MTAlgorithm, V: MTHash {
+    values: BTreeMap<SparseKey, V>,
+    // Non-empty nodes by (height, prefix)
+    nodes: BTreeMap<(usize, SparseKey), A::Value>,
+    empty: SparseEmptyHashes<A>,
+}
+
+impl <A, V> Default for SparseMerkleTree<A, V> where A: MTAlgorithm, V: MTHash {
+    fn default() -> Self {
+        SparseMerkleTree {
+            values: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            empty: SparseEmptyHashes::new(),
+        }
+    }
+}
+
+impl <A, V> SparseMerkleTree<A, V> where A: MTAlgorithm, V: MTHash {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Derives a key from any hashable value with the algorithm of the tree
+    pub fn key<K: MTHash>(key: &K) -> Result<SparseKey> {
+        SparseKey::new::<A, K>(key)
+    }
+
+    /// Returns hashes of empty subtrees, which are needed to verify proofs
+    pub fn empty_hashes(&self) -> &SparseEmptyHashes<A> {
+        &self.empty
+    }
+
+    /// Returns the number of keys
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Returns true if there are no keys
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Returns the value of the key
+    pub fn get(&self, key: &SparseKey) -> Option<&V> {
+        self.values.get(key)
+    }
+
+    /// Returns an iterator over keys and values, ordered by keys
+    pub fn iter<'s>(&'s self) -> Box<Iterator<Item=(&'s SparseKey, &'s V)> + 's> {
+        Box::new(self.values.iter())
+    }
+
+    /// Returns the root hash (the tree is never empty: a tree without keys has a well-known root)
+    pub fn get_root(&self) -> A::Value {
+        self.get_node(SPARSE_TREE_HEIGHT, &SparseKey::default())
+    }
+
+    /// Inserts or updates a value, returns the previous one
+    pub fn insert(&mut self, key: SparseKey, value: V) -> Option<V> {
+        let leaf = A::eval_hash(&(&key, &value));
+        let old = self.values.insert(key, value);
+        self.update_path(&key, leaf);
+        old
+    }
+
+    /// Removes a key, returns its value
+    pub fn remove(&mut self, key: &SparseKey) -> Option<V> {
+        let old = self.values.remove(key);
+        if old.is_some() {
+            let leaf = self.empty.get(0).clone();
+            self.update_path(key, leaf);
+        }
+        old
+    }
+
+    /// Returns a proof of membership, if the key is present, or non-membership otherwise
+    pub fn proof(&self, key: &SparseKey) -> SparseProof<A::Value> {
+        let mut bitmap = SparseKey::default();
+        let mut siblings = Vec::new();
+        let mut prefix = *key;
+        for height in 0 .. SPARSE_TREE_HEIGHT {
+            if let Some(sibling) = self.nodes.get(&(height, prefix.flip(height))) {
+                bitmap = bitmap.flip(height);
+                siblings.push(sibling.clone());
+            }
+            prefix = prefix.clear(height);
+        }
+        SparseProof { bitmap: bitmap.0, siblings }
+    }
+
+    /// Checks all the stored nodes against the values.
+    /// Will take a long time for a large tree
+    pub fn check_tree(&self) -> Result<()> {
+        let mut expected = SparseMerkleTree::<A, V> {
+            values: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            empty: self.empty.clone(),
+        };
+        for (key, value) in self.values.iter() {
+            expected.update_path(key, A::eval_hash(&(key, value)));
+        }
+        if expected.nodes != self.nodes {
+            Err(StateError::DataDoesNotMatchTheChecksum)?;
+        }
+        Ok(())
+    }
+
+    fn get_node(&self, height: usize, prefix: &SparseKey) -> A::Value {
+        self.nodes.get(&(height, *prefix)).cloned().unwrap_or_else(|| self.empty.get(height).clone())
+    }
+
+    fn set_node(&mut self, height: usize, prefix: SparseKey, hash: A::Value) {
+        if hash == *self.empty.get(height) {
+            self.nodes.remove(&(height, prefix));
+        } else {
+            self.nodes.insert((height, prefix), hash);
+        }
+    }
+
+    // Sets the leaf hash and recomputes all nodes up to the root
+    fn update_path(&mut self, key: &SparseKey, leaf: A::Value) {
+        let mut hash = leaf;
+        let mut prefix = *key;
+        for height in 0 .. SPARSE_TREE_HEIGHT {
+            self.set_node(height, prefix, hash.clone());
+            let sibling = self.get_node(height, &prefix.flip(height));
+            hash = match key.bit(height) {
+                false => A::eval_hash(&(hash, sibling)),
+                true => A::eval_hash(&(sibling, hash)),
+            };
+            prefix = prefix.clear(height);
+        }
+        self.set_node(SPARSE_TREE_HEIGHT, SparseKey::default(), hash);
+    }
+}
+
+impl <A, V> fmt::Debug for SparseMerkleTree<A, V> where A: MTAlgorithm, V: MTHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SparseMerkleTree(len={}, nodes={})", self.values.len(), self.nodes.len())
+    }
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    use fun::sha256::*;
+    use fun::stream::ReadRegion;
+    use super::*;
+
+    type Smt = SparseMerkleTree<Sha256, String>;
+
+    fn key(x: u32) -> SparseKey {
+        Smt::key(&x).unwrap()
+    }
+
+    fn sample() -> Smt {
+        let mut smt = Smt::new();
+        for x in 0 .. 10 {
+            smt.insert(key(x), format!("value {}", x));
+        }
+        smt
+    }
+
+    #[test]
+    fn sparse_tree_root() {
+        let mut smt = Smt::new();
+        let empty_root = smt.get_root();
+        assert_eq!(empty_root, *SparseEmptyHashes::<Sha256>::new().get(SPARSE_TREE_HEIGHT));
+
+        smt.insert(key(1), "one".to_string());
+        let root1 = smt.get_root();
+        smt.insert(key(2), "two".to_string());
+        assert!(smt.get_root() != root1);
+        assert_eq!(smt.remove(&key(2)), Some("two".to_string()));
+        assert_eq!(smt.get_root(), root1);
+        assert_eq!(smt.insert(key(1), "uno".to_string()), Some("one".to_string()));
+        assert!(smt.get_root() != root1);
+        smt.remove(&key(1));
+        assert_eq!(smt.get_root(), empty_root);
+        assert!(smt.nodes.is_empty());
+    }
+
+    #[test]
+    fn sparse_tree_is_independent_of_order() {
+        let a = sample();
+        let mut b = Smt::new();
+        for x in (0 .. 12).rev() {
+            b.insert(key(x), format!("value {}", x));
+        }
+        b.remove(&key(10));
+        b.remove(&key(11));
+        assert_eq!(a.get_root(), b.get_root());
+        a.check_tree().unwrap();
+        b.check_tree().unwrap();
+    }
+
+    #[test]
+    fn sparse_tree_proofs() {
+        let smt = sample();
+        let root = smt.get_root();
+        let empty = SparseEmptyHashes::<Sha256>::new();
+        for x in 0 .. 10 {
+            let value = format!("value {}", x);
+            let proof = smt.proof(&key(x));
+            assert!(proof.verify(&empty, &key(x), Some(&value), &root).unwrap());
+            assert!(!proof.verify::<_, String>(&empty, &key(x), None, &root).unwrap());
+            assert!(!proof.verify(&empty, &key(x), Some(&"other".to_string()), &root).unwrap());
+        }
+        let proof = smt.proof(&key(100));
+        assert!(proof.verify::<_, String>(smt.empty_hashes(), &key(100), None, &root).unwrap());
+        assert!(!proof.verify(&empty, &key(100), Some(&"value 1".to_string()), &root).unwrap());
+        assert!(proof.siblings.len() < 20);
+    }
+
+    #[test]
+    fn sparse_keys() {
+        use fun::defaulthash::DefaultHash;
+
+        assert_eq!(SparseKey::new::<Sha256, _>(&1u32).unwrap().0, Sha256::eval_hash(&1u32).0);
+        // Short hashes are continued
+        let a = SparseKey::new::<DefaultHash, _>(&1u32).unwrap();
+        assert_eq!(&a.0[.. 8], &DefaultHash::eval_hash(&1u32).as_bytes()[..]);
+        assert_eq!(&a.0[8 .. 16], &DefaultHash::eval_hash(&(1u32, &1u32)).as_bytes()[..]);
+        assert!(a != SparseKey::new::<DefaultHash, _>(&2u32).unwrap());
+
+        // Values, which can not be read, fail instead of panicking
+        let region = ReadRegion::new(Rc::new(RefCell::new(Cursor::new(vec![1u8, 2]))), 0, 10);
+        assert!(SparseKey::new::<Sha256, _>(&region).unwrap_err().is_io_error());
+        let smt = sample();
+        let proof = smt.proof(&key(100));
+        assert!(proof.verify(smt.empty_hashes(), &key(100), Some(&region), &smt.get_root()).unwrap_err().is_io_error());
+    }
+
+    #[test]
+    fn sparse_tree_corruption() {
+        let mut smt = sample();
+        let (&node, _) = smt.nodes.iter().next().unwrap();
+        smt.nodes.insert(node, Sha256Value([1; 32]));
+        assert!(smt.check_tree().is_err());
+    }
+}
@@ -0,0 +1,314 @@
+//! Merkle sum tree - every node carries the hash and the sum of amounts of its subtree.
+//!
+//! A leaf is `(H(data), amount)`, a node is `(H((left.hash, left.sum, right.hash, right.sum)), left.sum + right.sum)`.
+//! A lone last node of a level is moved to the next level unchanged,
+//! as pairing it with itself would count its amount twice.
+//!
+//! A proof lets the owner of a data block check that it is included with its amount,
+//! and that it is counted in the total of the root: amounts are unsigned and sums never overflow.
+
+use std::fmt;
+
+use prelude::*;
+
+
+/// A node of a Merkle sum tree
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct SumNode<V> {
+    pub hash: V,
+    pub sum: u64,
+}
+
+impl <V> SumNode<V> where V: MTValue {
+    /// Creates a leaf of the data block
+    pub fn leaf<A, H>(data: &H) -> Result<Self> where A: MTAlgorithm<Value=V>, H: MTAmount {
+        Ok(SumNode { hash: A::try_eval_hash(data)?, sum: data.amount() })
+    }
+
+    /// Creates a parent of two nodes, or error if the sum overflows
+    pub fn parent<A>(left: &Self, right: &Self) -> Result<Self> where A: MTAlgorithm<Value=V> {
+        let sum = left.sum.checked_add(right.sum).ok_or(StateError::AmountOverflow)?;
+        let hash = A::eval_hash(&(&left.hash, left.sum, &right.hash, right.sum));
+        Ok(SumNode { hash, sum })
+    }
+}
+
+
+/// A proof of inclusion of a data block with its amount into the tree of `size` blocks
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub struct SumProof<V> {
+    /// The index of the data block
+    pub index: usize,
+    /// The number of data blocks in the tree
+    pub size: usize,
+    /// Siblings on the path from the leaf to the root, from the bottom
+    pub siblings: Vec<SumNode<V>>,
+}
+
+impl <V> SumProof<V> where V: MTValue {
+    /// Checks the proof for a data block against the root, including all the sums
+    pub fn verify<A, H>(&self, data: &H, root: &SumNode<V>) -> Result<bool> where A: MTAlgorithm<Value=V>, H: MTAmount {
+        if self.index >= self.size {
+            return Ok(false);
+        }
+        let mut siblings = self.siblings.iter();
+        let mut node = SumNode::leaf::<A, _>(data)?;
+        let mut index = self.index;
+        let mut len = self.size;
+        while len > 1 {
+            if index % 2 == 1 || index + 1 < len {
+                let sibling = match siblings.next() {
+                    Some(sibling) => sibling,
+                    None => return Ok(false),
+                };
+                let parent = match index % 2 {
+                    0 => SumNode::parent::<A>(&node, sibling),
+                    _ => SumNode::parent::<A>(sibling, &node),
+                };
+                node = match parent {
+                    Ok(parent) => parent,
+                    Err(_) => return Ok(false),
+                };
+            }
+            index /= 2;
+            len = len / 2 + len % 2;
+        }
+        Ok(siblings.next().is_none() && node == *root)
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// A Merkle sum tree over a data storage; nodes are kept in memory
+pub struct MerkleSumTree<D, A> where D: DataStorageReadonly, D::DataValue: MTAmount, A: MTAlgorithm {
+    data: D,
+    // Levels of nodes, from leaves to the root
+    levels: Vec<Vec<SumNode<A::Value>>>,
+}
+
+impl <D, A> MerkleSumTree<D, A> where D: DataStorageReadonly, D::DataValue: MTAmount, A: MTAlgorithm {
+    /// Creates an instance and builds the tree
+    pub fn new_and_rebuild(data: D) -> Result<Self> {
+        let mut mt = MerkleSumTree { data, levels: Vec::new() };
+        mt.rebuild()?;
+        Ok(mt)
+    }
+
+    /// Returns a reference to the data storage
+    pub fn data(&self) -> &D {
+        &self.data
+    }
+
+    /// Rebuilds full tree from scratch, using the current state of the data
+    pub fn rebuild(&mut self) -> Result<()> {
+        self.levels.clear();
+        let mut level = Vec::with_capacity(self.data.len()?);
+        for block in self.data.iter()? {
+            level.push(SumNode::leaf::<A, _>(&block?)?);
+        }
+        while !level.is_empty() {
+            let next = match level.len() {
+                1 => Vec::new(),
+                _ => parents::<A>(&level)?,
+            };
+            self.levels.push(level);
+            level = next;
+        }
+        Ok(())
+    }
+
+    /// Checks if the data corresponds to the leaves
+    pub fn check_data(&self) -> Result<()> {
+        let leaves = self.levels.first().map(Vec::len).unwrap_or(0);
+        if self.data.len()? != leaves {
+            Err(StateError::InconsistentState)?;
+        }
+        if leaves == 0 {
+            return Ok(());
+        }
+        for (block, node) in self.data.iter()?.zip(self.levels[0].iter()) {
+            if SumNode::leaf::<A, _>(&block?)? != *node {
+                Err(StateError::DataDoesNotMatchTheChecksum)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks both hashes and sums of all the nodes
+    pub fn check_tree(&self) -> Result<()> {
+        for (level, next) in self.levels.iter().zip(self.levels.iter().skip(1)) {
+            if parents::<A>(level)? != *next {
+                Err(StateError::DataDoesNotMatchTheChecksum)?;
+            }
+        }
+        match self.levels.last() {
+            Some(root) if root.len() != 1 => Err(StateError::InconsistentState)?,
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the root, or None if tree is empty
+    pub fn get_root(&self) -> Option<SumNode<A::Value>> {
+        self.levels.last().and_then(|level| level.first()).cloned()
+    }
+
+    /// Returns the sum of all amounts
+    pub fn total(&self) -> u64 {
+        self.get_root().map(|root| root.sum).unwrap_or(0)
+    }
+
+    /// Returns a proof of inclusion of the data block
+    pub fn proof(&self, index: usize) -> Result<SumProof<A::Value>> {
+        let size = self.levels.first().map(Vec::len).unwrap_or(0);
+        if index >= size {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        let mut siblings = Vec::with_capacity(self.levels.len());
+        let mut index2 = index;
+        for level in &self.levels {
+            if let Some(sibling) = level.get(index2 ^ 1) {
+                siblings.push(sibling.clone());
+            }
+            index2 /= 2;
+        }
+        Ok(SumProof { index, size, siblings })
+    }
+}
+
+impl <D, A> MerkleSumTree<D, A> where D: DataStorage, D::DataValue: MTAmount, A: MTAlgorithm {
+    /// Appends a new data block, updating the last branch
+    pub fn push(&mut self, data: D::DataValue) -> Result<()> {
+        if !self.data.is_writeable() {
+            Err(Error::new_ro("Data storage is not writable"))?;
+        }
+        let leaf = SumNode::leaf::<A, _>(&data)?;
+        if let Some(root) = self.get_root() {
+            root.sum.checked_add(leaf.sum).ok_or(StateError::AmountOverflow)?;
+        }
+        self.data.push(data)?;
+        if self.levels.is_empty() {
+            self.levels.push(Vec::new());
+        }
+        self.levels[0].push(leaf);
+
+        // Recompute the last node of every level above
+        let mut level = 0;
+        while self.levels[level].len() > 1 {
+            let node = {
+                let nodes = &self.levels[level];
+                let last = nodes.len() - 1;
+                match last % 2 {
+                    1 => SumNode::parent::<A>(&nodes[last - 1], &nodes[last])?,
+                    _ => nodes[last].clone(),
+                }
+            };
+            let parents = (self.levels[level].len() + 1) / 2;
+            if self.levels.len() == level + 1 {
+                self.levels.push(Vec::new());
+            }
+            let next = &mut self.levels[level + 1];
+            if next.len() < parents {
+                next.push(node);
+            } else {
+                next[parents - 1] = node;
+            }
+            level += 1;
+        }
+        Ok(())
+    }
+}
+
+impl <D, A> fmt::Debug for MerkleSumTree<D, A> where D: DataStorageReadonly, D::DataValue: MTAmount, A: MTAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MerkleSumTree(data={:?}, levels={})", self.data, self.levels.len())
+    }
+}
+
+// Evaluates the next level; a lone last node is moved up unchanged
+fn parents<A>(level: &[SumNode<A::Value>]) -> Result<Vec<SumNode<A::Value>>> where A: MTAlgorithm {
+    level.chunks(2)
+        .map(|chunk| match chunk.len() {
+            2 => SumNode::parent::<A>(&chunk[0], &chunk[1]),
+            _ => Ok(chunk[0].clone()),
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Account(&'static str, u64);
+
+    impl MTHash for Account {
+        fn hash<H: MTContext>(&self, state: &mut H) {
+            (self.0, self.1).hash(state)
+        }
+    }
+
+    impl MTAmount for Account {
+        fn amount(&self) -> u64 {
+            self.1
+        }
+    }
+
+    type SumTree = MerkleSumTree<MemoryDataStorage<Account>, Sha256>;
+
+    fn accounts() -> Vec<Account> {
+        vec![Account("a", 10), Account("b", 20), Account("c", 5), Account("d", 0), Account("e", 7)]
+    }
+
+    #[test]
+    fn sum_tree_totals() {
+        let a = SumTree::new_and_rebuild(MemoryDataStorage::with_data(accounts())).unwrap();
+        assert_eq!(a.total(), 42);
+        a.check_tree().unwrap();
+        a.check_data().unwrap();
+
+        let mut b = SumTree::new_and_rebuild(MemoryDataStorage::new()).unwrap();
+        assert_eq!(b.get_root(), None);
+        for account in accounts() {
+            b.push(account).unwrap();
+            b.check_tree().unwrap();
+        }
+        assert_eq!(a.get_root(), b.get_root());
+
+        assert!(b.push(Account("f", !0)).unwrap_err().is_state_error());
+        assert_eq!(b.total(), 42);
+        b.check_data().unwrap();
+    }
+
+    #[test]
+    fn sum_tree_proofs() {
+        let a = SumTree::new_and_rebuild(MemoryDataStorage::with_data(accounts())).unwrap();
+        let root = a.get_root().unwrap();
+        for (index, account) in accounts().into_iter().enumerate() {
+            let proof = a.proof(index).unwrap();
+            assert!(proof.verify::<Sha256, _>(&account, &root).unwrap());
+            assert!(!proof.verify::<Sha256, _>(&Account(account.0, account.1 + 1), &root).unwrap());
+        }
+
+        // A sibling with a changed sum does not match the root
+        let mut proof = a.proof(0).unwrap();
+        proof.siblings[0].sum += 1;
+        assert!(!proof.verify::<Sha256, _>(&accounts()[0], &root).unwrap());
+    }
+
+    #[test]
+    fn sum_tree_corruption() {
+        let mut a = SumTree::new_and_rebuild(MemoryDataStorage::with_data(accounts())).unwrap();
+        a.levels[1][0].sum += 1;
+        assert!(a.check_tree().is_err());
+        a.rebuild().unwrap();
+        a.levels[0][0].sum = 11;
+        assert!(a.check_data().is_err());
+    }
+}
@@ -0,0 +1,211 @@
+//! Batches of changes, which are applied to `MerkleTree` atomically.
+
+
+/// A staged change of a data block
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(::serde::Serialize, ::serde::Deserialize))]
+pub enum Operation<V> {
+    /// Appends a data block to the back of data chain
+    Push(V),
+    /// Replaces the data block at the index
+    Update(usize, V),
+    /// Shortens data chain to the length, if it is longer
+    Truncate(usize),
+}
+
+impl <V> Operation<V> {
+    /// Returns the data block of the operation, or None if it has no one
+    pub fn value(&self) -> Option<&V> {
+        match *self {
+            Operation::Push(ref value) | Operation::Update(_, ref value) => Some(value),
+            Operation::Truncate(_) => None,
+        }
+    }
+}
+
+
+/// A list of changes, which are applied by `MerkleTree::apply()` all together or not at all
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transaction<V> {
+    operations: Vec<Operation<V>>,
+}
+
+impl <V> Default for Transaction<V> {
+    fn default() -> Self {
+        Transaction { operations: Vec::new() }
+    }
+}
+
+impl <V> Transaction<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Stages appending of a data block
+    pub fn push(&mut self, value: V) -> &mut Self {
+        self.operations.push(Operation::Push(value));
+        self
+    }
+
+    /// Stages replacing of the data block at the index.
+    /// The index may point to a block, pushed earlier in the same transaction
+    pub fn update(&mut self, index: usize, value: V) -> &mut Self {
+        self.operations.push(Operation::Update(index, value));
+        self
+    }
+
+    /// Stages shortening of data chain to the length.
+    /// Blocks, which are cut off, are restored if the transaction is rolled back
+    pub fn truncate(&mut self, len: usize) -> &mut Self {
+        self.operations.push(Operation::Truncate(len));
+        self
+    }
+
+    /// Returns the number of staged operations
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    /// Returns true if nothing is staged
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// Returns staged operations in order
+    pub fn operations(&self) -> &[Operation<V>] {
+        &self.operations
+    }
+
+    /// Returns staged operations in order, consuming the transaction
+    pub fn into_operations(self) -> Vec<Operation<V>> {
+        self.operations
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cmp;
+
+    use abc::*;
+    use data_storage::memory::MemoryDataStorage;
+    use error::StateError;
+    use fun::sha256::*;
+    use merkle_tree::MerkleTree;
+    use tree_storage::memory::MemoryTreeStorage;
+    use tree_storage::versioned::VersionedTreeStorage;
+    use super::*;
+
+    type Tree = MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+
+    fn sample(len: u32) -> Tree {
+        Tree::new_and_rebuild(MemoryDataStorage::with_data((0 .. len).collect::<Vec<_>>()), Default::default()).unwrap()
+    }
+
+    #[test]
+    fn transaction_is_applied() {
+        for len in 0 .. 10 {
+            let mut a = sample(len);
+            let mut tx = Transaction::new();
+            tx.push(100).push(101).push(102).update(len as usize + 1, 201);
+            if len > 3 {
+                tx.update(3, 203);
+            }
+            a.apply(tx).unwrap();
+
+            let mut expected = (0 .. len).chain(vec![100, 201, 102]).collect::<Vec<_>>();
+            if len > 3 {
+                expected[3] = 203;
+            }
+            let b = Tree::new_and_rebuild(MemoryDataStorage::with_data(expected), Default::default()).unwrap();
+            assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+            a.check_data().unwrap();
+            a.check_tree().unwrap();
+        }
+    }
+
+    #[test]
+    fn transaction_is_truncated() {
+        for len in 0 .. 10 {
+            for cut in 0 .. len + 2 {
+                let mut a = sample(len);
+                let mut tx = Transaction::new();
+                tx.truncate(cut as usize).push(100);
+                a.apply(tx).unwrap();
+
+                let expected = (0 .. cmp::min(cut, len)).chain(vec![100]).collect::<Vec<_>>();
+                let b = Tree::new_and_rebuild(MemoryDataStorage::with_data(expected), Default::default()).unwrap();
+                assert_eq!(a.get_root().unwrap(), b.get_root().unwrap());
+                a.check_data().unwrap();
+                a.check_tree().unwrap();
+
+                let mut a = sample(len);
+                let root = a.get_root().unwrap();
+                let mut tx = Transaction::new();
+                tx.truncate(cut as usize).push(100).push(101).update(len as usize + 10, 0);
+                assert!(a.apply(tx).is_err());
+                assert_eq!(a.get_root().unwrap(), root);
+                assert_eq!(a.data().len().unwrap(), len as usize);
+                a.check_data().unwrap();
+                a.check_tree().unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn transaction_is_rolled_back() {
+        for len in 0 .. 10 {
+            let mut a = sample(len);
+            let root = a.get_root().unwrap();
+            let mut tx = Transaction::new();
+            tx.push(100);
+            if len > 0 {
+                tx.update(0, 200).update(len as usize - 1, 201);
+            }
+            tx.push(101).push(102).push(103).update(len as usize + 10, 0);
+            assert!(a.apply(tx).is_err());
+
+            assert_eq!(a.get_root().unwrap(), root);
+            assert_eq!(a.data().len().unwrap(), len as usize);
+            a.check_data().unwrap();
+            a.check_tree().unwrap();
+        }
+
+        let mut a = sample(5);
+        a.data_mut().set_writable(false);
+        let mut tx = Transaction::new();
+        tx.push(5);
+        assert!(a.apply(tx).is_err());
+    }
+
+    #[test]
+    fn extend_is_rolled_back() {
+        for len in 0 .. 10 {
+            let mut a = sample(len);
+            let root = a.get_root().unwrap();
+            let data = vec![Ok(100), Ok(101), Err(StateError::InconsistentState.into()), Ok(102)];
+            assert!(a.extend(data).is_err());
+            assert_eq!(a.get_root().unwrap(), root);
+            assert_eq!(a.data().len().unwrap(), len as usize);
+            a.check_data().unwrap();
+            a.check_tree().unwrap();
+        }
+    }
+
+    #[test]
+    fn transaction_is_one_version() {
+        let mut a: MerkleTree<MemoryDataStorage<u32>, VersionedTreeStorage<Sha256>> = Default::default();
+        a.extend((0 .. 5).map(Ok)).unwrap();
+        let mut tx = Transaction::new();
+        tx.push(5).push(6).update(0, 10);
+        a.apply(tx.clone()).unwrap();
+        assert_eq!(a.tree().versions_len(), 2);
+
+        tx.update(100, 0);
+        assert!(a.apply(tx).is_err());
+        assert_eq!(a.tree().versions_len(), 2);
+        assert_eq!(a.get_root().unwrap(), a.root_at(1).unwrap());
+        a.check_data().unwrap();
+        a.check_tree().unwrap();
+    }
+}
@@ -0,0 +1,103 @@
+//! Serde support, enabled by the `serde` feature.
+//!
+//! Hash values are encoded as hex strings in human-readable formats (like JSON)
+//! and as raw bytes otherwise (like CBOR).
+
+use std::fmt;
+use std::marker::PhantomData;
+
+use serde::Deserializer;
+use serde::Serializer;
+use serde::de;
+
+use abc::MTValue;
+
+
+/// Serializes a hash value as a hex string or as raw bytes, depending on the format
+pub fn serialize_value<V, S>(value: &V, serializer: S) -> Result<S::Ok, S::Error>
+    where V: MTValue, S: Serializer
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&value.to_hex())
+    } else {
+        serializer.serialize_bytes(&value.as_bytes())
+    }
+}
+
+/// Deserializes a hash value from a hex string or from raw bytes, depending on the format
+pub fn deserialize_value<'de, V, D>(deserializer: D) -> Result<V, D::Error>
+    where V: MTValue, D: Deserializer<'de>
+{
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_str(ValueVisitor(PhantomData))
+    } else {
+        deserializer.deserialize_bytes(ValueVisitor(PhantomData))
+    }
+}
+
+
+struct ValueVisitor<V>(PhantomData<V>);
+
+impl <'de, V> de::Visitor<'de> for ValueVisitor<V> where V: MTValue {
+    type Value = V;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a hash value as a hex string or bytes")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<V, E> {
+        V::from_hex(v).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<V, E> {
+        V::from_bytes(v).map_err(E::custom)
+    }
+
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<V, A::Error> {
+        let mut buf = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(x) = seq.next_element::<u8>()? {
+            buf.push(x);
+        }
+        V::from_bytes(&buf).map_err(de::Error::custom)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_cbor;
+    use serde_json;
+
+    use abc::MTAlgorithm;
+    use fun::crc32::Crc32Ieee;
+    use fun::crc32::Crc32Value;
+    use fun::sha256::Sha256;
+    use fun::sha256::Sha256Value;
+
+    #[test]
+    fn values_as_hex_in_json() {
+        let value = Sha256::eval_hash(&b"123".as_ref());
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(json, "\"a665a45920422f9d417e4867efdc4fb8a04a1f3fff1fa07e998e86f7f7a27ae3\"");
+        assert_eq!(serde_json::from_str::<Sha256Value>(&json).unwrap(), value);
+
+        let values = vec![Crc32Ieee::eval_hash(&b"123".as_ref()), Crc32Ieee::eval_hash(&b"321".as_ref())];
+        let json = serde_json::to_string(&values).unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Crc32Value>>(&json).unwrap(), values);
+
+        assert!(serde_json::from_str::<Crc32Value>("\"884863\"").is_err());
+        assert!(serde_json::from_str::<Crc32Value>("\"88486xd2\"").is_err());
+    }
+
+    #[test]
+    fn values_as_bytes_in_cbor() {
+        let value = Sha256::eval_hash(&b"123".as_ref());
+        let cbor = serde_cbor::to_vec(&value).unwrap();
+        // major type 2 (byte string), 1-byte length
+        assert_eq!(&cbor[.. 2], &[0x58, 32]);
+        assert_eq!(&cbor[2 ..], &value.0[..]);
+        assert_eq!(serde_cbor::from_slice::<Sha256Value>(&cbor).unwrap(), value);
+
+        assert!(serde_cbor::from_slice::<Crc32Value>(&cbor).is_err());
+    }
+}
@@ -3,13 +3,13 @@ use std::fmt;
 use prelude::*;
 
 
-pub struct TreeLevel<'a, A: TreeStorage + 'a> {
+pub struct TreeLevel<'a, A: TreeStorageReadonly + 'a> {
     len: usize,
     level: usize,
     tree: &'a A,
 }
 
-impl<'a, A: TreeStorage + 'a> TreeLevel<'a, A> {
+impl<'a, A: TreeStorageReadonly + 'a> TreeLevel<'a, A> {
 
     /// Returns the number of values on the level
     pub fn len(&self) -> usize {
@@ -29,8 +29,9 @@ impl<'a, A: TreeStorage + 'a> TreeLevel<'a, A> {
 }
 
 
-/// Any tree storage backend should implement this trait
-pub trait TreeStorage: fmt::Debug {
+/// Static tree (for example, on read-only media).
+/// Can only be read and checked
+pub trait TreeStorageReadonly: fmt::Debug {
     type Algorithm: MTAlgorithm;
 
     /// Returns the number of levels in the tree
@@ -41,17 +42,6 @@ pub trait TreeStorage: fmt::Debug {
         self.len().map(|len| len == 0)
     }
 
-    /// Clears all data
-    fn clear(&mut self) -> Result<()> {
-        self.clear_and_reserve(&[])
-    }
-
-    /// Clears all data and reserves space for levels
-    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()>;
-
-    /// Adds 1 level to the tree
-    fn grow(&mut self) -> Result<()>;
-
     /// Returns an info about the specified level, if the level exists
     fn get_level(&self, level: usize) -> Result<TreeLevel<Self>> where Self: Sized {
         self.get_level_len(level).map(|len| TreeLevel { len, level, tree: self })
@@ -63,19 +53,6 @@ pub trait TreeStorage: fmt::Debug {
     /// Returns a value
     fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value>;
 
-    /// Returns a mutable reference
-    fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut <Self::Algorithm as MTAlgorithm>::Value>;
-
-    /// Appends a value to the back of the specified level
-    fn push(&mut self, level: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()>;
-
-    /// Appends values to the back of the specified level
-    fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
-        where I: IntoIterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>>;
-
-    /// Appends values to the back of the specified level
-    fn extend_from_slice(&mut self, level: usize, slice: &[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>;
-
     /// Return an iterator over all values of the specified level
     fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>>;
 
@@ -84,6 +61,46 @@ pub trait TreeStorage: fmt::Debug {
         Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
     > + 's>>;
 
+    /// Return an iterator over groups of `arity` values of the specified level.
+    /// The last group is padded by repeating its last value
+    fn iter_level_by_group<'s>(&'s self, level: usize, arity: usize) -> Result<Box<Iterator<
+        Item=Result<Vec<<Self::Algorithm as MTAlgorithm>::Value>>
+    > + 's>> {
+        let len = self.get_level_len(level)?;
+        Ok(Box::new((0 .. (len + arity - 1) / arity).map(move |group| {
+            let start = group * arity;
+            let mut values = Vec::with_capacity(arity);
+            for index in start .. len.min(start + arity) {
+                values.push(self.get_value(level, index)?);
+            }
+            while values.len() < arity {
+                let last = values[values.len() - 1].clone();
+                values.push(last);
+            }
+            Ok(values)
+        })))
+    }
+
+    /// Calls `f` with a reference to a value.
+    /// Storages, which keep values in memory, should override it to lend the value without cloning
+    fn with_value<R, F>(&self, level: usize, index: usize, f: F) -> Result<R>
+        where F: FnOnce(&<Self::Algorithm as MTAlgorithm>::Value) -> Result<R>
+    {
+        f(&self.get_value(level, index)?)
+    }
+
+    /// Calls `f` with every group of `arity` values of the specified level, stops at the first error.
+    /// The last group is padded by repeating its last value, as by `iter_level_by_group`.
+    /// Storages, which keep levels in memory, should override it, see `for_each_group_of_slice`
+    fn for_each_group<F>(&self, level: usize, arity: usize, mut f: F) -> Result<()>
+        where F: FnMut(&[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>
+    {
+        for group in self.iter_level_by_group(level, arity)? {
+            f(&group?)?;
+        }
+        Ok(())
+    }
+
     /// Returns root, if the tree is not empty
     fn get_root(&self) -> Result<Option<<Self::Algorithm as MTAlgorithm>::Value>> {
         if self.is_empty()? {
@@ -99,4 +116,106 @@ pub trait TreeStorage: fmt::Debug {
     fn root(&self) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
         Ok(self.get_root()?.expect("Tree is empty"))
     }
+
+    /// Writable tree storage have to override this method
+    fn is_writeable(&self) -> bool {
+        false
+    }
+}
+
+
+/// Any writable tree storage backend should implement this trait
+pub trait TreeStorage: TreeStorageReadonly {
+    /// Clears all data
+    fn clear(&mut self) -> Result<()> {
+        self.clear_and_reserve(&[])
+    }
+
+    /// Clears all data and reserves space for levels
+    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()>;
+
+    /// Adds 1 level to the tree
+    fn grow(&mut self) -> Result<()>;
+
+    /// Shortens the tree to `sizes.len()` levels and every level to its size.
+    /// Needed to roll transactions back; append-only storages may keep the default
+    fn truncate(&mut self, _sizes: &[usize]) -> Result<()> {
+        Err(AccessError::NotSupported)?
+    }
+
+    /// Marks the end of a consistent update of the tree.
+    /// Called by `MerkleTree` after each mutation; storages may flush or snapshot here
+    fn commit(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Discards changes since `clear_and_reserve`, when a rebuild has failed.
+    /// Called by `MerkleTree` instead of `commit`; storages, which can not restore the tree,
+    /// may keep the default, which does nothing
+    fn rollback(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns a mutable reference
+    fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut <Self::Algorithm as MTAlgorithm>::Value>;
+
+    /// Replaces a value. Storages, which can not lend a mutable reference, should override it
+    fn set_value(&mut self, level: usize, index: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        *self.get_value_mut(level, index)? = value;
+        Ok(())
+    }
+
+    /// Appends a value to the back of the specified level
+    fn push(&mut self, level: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()>;
+
+    /// Appends values to the back of the specified level
+    fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
+        where I: IntoIterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>>;
+
+    /// Appends values to the back of the specified level
+    fn extend_from_slice(&mut self, level: usize, slice: &[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>;
+}
+
+
+/// Collects nodes `start .. end`, returned by `node`, into a group,
+/// padded up to the arity by repeating the last node
+pub fn collect_group<V, F>(start: usize, end: usize, arity: usize, mut node: F) -> Result<Vec<V>>
+    where V: Clone, F: FnMut(usize) -> Result<V>
+{
+    if start >= end {
+        Err(AccessError::IndexIsOutOfBounds)?;
+    }
+    let mut group = Vec::with_capacity(arity);
+    for i in start .. end {
+        group.push(node(i)?);
+    }
+    let last = group[group.len() - 1].clone();
+    group.resize(arity, last);
+    Ok(group)
+}
+
+/// Returns the padded group of `arity` nodes of the level, which contains the index
+pub fn level_group<T>(tree: &T, level: usize, index: usize, arity: usize)
+    -> Result<Vec<<T::Algorithm as MTAlgorithm>::Value>> where T: TreeStorageReadonly + ?Sized
+{
+    let start = index - index % arity;
+    let end = tree.get_level_len(level)?.min(start + arity);
+    collect_group(start, end, arity, |i| tree.get_value(level, i))
+}
+
+/// Calls `f` with every group of `arity` values of the slice, stops at the first error.
+/// Only the last group, if it is short, is copied to be padded by repeating its last value
+pub fn for_each_group_of_slice<V, F>(values: &[V], arity: usize, mut f: F) -> Result<()>
+    where V: Clone, F: FnMut(&[V]) -> Result<()>
+{
+    for chunk in values.chunks(arity) {
+        if chunk.len() == arity {
+            f(chunk)?;
+        } else {
+            let mut group = chunk.to_vec();
+            group.resize(arity, chunk[chunk.len() - 1].clone());
+            f(&group)?;
+        }
+    }
+    Ok(())
 }
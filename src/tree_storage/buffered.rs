@@ -0,0 +1,499 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::mem;
+
+use prelude::*;
+
+
+// Changes of a level, which are not written yet
+struct LevelBuffer<V> {
+    // The width of the level in the wrapped storage
+    flushed_len: usize,
+    // Values appended after `flushed_len`
+    appended: Vec<V>,
+    // New values of nodes, which are already in the wrapped storage
+    rewritten: BTreeMap<usize, V>,
+}
+
+impl <V> LevelBuffer<V> {
+    fn new(flushed_len: usize) -> Self {
+        LevelBuffer { flushed_len, appended: Vec::new(), rewritten: BTreeMap::new() }
+    }
+
+    fn len(&self) -> usize {
+        self.flushed_len + self.appended.len()
+    }
+
+    fn is_dirty(&self) -> bool {
+        !self.appended.is_empty() || !self.rewritten.is_empty()
+    }
+}
+
+// A removal of written nodes, which is applied to the wrapped storage before new nodes are written
+enum Cut {
+    // The wrapped storage is cleared and reserved for levels of the sizes
+    Clear(Vec<usize>),
+    // Every level of the wrapped storage is shortened to its `flushed_len`
+    Truncate,
+}
+
+
+/// A wrapper over a slow tree storage, which keeps appended nodes and rewrites of nodes in memory
+/// and writes them in batches on `flush()` or on drop.
+/// Readers see all the changes, whether they are written or not.
+/// Errors of writing on drop are ignored, so `flush()` should be called explicitly to handle them
+pub struct BufferedTreeStorage<T> where T: TreeStorage {
+    inner: T,
+    levels: Vec<LevelBuffer<<T::Algorithm as MTAlgorithm>::Value>>,
+    cut: Option<Cut>,
+    // Buffers and the cut before `clear_and_reserve`, until the rebuild is committed
+    cleared: Option<(Vec<LevelBuffer<<T::Algorithm as MTAlgorithm>::Value>>, Option<Cut>)>,
+}
+
+impl <T> BufferedTreeStorage<T> where T: TreeStorage {
+    /// Creates a wrapper over the storage
+    pub fn new(inner: T) -> Result<Self> {
+        let levels = levels_of(&inner)?;
+        Ok(BufferedTreeStorage { inner, levels, cut: None, cleared: None })
+    }
+
+    /// Returns the wrapped storage; changes, which are not flushed, are not there
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns true if there are changes, which are not written yet
+    pub fn is_dirty(&self) -> Result<bool> {
+        Ok(self.cut.is_some() || self.levels.len() != self.inner.len()? || self.levels.iter().any(LevelBuffer::is_dirty))
+    }
+
+    /// Writes all the changes to the wrapped storage and commits it
+    pub fn flush(&mut self) -> Result<()> {
+        if !self.is_dirty()? {
+            return Ok(());
+        }
+        match self.cut {
+            Some(Cut::Clear(ref sizes)) => self.inner.clear_and_reserve(sizes)?,
+            Some(Cut::Truncate) => {
+                let flushed = self.levels.iter().map(|buffer| buffer.flushed_len).collect::<Vec<_>>();
+                let len = cmp::min(flushed.len(), self.inner.len()?);
+                self.inner.truncate(&flushed[.. len])?;
+            },
+            None => (),
+        }
+        self.cut = None;
+        self.cleared = None;
+        while self.inner.len()? < self.levels.len() {
+            self.inner.grow()?;
+        }
+        // A change is dropped only once it is written, so a failed flush can be repeated
+        for (level, buffer) in self.levels.iter_mut().enumerate() {
+            while let Some(index) = buffer.rewritten.keys().next().cloned() {
+                self.inner.set_value(level, index, buffer.rewritten[&index].clone())?;
+                buffer.rewritten.remove(&index);
+            }
+            if !buffer.appended.is_empty() {
+                self.inner.extend_from_slice(level, &buffer.appended)?;
+                buffer.flushed_len += buffer.appended.len();
+                buffer.appended.clear();
+            }
+        }
+        self.inner.commit()
+    }
+
+    fn buffer_mut(&mut self, level: usize) -> Result<&mut LevelBuffer<<T::Algorithm as MTAlgorithm>::Value>> {
+        Ok(self.levels.get_mut(level).ok_or(StateError::InconsistentState)?)
+    }
+}
+
+// Returns empty buffers for all the levels of the storage
+fn levels_of<T: TreeStorage>(inner: &T) -> Result<Vec<LevelBuffer<<T::Algorithm as MTAlgorithm>::Value>>> {
+    (0 .. inner.len()?)
+        .map(|level| inner.get_level_len(level).map(LevelBuffer::new))
+        .collect()
+}
+
+impl <T> Drop for BufferedTreeStorage<T> where T: TreeStorage {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+impl <T> fmt::Debug for BufferedTreeStorage<T> where T: TreeStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let pending: usize = self.levels.iter().map(|buffer| buffer.appended.len() + buffer.rewritten.len()).sum();
+        write!(f, "BufferedTreeStorage({:?}, pending={})", self.inner, pending)
+    }
+}
+
+impl <T> TreeStorageReadonly for BufferedTreeStorage<T> where T: TreeStorage {
+    type Algorithm = T::Algorithm;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.levels.len())
+    }
+
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        self.levels.get(level)
+            .map(LevelBuffer::len)
+            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
+        let buffer = self.levels.get(level).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        if index >= buffer.flushed_len {
+            return buffer.appended.get(index - buffer.flushed_len).cloned().ok_or(INDEX_IS_OUT_OF_BOUNDS);
+        }
+        match buffer.rewritten.get(&index) {
+            Some(value) => Ok(value.clone()),
+            None => self.inner.get_value(level, index),
+        }
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>> {
+        let len = self.get_level_len(level)?;
+        Ok(Box::new((0 .. len).map(move |index| self.get_value(level, index))))
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<
+        Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
+    > + 's>> {
+        Ok(Box::new(self.iter_level_by_group(level, 2)?.map(|group| group.map(|mut group| {
+            let right = group.pop().expect("Group has 2 values");
+            (group.pop().expect("Group has 2 values"), right)
+        }))))
+    }
+
+    fn is_writeable(&self) -> bool {
+        self.inner.is_writeable()
+    }
+}
+
+impl <T> TreeStorage for BufferedTreeStorage<T> where T: TreeStorage {
+    /// Drops all the buffers; the wrapped storage is cleared by `flush()`, before new levels are written
+    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+        let levels = sizes.iter().map(|_| LevelBuffer::new(0)).collect();
+        let levels = mem::replace(&mut self.levels, levels);
+        let cut = mem::replace(&mut self.cut, Some(Cut::Clear(sizes.to_vec())));
+        if self.cleared.is_none() {
+            self.cleared = Some((levels, cut));
+        }
+        Ok(())
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        self.levels.push(LevelBuffer::new(0));
+        Ok(())
+    }
+
+    /// Drops buffered changes beyond the sizes; if the written part of the wrapped storage is cut off,
+    /// it is truncated by `flush()`, before new nodes are written
+    fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+        let mut cut = self.levels.len() > sizes.len();
+        self.levels.truncate(sizes.len());
+        for (buffer, &size) in self.levels.iter_mut().zip(sizes) {
+            if size < buffer.flushed_len {
+                buffer.flushed_len = size;
+                buffer.appended.clear();
+                cut = true;
+            } else {
+                buffer.appended.truncate(size - buffer.flushed_len);
+            }
+            buffer.rewritten.split_off(&size);
+        }
+        match self.cut {
+            // The cleared storage has as many levels, as were reserved
+            Some(Cut::Clear(ref mut reserved)) => reserved.truncate(sizes.len()),
+            _ if cut => self.cut = Some(Cut::Truncate),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Does not write: changes are kept till `flush()`
+    fn commit(&mut self) -> Result<()> {
+        self.cleared = None;
+        Ok(())
+    }
+
+    /// Restores the buffers, dropped by `clear_and_reserve`
+    fn rollback(&mut self) -> Result<()> {
+        if let Some((levels, cut)) = self.cleared.take() {
+            self.levels = levels;
+            self.cut = cut;
+        }
+        Ok(())
+    }
+
+    fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut <Self::Algorithm as MTAlgorithm>::Value> {
+        let flushed_len = self.levels.get(level).ok_or(INDEX_IS_OUT_OF_BOUNDS)?.flushed_len;
+        if index >= flushed_len {
+            let buffer = &mut self.levels[level];
+            return buffer.appended.get_mut(index - flushed_len).ok_or(INDEX_IS_OUT_OF_BOUNDS);
+        }
+        if !self.levels[level].rewritten.contains_key(&index) {
+            let value = self.inner.get_value(level, index)?;
+            self.levels[level].rewritten.insert(index, value);
+        }
+        Ok(self.levels[level].rewritten.get_mut(&index).expect("Value is just inserted"))
+    }
+
+    /// Unlike `get_value_mut`, does not read the value from the wrapped storage
+    fn set_value(&mut self, level: usize, index: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        let buffer = self.levels.get_mut(level).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        if index >= buffer.len() {
+            Err(AccessError::IndexIsOutOfBounds)?;
+        }
+        match index.checked_sub(buffer.flushed_len) {
+            Some(appended) => buffer.appended[appended] = value,
+            None => {
+                buffer.rewritten.insert(index, value);
+            },
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, level: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        self.buffer_mut(level)?.appended.push(value);
+        Ok(())
+    }
+
+    fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
+        where I: IntoIterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>>
+    {
+        let buffer = self.buffer_mut(level)?;
+        for v in other.into_iter() {
+            buffer.appended.push(v?);
+        }
+        Ok(())
+    }
+
+    fn extend_from_slice(&mut self, level: usize, slice: &[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()> {
+        self.buffer_mut(level)?.appended.extend_from_slice(slice);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use fun::stream::ReadRegion;
+    use merkle_tree::MerkleTree;
+    use merkle_tree::transaction::Transaction;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    // Counts calls, which write to the storage
+    #[derive(Debug)]
+    struct CountingTree(MemoryTreeStorage<Sha256>, Rc<Cell<usize>>);
+
+    impl CountingTree {
+        fn count(&self) {
+            self.1.set(self.1.get() + 1);
+        }
+    }
+
+    impl TreeStorageReadonly for CountingTree {
+        type Algorithm = Sha256;
+
+        fn len(&self) -> Result<usize> {
+            self.0.len()
+        }
+
+        fn get_level_len(&self, level: usize) -> Result<usize> {
+            self.0.get_level_len(level)
+        }
+
+        fn get_value(&self, level: usize, index: usize) -> Result<Sha256Value> {
+            self.0.get_value(level, index)
+        }
+
+        fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<Sha256Value>> + 's>> {
+            self.0.iter_level(level)
+        }
+
+        fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<(Sha256Value, Sha256Value)>> + 's>> {
+            self.0.iter_level_by_pair(level)
+        }
+
+        fn is_writeable(&self) -> bool {
+            true
+        }
+    }
+
+    impl TreeStorage for CountingTree {
+        fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+            self.count();
+            self.0.clear_and_reserve(sizes)
+        }
+
+        fn grow(&mut self) -> Result<()> {
+            self.count();
+            self.0.grow()
+        }
+
+        fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+            self.count();
+            self.0.truncate(sizes)
+        }
+
+        fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut Sha256Value> {
+            self.count();
+            self.0.get_value_mut(level, index)
+        }
+
+        fn push(&mut self, level: usize, value: Sha256Value) -> Result<()> {
+            self.count();
+            self.0.push(level, value)
+        }
+
+        fn extend<I>(&mut self, level: usize, other: I) -> Result<()> where I: IntoIterator<Item=Result<Sha256Value>> {
+            self.count();
+            self.0.extend(level, other)
+        }
+
+        fn extend_from_slice(&mut self, level: usize, slice: &[Sha256Value]) -> Result<()> {
+            self.count();
+            self.0.extend_from_slice(level, slice)
+        }
+    }
+
+    fn expected(len: u32) -> MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>> {
+        MerkleTree::new_and_rebuild(MemoryDataStorage::with_data((0 .. len).collect::<Vec<_>>()), Default::default()).unwrap()
+    }
+
+    #[test]
+    fn buffered_tree_storage_batches_writes() {
+        let writes = Rc::new(Cell::new(0));
+        let inner = CountingTree(MemoryTreeStorage::new(), writes.clone());
+        let mut mt = MerkleTree::new_and_rebuild(MemoryDataStorage::new(), BufferedTreeStorage::new(inner).unwrap()).unwrap();
+        writes.set(0);
+        for x in 0 .. 100 {
+            mt.push(x).unwrap();
+            assert_eq!(mt.get_root().unwrap(), expected(x + 1).get_root().unwrap());
+        }
+        assert_eq!(writes.get(), 0);
+        assert!(mt.tree().inner().is_empty().unwrap());
+        mt.check_tree().unwrap();
+
+        mt.flush().unwrap();
+        let flushed = writes.get();
+        assert!(flushed > 0 && flushed < 30);
+        assert_eq!(mt.tree().inner().get_root().unwrap(), expected(100).get_root().unwrap());
+
+        // Nodes, which are already written, are rewritten in memory
+        mt.update(0, 1000).unwrap();
+        mt.push(100).unwrap();
+        assert_eq!(writes.get(), flushed);
+        assert!(mt.tree().is_dirty().unwrap());
+        mt.check_tree().unwrap();
+        mt.flush().unwrap();
+        assert!(!mt.tree().is_dirty().unwrap());
+        assert_eq!(mt.tree().inner().get_root().unwrap(), mt.get_root().unwrap());
+    }
+
+    #[test]
+    fn buffered_tree_storage_rolls_back_in_memory() {
+        let writes = Rc::new(Cell::new(0));
+        let inner = CountingTree(MemoryTreeStorage::new(), writes.clone());
+        let mut mt = MerkleTree::new_unchecked(MemoryDataStorage::new(), BufferedTreeStorage::new(inner).unwrap());
+        mt.extend((0 .. 10).map(Ok)).unwrap();
+        mt.flush().unwrap();
+        mt.push(10).unwrap();
+        mt.update(1, 1).unwrap();
+        writes.set(0);
+
+        let mut tx = Transaction::new();
+        tx.push(11).push(12).update(0, 100).update(100, 0);
+        assert!(mt.apply(tx).is_err());
+        assert_eq!(writes.get(), 0);
+        assert_eq!(mt.get_root().unwrap(), expected(11).get_root().unwrap());
+        mt.check_tree().unwrap();
+
+        // Nodes, which are written, are cut off in the wrapped storage
+        let mut tx = Transaction::new();
+        tx.truncate(3);
+        mt.apply(tx).unwrap();
+        assert_eq!(mt.get_root().unwrap(), expected(3).get_root().unwrap());
+        mt.flush().unwrap();
+        assert_eq!(mt.tree().inner().get_root().unwrap(), expected(3).get_root().unwrap());
+        assert_eq!(mt.tree().inner().get_level_len(0).unwrap(), 3);
+    }
+
+    #[test]
+    fn buffered_tree_storage_defers_clear_and_truncate() {
+        let writes = Rc::new(Cell::new(0));
+        let inner = CountingTree(MemoryTreeStorage::new(), writes.clone());
+        let mut mt = MerkleTree::new_unchecked(MemoryDataStorage::new(), BufferedTreeStorage::new(inner).unwrap());
+        mt.extend((0 .. 10).map(Ok)).unwrap();
+        mt.flush().unwrap();
+        let flushed_root = expected(10).get_root().unwrap();
+        writes.set(0);
+
+        // The wrapped storage is untouched until flush
+        mt.rebuild().unwrap();
+        mt.data_mut().truncate(5).unwrap();
+        mt.rebuild().unwrap();
+        let mut tx = Transaction::new();
+        tx.truncate(3);
+        mt.apply(tx).unwrap();
+        mt.push(3).unwrap();
+        assert_eq!(writes.get(), 0);
+        assert_eq!(mt.tree().inner().get_root().unwrap(), flushed_root);
+        assert_eq!(mt.get_root().unwrap(), expected(4).get_root().unwrap());
+        mt.check_tree().unwrap();
+
+        mt.flush().unwrap();
+        assert!(writes.get() > 0);
+        assert_eq!(mt.tree().inner().get_root().unwrap(), expected(4).get_root().unwrap());
+        assert_eq!(mt.tree().inner().get_level_len(0).unwrap(), 4);
+
+        // Written nodes, which are cut off, stay in the wrapped storage until flush
+        writes.set(0);
+        let mut tx = Transaction::new();
+        tx.truncate(1);
+        mt.apply(tx).unwrap();
+        mt.extend((1 .. 3).map(Ok)).unwrap();
+        assert_eq!(writes.get(), 0);
+        assert_eq!(mt.tree().inner().get_level_len(0).unwrap(), 4);
+        mt.flush().unwrap();
+        assert_eq!(mt.tree().inner().get_root().unwrap(), expected(3).get_root().unwrap());
+        assert_eq!(mt.tree().inner().len().unwrap(), 3);
+        assert_eq!(mt.tree().inner().0.levels(), expected(3).tree().levels());
+    }
+
+    #[test]
+    fn buffered_tree_storage_rolls_back_failed_rebuild() {
+        let source = Rc::new(RefCell::new(Cursor::new((0 .. 100u8).collect::<Vec<_>>())));
+        let blocks = (0 .. 5).map(|i| ReadRegion::new(source.clone(), i * 10, 10)).collect::<Vec<_>>();
+        let inner = CountingTree(MemoryTreeStorage::new(), Rc::new(Cell::new(0)));
+        let mut mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(blocks), BufferedTreeStorage::new(inner).unwrap()).unwrap();
+        let root = mt.get_root().unwrap();
+
+        // The block past the end of the source fails the rebuild, buffers are restored
+        mt.data_mut().push(ReadRegion::new(source.clone(), 95, 10)).unwrap();
+        assert!(mt.rebuild().is_err());
+        assert_eq!(mt.get_root().unwrap(), root);
+        mt.flush().unwrap();
+        assert_eq!(mt.tree().inner().get_root().unwrap(), root);
+    }
+
+    #[test]
+    fn buffered_tree_storage_is_flushed_on_drop() {
+        let writes = Rc::new(Cell::new(0));
+        {
+            let inner = CountingTree(MemoryTreeStorage::new(), writes.clone());
+            let mut mt = MerkleTree::new_unchecked(MemoryDataStorage::new(), BufferedTreeStorage::new(inner).unwrap());
+            mt.extend((0 .. 10).map(Ok)).unwrap();
+            assert_eq!(writes.get(), 0);
+        }
+        assert!(writes.get() > 0);
+    }
+}
@@ -0,0 +1,246 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+
+use cache::{CacheLimit, CacheStats, LruCache};
+use prelude::*;
+
+
+/// A wrapper over a slow tree storage, which keeps recently read nodes in memory.
+/// Nodes of the top levels may be pinned: they are kept until changed and do not count
+/// against the limit. Writes go straight to the storage
+pub struct CachedTreeStorage<T> where T: TreeStorage {
+    inner: T,
+    cache: RefCell<LruCache<(usize, usize), <T::Algorithm as MTAlgorithm>::Value>>,
+    // The number of top levels, which are pinned
+    pinned_levels: usize,
+    pinned: RefCell<HashMap<(usize, usize), <T::Algorithm as MTAlgorithm>::Value>>,
+}
+
+impl <T> CachedTreeStorage<T> where T: TreeStorage {
+    /// Creates a wrapper; if bounded by bytes, nodes are weighed by the length of hash values
+    pub fn new(inner: T, limit: CacheLimit) -> Self {
+        CachedTreeStorage {
+            inner,
+            cache: RefCell::new(LruCache::with_weigher(limit, |value| value.as_bytes().len())),
+            pinned_levels: 0,
+            pinned: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Pins the number of top levels (none by default).
+    /// Proofs always pass the top levels, while those have just a few nodes
+    pub fn with_pinned_levels(mut self, levels: usize) -> Self {
+        self.pinned_levels = levels;
+        self.pinned.borrow_mut().clear();
+        self
+    }
+
+    /// Returns the wrapped storage
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns the wrapped storage, dropping the cache
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Returns counters of the cache; pinned nodes are not counted
+    pub fn stats(&self) -> CacheStats {
+        self.cache.borrow().stats()
+    }
+
+    /// Returns the number of pinned nodes, which are in memory
+    pub fn pinned_len(&self) -> usize {
+        self.pinned.borrow().len()
+    }
+
+    fn is_pinned(&self, level: usize) -> Result<bool> {
+        Ok(level + self.pinned_levels >= self.inner.len()?)
+    }
+
+    fn forget(&self, level: usize, index: usize) {
+        self.cache.borrow_mut().remove(&(level, index));
+        self.pinned.borrow_mut().remove(&(level, index));
+    }
+
+    fn forget_all(&self) {
+        self.cache.borrow_mut().clear();
+        self.pinned.borrow_mut().clear();
+    }
+}
+
+impl <T> fmt::Debug for CachedTreeStorage<T> where T: TreeStorage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CachedTreeStorage({:?}, {:?}, pinned_levels={})", self.inner, self.cache.borrow(), self.pinned_levels)
+    }
+}
+
+/// Iterators go straight to the storage, not to spoil the cache
+impl <T> TreeStorageReadonly for CachedTreeStorage<T> where T: TreeStorage {
+    type Algorithm = T::Algorithm;
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        self.inner.get_level_len(level)
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
+        if self.is_pinned(level)? {
+            if let Some(value) = self.pinned.borrow().get(&(level, index)) {
+                return Ok(value.clone());
+            }
+            let value = self.inner.get_value(level, index)?;
+            self.pinned.borrow_mut().insert((level, index), value.clone());
+            return Ok(value);
+        }
+        if let Some(value) = self.cache.borrow_mut().get(&(level, index)) {
+            return Ok(value);
+        }
+        let value = self.inner.get_value(level, index)?;
+        self.cache.borrow_mut().insert((level, index), value.clone());
+        Ok(value)
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>> {
+        self.inner.iter_level(level)
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<
+        Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
+    > + 's>> {
+        self.inner.iter_level_by_pair(level)
+    }
+
+    fn iter_level_by_group<'s>(&'s self, level: usize, arity: usize) -> Result<Box<Iterator<
+        Item=Result<Vec<<Self::Algorithm as MTAlgorithm>::Value>>
+    > + 's>> {
+        self.inner.iter_level_by_group(level, arity)
+    }
+
+    fn for_each_group<F>(&self, level: usize, arity: usize, f: F) -> Result<()>
+        where F: FnMut(&[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>
+    {
+        self.inner.for_each_group(level, arity, f)
+    }
+
+    fn is_writeable(&self) -> bool {
+        self.inner.is_writeable()
+    }
+}
+
+impl <T> TreeStorage for CachedTreeStorage<T> where T: TreeStorage {
+    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+        self.forget_all();
+        self.inner.clear_and_reserve(sizes)
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        self.inner.grow()?;
+        // The lowest pinned level is not pinned anymore
+        let first_pinned = self.inner.len()?.saturating_sub(self.pinned_levels);
+        self.pinned.borrow_mut().retain(|&(level, _), _| level >= first_pinned);
+        Ok(())
+    }
+
+    fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+        self.forget_all();
+        self.inner.truncate(sizes)
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        self.inner.commit()
+    }
+
+    fn rollback(&mut self) -> Result<()> {
+        self.forget_all();
+        self.inner.rollback()
+    }
+
+    fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut <Self::Algorithm as MTAlgorithm>::Value> {
+        self.forget(level, index);
+        self.inner.get_value_mut(level, index)
+    }
+
+    fn set_value(&mut self, level: usize, index: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        self.forget(level, index);
+        self.inner.set_value(level, index, value)
+    }
+
+    fn push(&mut self, level: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        self.inner.push(level, value)
+    }
+
+    fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
+        where I: IntoIterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>>
+    {
+        self.inner.extend(level, other)
+    }
+
+    fn extend_from_slice(&mut self, level: usize, slice: &[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()> {
+        self.inner.extend_from_slice(level, slice)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::cached::CachedDataStorage;
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use merkle_tree::MerkleTree;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    type Tree = MerkleTree<CachedDataStorage<MemoryDataStorage<u32>>, CachedTreeStorage<MemoryTreeStorage<Sha256>>>;
+
+    fn sample(limit: CacheLimit, pinned: usize) -> Tree {
+        let data = CachedDataStorage::new(MemoryDataStorage::with_data((0 .. 100).collect::<Vec<_>>()), limit);
+        let tree = CachedTreeStorage::new(MemoryTreeStorage::new(), limit).with_pinned_levels(pinned);
+        MerkleTree::new_and_rebuild(data, tree).unwrap()
+    }
+
+    #[test]
+    fn cached_tree_storage_proofs() {
+        let mt = sample(CacheLimit::Bytes(32 * 8), 3);
+        let root = mt.get_root().unwrap().unwrap();
+        for _ in 0 .. 2 {
+            for index in 0 .. 8 {
+                let proof = mt.inclusion_proof(index).unwrap();
+                assert!(proof.verify::<Sha256, _>(&(index as u32), &root).unwrap());
+            }
+        }
+        let stats = mt.tree().stats();
+        assert!(stats.weight <= 32 * 8);
+        assert!(stats.hits > stats.misses);
+        // The root, 2 nodes below it, and up to 4 nodes of the third level
+        assert!(mt.tree().pinned_len() <= 7);
+        mt.check_tree().unwrap();
+    }
+
+    #[test]
+    fn cached_tree_storage_is_updated() {
+        let mut mt = sample(CacheLimit::Entries(1000), 2);
+        for index in 0 .. 100 {
+            mt.audit_proof(index).unwrap();
+        }
+        mt.update(5, 1000).unwrap();
+        mt.extend((100 .. 200).map(Ok)).unwrap();
+        mt.push(200).unwrap();
+        mt.check_data().unwrap();
+        mt.check_tree().unwrap();
+
+        let mut data = (0 .. 201).collect::<Vec<_>>();
+        data[5] = 1000;
+        let expected: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+        expected = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data), Default::default()).unwrap();
+        assert_eq!(mt.get_root().unwrap(), expected.get_root().unwrap());
+        for index in 0 .. 201 {
+            assert_eq!(mt.audit_proof(index).unwrap(), expected.audit_proof(index).unwrap());
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::fmt;
+
+use prelude::*;
+
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Level {
+    offset: usize,
+    len: usize,
+    capacity: usize,
+}
+
+/// An inmemory storage for a tree of hashes, which keeps all levels in one buffer.
+/// Every level has a reserved range of the buffer, from the bottom level to the root.
+/// When a level is full, all the levels are moved into a new buffer with twice the room
+pub struct FlatTreeStorage<A> where A: MTAlgorithm {
+    // Slots behind the length of a level are either missing or filled with copies of a value
+    buffer: Vec<A::Value>,
+    levels: Vec<Level>,
+}
+
+impl <A> Default for FlatTreeStorage<A> where A: MTAlgorithm {
+    fn default() -> Self {
+        FlatTreeStorage {
+            buffer: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+}
+
+impl <A> FlatTreeStorage<A> where A: MTAlgorithm {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the values of a level
+    pub fn level(&self, level: usize) -> Result<&[A::Value]> {
+        let level = self.levels.get(level).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        Ok(&self.buffer[level.offset .. level.offset + level.len])
+    }
+
+    // Makes room for `additional` values at the level
+    fn reserve(&mut self, level: usize, additional: usize, fill: &A::Value) -> Result<()> {
+        let needed = {
+            let level = self.levels.get(level).ok_or(StateError::InconsistentState)?;
+            level.len + additional
+        };
+        if needed <= self.levels[level].capacity {
+            return Ok(());
+        }
+        // An upper level gets at least a half of the room of the level below,
+        // so it can not be full before the bottom level is full
+        let mut bottom = self.levels[0].capacity;
+        if level == 0 {
+            bottom = needed.max(bottom * 2);
+        }
+        let capacities = self.levels.iter().enumerate()
+            .map(|(index, old)| {
+                let room = bottom.saturating_sub(1).checked_shr(index as u32).unwrap_or(0) + 1;
+                let capacity = room.max(old.len * 2).max(old.capacity);
+                if index == level { capacity.max(needed) } else { capacity }
+            })
+            .collect::<Vec<_>>();
+        let mut buffer = Vec::with_capacity(capacities.iter().sum());
+        let mut offset = 0;
+        let last = self.levels.len() - 1;
+        for (index, (old, capacity)) in self.levels.iter_mut().zip(capacities).enumerate() {
+            let end = (old.offset + old.len).min(self.buffer.len());
+            buffer.extend_from_slice(&self.buffer[old.offset.min(end) .. end]);
+            if index < last {
+                let value = buffer.last().unwrap_or(fill).clone();
+                buffer.resize(offset + capacity, value);
+            }
+            *old = Level { offset, len: old.len, capacity };
+            offset += capacity;
+        }
+        self.buffer = buffer;
+        Ok(())
+    }
+
+    // Places a value behind the end of the level, which has room for it
+    fn put(&mut self, level: usize, value: A::Value) {
+        let position = self.levels[level].offset + self.levels[level].len;
+        if position < self.buffer.len() {
+            self.buffer[position] = value;
+        } else {
+            let fill = value.clone();
+            self.buffer.resize(position, fill);
+            self.buffer.push(value);
+        }
+        self.levels[level].len += 1;
+    }
+}
+
+impl <A> fmt::Debug for FlatTreeStorage<A> where A: MTAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "FlatTreeStorage(len={}, buffer={})", self.levels.len(), self.buffer.len())
+    }
+}
+
+impl <A> TreeStorageReadonly for FlatTreeStorage<A> where A: MTAlgorithm {
+    type Algorithm = A;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.levels.len())
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.levels.is_empty())
+    }
+
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        self.levels.get(level)
+            .map(|level| level.len)
+            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
+        match self.levels.get(level) {
+            Some(level) if index < level.len => Ok(self.buffer[level.offset + index].clone()),
+            _ => Err(INDEX_IS_OUT_OF_BOUNDS),
+        }
+    }
+
+    fn with_value<R, F>(&self, level: usize, index: usize, f: F) -> Result<R>
+        where F: FnOnce(&<Self::Algorithm as MTAlgorithm>::Value) -> Result<R>
+    {
+        f(self.level(level)?.get(index).ok_or(INDEX_IS_OUT_OF_BOUNDS)?)
+    }
+
+    fn for_each_group<F>(&self, level: usize, arity: usize, f: F) -> Result<()>
+        where F: FnMut(&[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>
+    {
+        for_each_group_of_slice(self.level(level)?, arity, f)
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>> {
+        Ok(Box::new(self.level(level)?.iter().cloned().map(Ok)))
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<
+        Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
+    > + 's>> {
+        Ok(Box::new(self.level(level)?.chunks(2).map(|chunk| {
+            let i2 = (chunk.len() + 1) % 2;
+            Ok((chunk[0].clone(), chunk[i2].clone()))
+        })))
+    }
+
+    fn iter_level_by_group<'s>(&'s self, level: usize, arity: usize) -> Result<Box<Iterator<
+        Item=Result<Vec<<Self::Algorithm as MTAlgorithm>::Value>>
+    > + 's>> {
+        Ok(Box::new(self.level(level)?.chunks(arity).map(move |chunk| {
+            let mut group = chunk.to_vec();
+            group.resize(arity, chunk[chunk.len() - 1].clone());
+            Ok(group)
+        })))
+    }
+
+    fn is_writeable(&self) -> bool {
+        true
+    }
+}
+
+impl <A> TreeStorage for FlatTreeStorage<A> where A: MTAlgorithm {
+    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+        self.buffer.clear();
+        self.buffer.reserve(sizes.iter().sum());
+        self.levels.clear();
+        let mut offset = 0;
+        for &size in sizes {
+            self.levels.push(Level { offset, len: 0, capacity: size });
+            offset += size;
+        }
+        Ok(())
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        let offset = self.levels.last().map_or(0, |level| level.offset + level.capacity);
+        self.levels.push(Level { offset, len: 0, capacity: 0 });
+        Ok(())
+    }
+
+    fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+        self.levels.truncate(sizes.len());
+        for (level, &size) in self.levels.iter_mut().zip(sizes) {
+            level.len = level.len.min(size);
+        }
+        Ok(())
+    }
+
+    fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut <Self::Algorithm as MTAlgorithm>::Value> {
+        match self.levels.get(level) {
+            Some(level) if index < level.len => Ok(&mut self.buffer[level.offset + index]),
+            _ => Err(INDEX_IS_OUT_OF_BOUNDS),
+        }
+    }
+
+    fn push(&mut self, level: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        self.reserve(level, 1, &value)?;
+        self.put(level, value);
+        Ok(())
+    }
+
+    fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
+        where I: IntoIterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>>
+    {
+        for v in other.into_iter() {
+            self.push(level, v?)?;
+        }
+        Ok(())
+    }
+
+    fn extend_from_slice(&mut self, level: usize, slice: &[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()> {
+        if let Some(fill) = slice.first() {
+            self.reserve(level, slice.len(), fill)?;
+        }
+        for value in slice {
+            self.put(level, value.clone());
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use merkle_tree::MerkleTree;
+    use tree_storage::memory::MemoryTreeStorage;
+    use super::*;
+
+    #[test]
+    fn flat_tree_storage() {
+        let mut mt: MerkleTree<MemoryDataStorage<u32>, FlatTreeStorage<Sha256>> = MerkleTree::default();
+        let mut expected: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>> = MerkleTree::default();
+        for x in 0 .. 100 {
+            mt.push(x).unwrap();
+            expected.push(x).unwrap();
+            assert_eq!(mt.get_root().unwrap(), expected.get_root().unwrap());
+        }
+        mt.extend((100 .. 300).map(Ok)).unwrap();
+        expected.extend((100 .. 300).map(Ok)).unwrap();
+        mt.update(10, 1000).unwrap();
+        expected.update(10, 1000).unwrap();
+        mt.check_tree().unwrap();
+        for level in 0 .. mt.tree().len().unwrap() {
+            let values = mt.tree().iter_level(level).unwrap().collect::<Result<Vec<_>>>().unwrap();
+            let expected = expected.tree().iter_level(level).unwrap().collect::<Result<Vec<_>>>().unwrap();
+            assert_eq!(values, expected);
+        }
+
+        mt.rebuild().unwrap();
+        assert_eq!(mt.get_root().unwrap(), expected.get_root().unwrap());
+        // Levels are laid out one after another, as they were reserved by `rebuild`
+        assert_eq!(mt.tree().buffer.len(), 300 + 150 + 75 + 38 + 19 + 10 + 5 + 3 + 2 + 1);
+        mt.push(300).unwrap();
+        expected.push(300).unwrap();
+        assert_eq!(mt.get_root().unwrap(), expected.get_root().unwrap());
+        mt.check_tree().unwrap();
+    }
+}
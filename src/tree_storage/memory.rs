@@ -1,19 +1,111 @@
+use std::borrow::Cow;
 use std::fmt;
 
 use prelude::*;
 
+
+/// An inmemory tree of hashes, which can only be read
+/// May be just a reference to the levels of another tree
+pub struct MemoryReadonlyTreeStorage<'a, A> where A: MTAlgorithm, A::Value: 'a {
+    layers: Cow<'a, [Vec<A::Value>]>,
+}
+
+impl <'a, A> MemoryReadonlyTreeStorage<'a, A> where A: MTAlgorithm, A::Value: 'a {
+    /// Creates an instance, representing `levels`, from the bottom level to the root
+    pub fn with_levels<L: Into<Cow<'a, [Vec<A::Value>]>>>(levels: L) -> Self {
+        MemoryReadonlyTreeStorage { layers: levels.into() }
+    }
+}
+
+impl <'a, A> fmt::Debug for MemoryReadonlyTreeStorage<'a, A> where A: MTAlgorithm, A::Value: 'a {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "MemoryReadonlyTreeStorage(len={})", self.layers.len())
+    }
+}
+
+impl <'a, A> TreeStorageReadonly for MemoryReadonlyTreeStorage<'a, A> where A: MTAlgorithm, A::Value: 'a {
+    type Algorithm = A;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.layers.len())
+    }
+
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        Ok(get_level(&self.layers, level)?.len())
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
+        get_value(&self.layers, level, index).map(Clone::clone)
+    }
+
+    fn with_value<R, F>(&self, level: usize, index: usize, f: F) -> Result<R>
+        where F: FnOnce(&<Self::Algorithm as MTAlgorithm>::Value) -> Result<R>
+    {
+        f(get_value(&self.layers, level, index)?)
+    }
+
+    fn for_each_group<F>(&self, level: usize, arity: usize, f: F) -> Result<()>
+        where F: FnMut(&[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>
+    {
+        for_each_group_of_slice(get_level(&self.layers, level)?, arity, f)
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>> {
+        iter_level(&self.layers, level)
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<
+        Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
+    > + 's>> {
+        iter_level_by_pair(&self.layers, level)
+    }
+
+    fn get_root(&self) -> Result<Option<<Self::Algorithm as MTAlgorithm>::Value>> {
+        Ok(get_root(&self.layers))
+    }
+}
+
+
+// Readers over levels, from the bottom level to the root, shared by both storages
+
+fn get_level<V>(layers: &[Vec<V>], level: usize) -> Result<&[V]> {
+    layers.get(level).map(|layer| &layer[..]).ok_or(INDEX_IS_OUT_OF_BOUNDS)
+}
+
+fn get_value<V>(layers: &[Vec<V>], level: usize, index: usize) -> Result<&V> {
+    get_level(layers, level)?.get(index).ok_or(INDEX_IS_OUT_OF_BOUNDS)
+}
+
+fn get_root<V: Clone>(layers: &[Vec<V>]) -> Option<V> {
+    layers.last().and_then(|layer| layer.last().cloned())
+}
+
+fn iter_level<'s, V: Clone>(layers: &'s [Vec<V>], level: usize) -> Result<Box<Iterator<Item=Result<V>> + 's>> {
+    Ok(Box::new(get_level(layers, level)?.iter().cloned().map(Ok)))
+}
+
+fn iter_level_by_pair<'s, V: Clone>(layers: &'s [Vec<V>], level: usize) -> Result<Box<Iterator<Item=Result<(V, V)>> + 's>> {
+    Ok(Box::new(get_level(layers, level)?.chunks(2).map(|chunk| {
+        let i2 = (chunk.len() + 1) % 2;
+        Ok((chunk[0].clone(), chunk[i2].clone()))
+    })))
+}
+
+
 /// An inmemory storage for a tree of hashes
 pub struct MemoryTreeStorage<A> where A: MTAlgorithm {
     // Hashes are stored as layers
     // In the begin (index 0) is the bottom level 0 with hashes of the data
     // next layers keep hashes of previous levels, till the root
-    layers: Vec<Vec<A::Value>>
+    layers: Vec<Vec<A::Value>>,
+    is_writable: bool,
 }
 
 impl <A> Default for MemoryTreeStorage<A> where A: MTAlgorithm {
     fn default() -> Self {
         MemoryTreeStorage {
-            layers: Vec::new()
+            layers: Vec::new(),
+            is_writable: true,
         }
     }
 }
@@ -23,10 +115,37 @@ impl <A> MemoryTreeStorage<A> where A: MTAlgorithm {
         Default::default()
     }
 
+    /// Creates an instance with the levels, from the bottom level to the root
+    pub fn with_levels<L: Into<Vec<Vec<A::Value>>>>(levels: L) -> Self {
+        MemoryTreeStorage { layers: levels.into(), is_writable: true }
+    }
+
+    /// Returns levels of the tree, from the bottom level to the root
+    pub fn levels(&self) -> &[Vec<A::Value>] {
+        &self.layers
+    }
+
+    /// Returns levels of the tree, consuming the storage
+    pub fn into_levels(self) -> Vec<Vec<A::Value>> {
+        self.layers
+    }
+
+    /// sets whether tree storage can accept new values
+    pub fn set_writable(&mut self, is_writable: bool) {
+        self.is_writable = is_writable;
+    }
+
     #[cfg(test)]
     pub fn data_mut(&mut self) -> &mut Vec<Vec<A::Value>> {
         &mut self.layers
     }
+
+    fn check_if_writable(&self) -> Result<()> {
+        if !self.is_writeable() {
+            Err(Error::new_ro("The tree storage is in read-only mode"))?;
+        }
+        Ok(())
+    }
 }
 
 impl <A> fmt::Debug for MemoryTreeStorage<A> where A: MTAlgorithm {
@@ -35,7 +154,24 @@ impl <A> fmt::Debug for MemoryTreeStorage<A> where A: MTAlgorithm {
     }
 }
 
-impl <A> TreeStorage for MemoryTreeStorage<A> where A: MTAlgorithm {
+/// Serialized as a sequence of levels, from the bottom level to the root;
+/// the writable mode is not preserved
+#[cfg(feature = "serde")]
+impl <A> ::serde::Serialize for MemoryTreeStorage<A> where A: MTAlgorithm, A::Value: ::serde::Serialize {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        ::serde::Serialize::serialize(&self.layers, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl <'de, A> ::serde::Deserialize<'de> for MemoryTreeStorage<A> where A: MTAlgorithm, A::Value: ::serde::Deserialize<'de> {
+    fn deserialize<D: ::serde::Deserializer<'de>>(deserializer: D) -> ::std::result::Result<Self, D::Error> {
+        let layers = ::serde::Deserialize::deserialize(deserializer)?;
+        Ok(MemoryTreeStorage { layers, is_writable: true })
+    }
+}
+
+impl <A> TreeStorageReadonly for MemoryTreeStorage<A> where A: MTAlgorithm {
     type Algorithm = A;
 
     fn len(&self) -> Result<usize> {
@@ -46,7 +182,58 @@ impl <A> TreeStorage for MemoryTreeStorage<A> where A: MTAlgorithm {
         Ok(self.layers.is_empty())
     }
 
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        Ok(get_level(&self.layers, level)?.len())
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
+        get_value(&self.layers, level, index).map(Clone::clone)
+    }
+
+    fn with_value<R, F>(&self, level: usize, index: usize, f: F) -> Result<R>
+        where F: FnOnce(&<Self::Algorithm as MTAlgorithm>::Value) -> Result<R>
+    {
+        f(get_value(&self.layers, level, index)?)
+    }
+
+    fn for_each_group<F>(&self, level: usize, arity: usize, f: F) -> Result<()>
+        where F: FnMut(&[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()>
+    {
+        for_each_group_of_slice(get_level(&self.layers, level)?, arity, f)
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>> {
+        iter_level(&self.layers, level)
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<
+        Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
+    > + 's>> {
+        iter_level_by_pair(&self.layers, level)
+    }
+
+    fn iter_level_by_group<'s>(&'s self, level: usize, arity: usize) -> Result<Box<Iterator<
+        Item=Result<Vec<<Self::Algorithm as MTAlgorithm>::Value>>
+    > + 's>> {
+        Ok(Box::new(get_level(&self.layers, level)?.chunks(arity).map(move |chunk| {
+            let mut group = chunk.to_vec();
+            group.resize(arity, chunk[chunk.len() - 1].clone());
+            Ok(group)
+        })))
+    }
+
+    fn get_root(&self) -> Result<Option<<Self::Algorithm as MTAlgorithm>::Value>> {
+        Ok(get_root(&self.layers))
+    }
+
+    fn is_writeable(&self) -> bool {
+        self.is_writable
+    }
+}
+
+impl <A> TreeStorage for MemoryTreeStorage<A> where A: MTAlgorithm {
     fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+        self.check_if_writable()?;
         self.layers.truncate(sizes.len());
         while self.layers.len() < sizes.len() {
             self.layers.push(Vec::new());
@@ -60,29 +247,29 @@ impl <A> TreeStorage for MemoryTreeStorage<A> where A: MTAlgorithm {
     }
 
     fn grow(&mut self) -> Result<()> {
+        self.check_if_writable()?;
         self.layers.push(Vec::new());
         Ok(())
     }
 
-    fn get_level_len(&self, level: usize) -> Result<usize> {
-        self.layers.get(level)
-            .map(Vec::len)
-            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
-    }
-
-    fn get_value(&self, level: usize, index: usize) -> Result<<Self::Algorithm as MTAlgorithm>::Value> {
-        self.layers.get(level)
-            .and_then(|layer| layer.get(index).cloned())
-            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+        self.check_if_writable()?;
+        self.layers.truncate(sizes.len());
+        for (layer, &size) in self.layers.iter_mut().zip(sizes) {
+            layer.truncate(size);
+        }
+        Ok(())
     }
 
     fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut <Self::Algorithm as MTAlgorithm>::Value> {
+        self.check_if_writable()?;
         self.layers.get_mut(level)
             .and_then(|layer| layer.get_mut(index))
             .ok_or(INDEX_IS_OUT_OF_BOUNDS)
     }
 
     fn push(&mut self, level: usize, value: <Self::Algorithm as MTAlgorithm>::Value) -> Result<()> {
+        self.check_if_writable()?;
         let layer = self.layers.get_mut(level).ok_or(StateError::InconsistentState)?;
         layer.push(value);
         Ok(())
@@ -91,6 +278,7 @@ impl <A> TreeStorage for MemoryTreeStorage<A> where A: MTAlgorithm {
     fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
         where I: IntoIterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>>
     {
+        self.check_if_writable()?;
         let layer = self.layers.get_mut(level).ok_or(StateError::InconsistentState)?;
         for v in other.into_iter() {
             layer.push(v?);
@@ -99,29 +287,48 @@ impl <A> TreeStorage for MemoryTreeStorage<A> where A: MTAlgorithm {
     }
 
     fn extend_from_slice(&mut self, level: usize, slice: &[<Self::Algorithm as MTAlgorithm>::Value]) -> Result<()> {
+        self.check_if_writable()?;
         let layer = self.layers.get_mut(level).ok_or(StateError::InconsistentState)?;
         layer.extend_from_slice(slice);
         Ok(())
     }
+}
 
-    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<<Self::Algorithm as MTAlgorithm>::Value>> + 's>> {
-        self.layers.get(level)
-            .map(|layer| Box::new(layer.iter().cloned().map(Ok)) as Box<Iterator<Item=_>>)
-            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
-    }
 
-    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<
-        Item=Result<(<Self::Algorithm as MTAlgorithm>::Value, <Self::Algorithm as MTAlgorithm>::Value)>
-    > + 's>> {
-        self.layers.get(level)
-            .map(|layer| Box::new(layer.chunks(2).map(|chunk| {
-                let i2 = (chunk.len() + 1) % 2;
-                Ok((chunk[0].clone(), chunk[i2].clone()))
-            })) as Box<Iterator<Item=_>>)
-            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+#[cfg(test)]
+mod tests {
+    use abc::*;
+    use fun::sha256::Sha256;
+    use super::MemoryReadonlyTreeStorage;
+    use super::MemoryTreeStorage;
+
+    #[test]
+    fn memory_tree_storage_ro_rw_mode() {
+        let mut ts = MemoryTreeStorage::<Sha256>::default();
+        ts.grow().unwrap();
+        assert!(ts.push(0, Sha256::eval_hash(&0u32)).is_ok());
+        ts.set_writable(false);
+        assert!(!ts.is_writeable());
+        assert!(ts.push(0, Sha256::eval_hash(&1u32)).is_err());
+        assert!(ts.extend_from_slice(0, &[Sha256::eval_hash(&1u32)]).is_err());
+        assert!(ts.extend(0, vec![Ok(Sha256::eval_hash(&1u32))]).is_err());
+        assert!(ts.truncate(&[]).is_err());
+        assert!(ts.clear_and_reserve(&[]).is_err());
+        assert!(ts.grow().is_err());
+        assert!(ts.get_level_len(0).unwrap() == 1);
+        ts.set_writable(true);
+        assert!(ts.push(0, Sha256::eval_hash(&1u32)).is_ok());
+        assert!(ts.get_level_len(0).unwrap() == 2);
     }
 
-    fn get_root(&self) -> Result<Option<<Self::Algorithm as MTAlgorithm>::Value>> {
-        Ok(self.layers.iter().last().and_then(|layer| layer.iter().last().cloned()))
+    #[test]
+    fn memory_readonly_tree_storage() {
+        let mut ts = MemoryTreeStorage::<Sha256>::default();
+        ts.grow().unwrap();
+        ts.push(0, Sha256::eval_hash(&0u32)).unwrap();
+        let ro = MemoryReadonlyTreeStorage::<Sha256>::with_levels(ts.levels());
+        assert!(ro.len().unwrap() == 1);
+        assert_eq!(ro.get_root().unwrap(), ts.get_root().unwrap());
+        assert!(!ro.is_writeable());
     }
 }
@@ -1,4 +1,16 @@
 pub mod abc;
+pub mod buffered;
+pub mod cached;
+pub mod flat;
 pub mod memory;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod versioned;
 
+pub use self::buffered::*;
+pub use self::cached::*;
+pub use self::flat::*;
 pub use self::memory::*;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::*;
+pub use self::versioned::*;
@@ -0,0 +1,314 @@
+use std::fmt;
+use std::marker::PhantomData;
+use std::path::Path;
+
+use rusqlite::Connection;
+use rusqlite::NO_PARAMS;
+use rusqlite::types::ToSql;
+
+use prelude::*;
+
+
+/// A storage for the tree of hashes in a table of a SQLite database, a row per node.
+/// Hash values are kept as raw bytes. Widths of levels are kept in memory.
+/// `clear_and_reserve` opens a transaction, which `commit` closes and `rollback` discards,
+/// so a rebuild is atomic
+pub struct SqliteTreeStorage<A> where A: MTAlgorithm {
+    connection: Connection,
+    // The quoted name of the table
+    table: String,
+    levels: Vec<usize>,
+    // Widths of levels before `clear_and_reserve`, while its transaction is open
+    cleared: Option<Vec<usize>>,
+    _algorithm: PhantomData<A>,
+}
+
+impl <A> SqliteTreeStorage<A> where A: MTAlgorithm {
+    /// Opens the database file and creates the table, if it does not exist.
+    /// The table may be in the same file as the data
+    pub fn open<P: AsRef<Path>>(path: P, table: &str) -> Result<Self> {
+        Self::with_connection(Connection::open(path)?, table)
+    }
+
+    /// Creates the table in the database, if it does not exist
+    pub fn with_connection(connection: Connection, table: &str) -> Result<Self> {
+        let (table, levels) = open_tree_table(&connection, table)?;
+        Ok(SqliteTreeStorage { connection, table, levels, cleared: None, _algorithm: PhantomData })
+    }
+
+    /// Returns the connection to the database
+    pub fn connection(&self) -> &Connection {
+        &self.connection
+    }
+
+    /// Returns the connection to the database, consuming the storage
+    pub fn into_connection(self) -> Connection {
+        self.connection
+    }
+
+    fn check_index(&self, level: usize, index: usize) -> Result<()> {
+        match self.levels.get(level) {
+            Some(&len) if index < len => Ok(()),
+            _ => Err(INDEX_IS_OUT_OF_BOUNDS),
+        }
+    }
+}
+
+/// Creates the table for nodes of the tree, if it does not exist.
+/// Returns the quoted name of the table and widths of levels
+pub fn open_tree_table(connection: &Connection, table: &str) -> Result<(String, Vec<usize>)> {
+    let table = format!("\"{}\"", table.replace('"', "\"\""));
+    connection.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {} (
+            level INTEGER NOT NULL,
+            idx INTEGER NOT NULL,
+            hash BLOB NOT NULL,
+            PRIMARY KEY (level, idx)
+        ) WITHOUT ROWID", table
+    ))?;
+    let mut levels = Vec::new();
+    let mut statement = connection.prepare(&format!(
+        "SELECT level, COUNT(*), MAX(idx) + 1 FROM {} GROUP BY level ORDER BY level", table
+    ))?;
+    let rows = statement.query_map(NO_PARAMS, |row| {
+        Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?, row.get::<_, i64>(2)?))
+    })?;
+    for row in rows {
+        let (level, count, end) = row?;
+        // Levels and nodes are numbered from 0 without gaps
+        if level != levels.len() as i64 || count != end {
+            Err(StateError::InconsistentState)?;
+        }
+        levels.push(count as usize);
+    }
+    Ok((table, levels))
+}
+
+impl <A> fmt::Debug for SqliteTreeStorage<A> where A: MTAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SqliteTreeStorage(table={}, levels={:?})", self.table, self.levels)
+    }
+}
+
+impl <A> TreeStorageReadonly for SqliteTreeStorage<A> where A: MTAlgorithm {
+    type Algorithm = A;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.levels.len())
+    }
+
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        self.levels.get(level).cloned().ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<A::Value> {
+        self.check_index(level, index)?;
+        let mut statement = self.connection.prepare_cached(&format!("SELECT hash FROM {} WHERE level = ? AND idx = ?", self.table))?;
+        let bytes: Vec<u8> = statement.query_row(&[level as i64, index as i64], |row| row.get(0))?;
+        A::Value::from_bytes(&bytes)
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<A::Value>> + 's>> {
+        let len = self.get_level_len(level)?;
+        Ok(Box::new((0 .. len).map(move |index| self.get_value(level, index))))
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<(A::Value, A::Value)>> + 's>> {
+        let len = self.get_level_len(level)?;
+        Ok(Box::new((0 .. (len + 1) / 2).map(move |pair| {
+            let left = self.get_value(level, pair * 2)?;
+            let right = self.get_value(level, pair * 2 + 1).iob_is_ok()?.unwrap_or_else(|| left.clone());
+            Ok((left, right))
+        })))
+    }
+
+    fn is_writeable(&self) -> bool {
+        true
+    }
+}
+
+/// Values are in the database, so `get_value_mut` is not supported, `set_value` is.
+/// `extend` and `extend_from_slice` write a level in one savepoint,
+/// which is a part of the transaction of a rebuild, if it is open
+impl <A> TreeStorage for SqliteTreeStorage<A> where A: MTAlgorithm {
+    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+        if self.cleared.is_none() {
+            self.connection.execute_batch("BEGIN")?;
+            self.cleared = Some(self.levels.clone());
+        }
+        self.connection.execute(&format!("DELETE FROM {}", self.table), NO_PARAMS)?;
+        self.levels = vec![0; sizes.len()];
+        Ok(())
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        self.levels.push(0);
+        Ok(())
+    }
+
+    fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+        let transaction = self.connection.savepoint()?;
+        transaction.execute(&format!("DELETE FROM {} WHERE level >= ?", self.table), &[sizes.len() as i64])?;
+        for (level, &size) in sizes.iter().enumerate().take(self.levels.len()) {
+            transaction.execute(
+                &format!("DELETE FROM {} WHERE level = ? AND idx >= ?", self.table),
+                &[level as i64, size as i64],
+            )?;
+        }
+        transaction.commit()?;
+        self.levels.truncate(sizes.len());
+        for (len, &size) in self.levels.iter_mut().zip(sizes) {
+            *len = size.min(*len);
+        }
+        Ok(())
+    }
+
+    /// Commits the transaction of a rebuild, if it is open
+    fn commit(&mut self) -> Result<()> {
+        if self.cleared.is_some() {
+            self.connection.execute_batch("COMMIT")?;
+            self.cleared = None;
+        }
+        Ok(())
+    }
+
+    /// Rolls back the transaction of a rebuild, if it is open.
+    /// A transaction, which is not committed, is also rolled back when the connection is closed
+    fn rollback(&mut self) -> Result<()> {
+        if let Some(levels) = self.cleared.take() {
+            self.connection.execute_batch("ROLLBACK")?;
+            self.levels = levels;
+        }
+        Ok(())
+    }
+
+    fn get_value_mut(&mut self, _level: usize, _index: usize) -> Result<&mut A::Value> {
+        Err(AccessError::NotSupported)?
+    }
+
+    fn set_value(&mut self, level: usize, index: usize, value: A::Value) -> Result<()> {
+        self.check_index(level, index)?;
+        let bytes = value.as_bytes();
+        let mut statement = self.connection.prepare_cached(&format!("UPDATE {} SET hash = ? WHERE level = ? AND idx = ?", self.table))?;
+        statement.execute(&[&&bytes[..] as &ToSql, &(level as i64), &(index as i64)])?;
+        Ok(())
+    }
+
+    fn push(&mut self, level: usize, value: A::Value) -> Result<()> {
+        let index = *self.levels.get(level).ok_or(StateError::InconsistentState)?;
+        let bytes = value.as_bytes();
+        let mut statement = self.connection.prepare_cached(&format!("INSERT INTO {} (level, idx, hash) VALUES (?, ?, ?)", self.table))?;
+        statement.execute(&[&(level as i64) as &ToSql, &(index as i64), &&bytes[..]])?;
+        self.levels[level] += 1;
+        Ok(())
+    }
+
+    fn extend<I>(&mut self, level: usize, other: I) -> Result<()> where I: IntoIterator<Item=Result<A::Value>> {
+        let mut index = *self.levels.get(level).ok_or(StateError::InconsistentState)?;
+        let transaction = self.connection.savepoint()?;
+        {
+            let mut statement = transaction.prepare_cached(&format!("INSERT INTO {} (level, idx, hash) VALUES (?, ?, ?)", self.table))?;
+            for value in other.into_iter() {
+                let bytes = value?.as_bytes().into_owned();
+                statement.execute(&[&(level as i64) as &ToSql, &(index as i64), &bytes])?;
+                index += 1;
+            }
+        }
+        transaction.commit()?;
+        self.levels[level] = index;
+        Ok(())
+    }
+
+    fn extend_from_slice(&mut self, level: usize, slice: &[A::Value]) -> Result<()> {
+        self.extend(level, slice.iter().cloned().map(Ok))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+
+    use data_storage::memory::MemoryDataStorage;
+    use data_storage::sqlite::SqliteDataStorage;
+    use fun::sha256::*;
+    use fun::stream::ReadRegion;
+    use merkle_tree::{MerkleTree, Transaction};
+    use tree_storage::memory::MemoryTreeStorage;
+    use tempfile;
+    use super::*;
+
+    type Tree = MerkleTree<SqliteDataStorage<u32>, SqliteTreeStorage<Sha256>>;
+
+    fn open(path: &Path) -> Tree {
+        let data = SqliteDataStorage::open(path, "data").unwrap();
+        let tree = SqliteTreeStorage::open(path, "tree").unwrap();
+        MerkleTree::new_and_check(data, tree).unwrap()
+    }
+
+    #[test]
+    fn sqlite_storages() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.sqlite");
+
+        let mut mt = open(&path);
+        mt.extend((0 .. 50).map(Ok)).unwrap();
+        mt.push(50).unwrap();
+        let mut tx = Transaction::new();
+        tx.update(7, 70).push(51);
+        mt.apply(tx).unwrap();
+        mt.rebuild().unwrap();
+        let root = mt.get_root().unwrap();
+        drop(mt);
+
+        let mut data = (0 .. 52).collect::<Vec<_>>();
+        data[7] = 70;
+        let expected: MerkleTree<MemoryDataStorage<u32>, MemoryTreeStorage<Sha256>>;
+        expected = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(data), Default::default()).unwrap();
+        assert_eq!(root, expected.get_root().unwrap());
+
+        let mt = open(&path);
+        assert_eq!(mt.get_root().unwrap(), root);
+        mt.check_data().unwrap();
+        mt.check_tree().unwrap();
+        for index in 0 .. 52 {
+            assert_eq!(mt.audit_proof(index).unwrap(), expected.audit_proof(index).unwrap());
+        }
+    }
+
+    #[test]
+    fn sqlite_rebuild_is_atomic() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tree.sqlite");
+
+        // Blocks are read from a source, so a rebuild fails in the middle on a block past its end
+        let source = Rc::new(RefCell::new(Cursor::new((0 .. 100u8).collect::<Vec<_>>())));
+        let blocks = (0 .. 5).map(|i| ReadRegion::new(source.clone(), i * 10, 10)).collect::<Vec<_>>();
+        let tree = SqliteTreeStorage::<Sha256>::open(&path, "tree").unwrap();
+        let mut mt = MerkleTree::new_and_rebuild(MemoryDataStorage::with_data(blocks), tree).unwrap();
+        let root = mt.get_root().unwrap();
+
+        mt.data_mut().push(ReadRegion::new(source.clone(), 95, 10)).unwrap();
+        assert!(mt.rebuild().unwrap_err().is_io_error());
+        assert!(mt.rebuild_streaming().unwrap_err().is_io_error());
+        assert_eq!(mt.get_root().unwrap(), root);
+        mt.check_tree().unwrap();
+        {
+            // The transaction is closed, so other connections can write
+            SqliteTreeStorage::<Sha256>::open(&path, "other").unwrap();
+            assert_eq!(SqliteTreeStorage::<Sha256>::open(&path, "tree").unwrap().get_root().unwrap(), root);
+        }
+
+        mt.data_mut().truncate(5).unwrap();
+        mt.push(ReadRegion::new(source.clone(), 50, 10)).unwrap();
+        mt.check_tree().unwrap();
+        let root = mt.get_root().unwrap();
+        drop(mt);
+        let blocks = (0 .. 6).map(|i| ReadRegion::new(source.clone(), i * 10, 10)).collect::<Vec<_>>();
+        let tree = SqliteTreeStorage::<Sha256>::open(&path, "tree").unwrap();
+        let mt = MerkleTree::new_and_check(MemoryDataStorage::with_data(blocks), tree).unwrap();
+        assert_eq!(mt.get_root().unwrap(), root);
+    }
+}
@@ -0,0 +1,432 @@
+//! A copy-on-write tree storage, which keeps every committed version of the tree.
+//!
+//! Every level is a persistent vector - a trie of shared nodes with 32 children.
+//! A change copies only the nodes on the path to the changed value,
+//! so versions share all the unchanged nodes, and a commit takes O(levels).
+
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use merkle_tree::MerkleTree;
+use merkle_tree::proof::InclusionProof;
+use prelude::*;
+
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum Node<V> {
+    Leaf(Vec<V>),
+    Branch(Vec<Rc<Node<V>>>),
+}
+
+// A persistent vector; clones share all the nodes
+#[derive(Clone)]
+struct Level<V> {
+    root: Rc<Node<V>>,
+    len: usize,
+    // The number of branch levels above leaves
+    height: usize,
+}
+
+impl <V> Level<V> where V: Clone {
+    fn new() -> Self {
+        Level { root: Rc::new(Node::Leaf(Vec::new())), len: 0, height: 0 }
+    }
+
+    fn get(&self, index: usize) -> Option<&V> {
+        if index >= self.len {
+            return None;
+        }
+        let mut node = &*self.root;
+        let mut shift = self.height * BITS;
+        loop {
+            match *node {
+                Node::Branch(ref children) => {
+                    node = &children[(index >> shift) & MASK];
+                    shift -= BITS;
+                },
+                Node::Leaf(ref values) => return values.get(index & MASK),
+            }
+        }
+    }
+
+    fn get_mut(&mut self, index: usize) -> Option<&mut V> {
+        match index < self.len {
+            true => Some(node_get_mut(&mut self.root, index, self.height * BITS)),
+            false => None,
+        }
+    }
+
+    // Keeps first `len` values; only the new last path is copied, the rest is shared
+    fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        if len == 0 {
+            *self = Level::new();
+            return;
+        }
+        while self.height > 0 && len <= WIDTH << ((self.height - 1) * BITS) {
+            let first = match *self.root {
+                Node::Branch(ref children) => children[0].clone(),
+                Node::Leaf(_) => unreachable!("The root above leaves is a branch"),
+            };
+            self.root = first;
+            self.height -= 1;
+        }
+        node_truncate(&mut self.root, len, self.height * BITS);
+        self.len = len;
+    }
+
+    fn push(&mut self, value: V) {
+        if self.len == WIDTH << (self.height * BITS) {
+            let root = mem::replace(&mut self.root, Rc::new(Node::Branch(Vec::new())));
+            self.root = Rc::new(Node::Branch(vec![root]));
+            self.height += 1;
+        }
+        node_push(&mut self.root, self.len, self.height * BITS, value);
+        self.len += 1;
+    }
+}
+
+// Copies the path to the value, if it is shared
+fn node_get_mut<V: Clone>(node: &mut Rc<Node<V>>, index: usize, shift: usize) -> &mut V {
+    match *Rc::make_mut(node) {
+        Node::Branch(ref mut children) => node_get_mut(&mut children[(index >> shift) & MASK], index, shift - BITS),
+        Node::Leaf(ref mut values) => &mut values[index & MASK],
+    }
+}
+
+// Copies the last path, if it is shared, and keeps first `len` values of the subtree
+fn node_truncate<V: Clone>(node: &mut Rc<Node<V>>, len: usize, shift: usize) {
+    match *Rc::make_mut(node) {
+        Node::Leaf(ref mut values) => values.truncate(len),
+        Node::Branch(ref mut children) => {
+            let last = (len - 1) >> shift;
+            children.truncate(last + 1);
+            let rest = len - (last << shift);
+            if rest < 1 << shift {
+                node_truncate(&mut children[last], rest, shift - BITS)
+            }
+        },
+    }
+}
+
+// Copies the last path, if it is shared, and appends the value at `index`
+fn node_push<V: Clone>(node: &mut Rc<Node<V>>, index: usize, shift: usize, value: V) {
+    match *Rc::make_mut(node) {
+        Node::Leaf(ref mut values) => values.push(value),
+        Node::Branch(ref mut children) => {
+            let child = (index >> shift) & MASK;
+            if child == children.len() {
+                children.push(Rc::new(match shift {
+                    BITS => Node::Leaf(Vec::with_capacity(WIDTH)),
+                    _ => Node::Branch(Vec::with_capacity(WIDTH)),
+                }));
+            }
+            node_push(&mut children[child], index, shift - BITS, value)
+        },
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+/// An inmemory storage for a tree of hashes, which keeps all committed versions.
+/// `MerkleTree` commits a new version after each mutation
+pub struct VersionedTreeStorage<A> where A: MTAlgorithm {
+    // The current state, from the bottom level to the root
+    layers: Vec<Level<A::Value>>,
+    // Committed versions, from the oldest
+    versions: Vec<Vec<Level<A::Value>>>,
+}
+
+impl <A> Default for VersionedTreeStorage<A> where A: MTAlgorithm {
+    fn default() -> Self {
+        VersionedTreeStorage {
+            layers: Vec::new(),
+            versions: Vec::new(),
+        }
+    }
+}
+
+impl <A> VersionedTreeStorage<A> where A: MTAlgorithm {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Returns the number of committed versions
+    pub fn versions_len(&self) -> usize {
+        self.versions.len()
+    }
+
+    /// Returns the last committed version, or None if nothing is committed yet
+    pub fn version(&self) -> Option<usize> {
+        self.versions.len().checked_sub(1)
+    }
+
+    /// Returns a storage with the tree of the version, without history.
+    /// The storage shares all the nodes with this one
+    pub fn at(&self, version: usize) -> Result<Self> {
+        let layers = self.versions.get(version).ok_or(INDEX_IS_OUT_OF_BOUNDS)?.clone();
+        Ok(VersionedTreeStorage { layers, versions: Vec::new() })
+    }
+
+    /// Returns the root of the version, or None if the tree of the version is empty
+    pub fn root_at(&self, version: usize) -> Result<Option<A::Value>> {
+        self.at(version)?.get_root()
+    }
+
+    fn layer_mut(&mut self, level: usize) -> Result<&mut Level<A::Value>> {
+        Ok(self.layers.get_mut(level).ok_or(StateError::InconsistentState)?)
+    }
+}
+
+impl <A> fmt::Debug for VersionedTreeStorage<A> where A: MTAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VersionedTreeStorage(len={}, versions={})", self.layers.len(), self.versions.len())
+    }
+}
+
+impl <A> TreeStorageReadonly for VersionedTreeStorage<A> where A: MTAlgorithm {
+    type Algorithm = A;
+
+    fn len(&self) -> Result<usize> {
+        Ok(self.layers.len())
+    }
+
+    fn get_level_len(&self, level: usize) -> Result<usize> {
+        self.layers.get(level)
+            .map(|layer| layer.len)
+            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn get_value(&self, level: usize, index: usize) -> Result<A::Value> {
+        self.layers.get(level)
+            .and_then(|layer| layer.get(index).cloned())
+            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn with_value<R, F: FnOnce(&A::Value) -> Result<R>>(&self, level: usize, index: usize, f: F) -> Result<R> {
+        f(self.layers.get(level).and_then(|layer| layer.get(index)).ok_or(INDEX_IS_OUT_OF_BOUNDS)?)
+    }
+
+    fn iter_level<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<A::Value>> + 's>> {
+        let layer = self.layers.get(level).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        Ok(Box::new((0 .. layer.len).map(move |index| Ok(layer.get(index).cloned().expect("Index is in bounds")))))
+    }
+
+    fn iter_level_by_pair<'s>(&'s self, level: usize) -> Result<Box<Iterator<Item=Result<(A::Value, A::Value)>> + 's>> {
+        let layer = self.layers.get(level).ok_or(INDEX_IS_OUT_OF_BOUNDS)?;
+        Ok(Box::new((0 .. (layer.len + 1) / 2).map(move |pair| {
+            let left = layer.get(pair * 2).cloned().expect("Index is in bounds");
+            let right = layer.get(pair * 2 + 1).cloned().unwrap_or_else(|| left.clone());
+            Ok((left, right))
+        })))
+    }
+
+    fn is_writeable(&self) -> bool {
+        true
+    }
+}
+
+impl <A> TreeStorage for VersionedTreeStorage<A> where A: MTAlgorithm {
+    fn clear_and_reserve(&mut self, sizes: &[usize]) -> Result<()> {
+        self.layers = sizes.iter().map(|_| Level::new()).collect();
+        Ok(())
+    }
+
+    fn grow(&mut self) -> Result<()> {
+        self.layers.push(Level::new());
+        Ok(())
+    }
+
+    fn truncate(&mut self, sizes: &[usize]) -> Result<()> {
+        self.layers.truncate(sizes.len());
+        for (layer, &size) in self.layers.iter_mut().zip(sizes) {
+            layer.truncate(size);
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        let layers = self.layers.clone();
+        self.versions.push(layers);
+        Ok(())
+    }
+
+    /// Returns to the last committed version
+    fn rollback(&mut self) -> Result<()> {
+        self.layers = self.versions.last().cloned().unwrap_or_default();
+        Ok(())
+    }
+
+    fn get_value_mut(&mut self, level: usize, index: usize) -> Result<&mut A::Value> {
+        self.layers.get_mut(level)
+            .and_then(|layer| layer.get_mut(index))
+            .ok_or(INDEX_IS_OUT_OF_BOUNDS)
+    }
+
+    fn push(&mut self, level: usize, value: A::Value) -> Result<()> {
+        self.layer_mut(level)?.push(value);
+        Ok(())
+    }
+
+    fn extend<I>(&mut self, level: usize, other: I) -> Result<()>
+        where I: IntoIterator<Item=Result<A::Value>>
+    {
+        let layer = self.layer_mut(level)?;
+        for v in other.into_iter() {
+            layer.push(v?);
+        }
+        Ok(())
+    }
+
+    fn extend_from_slice(&mut self, level: usize, slice: &[A::Value]) -> Result<()> {
+        let layer = self.layer_mut(level)?;
+        for v in slice {
+            layer.push(v.clone());
+        }
+        Ok(())
+    }
+}
+
+
+// -------------------------------------------------------------------------------------------------
+
+
+impl <D, A> MerkleTree<D, VersionedTreeStorage<A>> where D: DataStorageReadonly, A: MTAlgorithm {
+    /// Returns the root hash of the version, or None if the tree of the version is empty
+    pub fn root_at(&self, version: usize) -> Result<Option<A::Value>> {
+        self.tree().root_at(version)
+    }
+
+    /// Returns the chain of hashes from the leaf to the root of the version.
+    /// Checks that every hash in the chain corresponds to its children;
+    /// the data is not versioned, so the leaf is not checked against the data block
+    pub fn audit_proof_at(&self, version: usize, mut index: usize) -> Result<Vec<A::Value>> {
+        let tree = self.tree().at(version)?;
+        let arity = self.arity();
+        let mut path = Vec::with_capacity(tree.len()?);
+        path.push(tree.get_value(0, index)?);
+        for level in 0 .. tree.len()? - 1 {
+            let group = level_group(&tree, level, index, arity)?;
+            index /= arity;
+            let hash = tree.get_value(level + 1, index)?;
+            if hash != A::eval_hash(&&group[..]) {
+                Err(StateError::DataDoesNotMatchTheChecksum)?;
+            }
+            path.push(hash);
+        }
+        Ok(path)
+    }
+
+    /// Returns a proof of inclusion of the data block into the tree of the version
+    pub fn inclusion_proof_at(&self, version: usize, index: usize) -> Result<InclusionProof<A::Value>> {
+        InclusionProof::from_tree(&self.tree().at(version)?, self.arity(), index)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use data_storage::memory::MemoryDataStorage;
+    use fun::sha256::*;
+    use super::*;
+
+    type Versioned = MerkleTree<MemoryDataStorage<u32>, VersionedTreeStorage<Sha256>>;
+
+    #[test]
+    fn persistent_level() {
+        let mut a = Level::new();
+        for x in 0 .. 2000u32 {
+            a.push(x);
+        }
+        let b = a.clone();
+        *a.get_mut(1500).unwrap() = 0;
+        a.push(2000);
+        assert_eq!(a.get(1500), Some(&0));
+        assert_eq!(b.get(1500), Some(&1500));
+        assert_eq!(a.get(2000), Some(&2000));
+        assert_eq!(b.get(2000), None);
+        assert!((0 .. 2000).all(|x| b.get(x as usize) == Some(&x)));
+
+        // Truncation copies only the last path and keeps the rest shared
+        for &len in &[1999, 1025, 1024, 1000, 33, 32, 1, 0] {
+            let mut c = b.clone();
+            c.truncate(len);
+            assert_eq!(c.len, len);
+            assert!((0 .. len).all(|x| c.get(x) == b.get(x)));
+            assert_eq!(c.get(len), None);
+            for x in len .. 2001 {
+                c.push(x as u32 + 1);
+            }
+            assert_eq!(c.get(len), Some(&(len as u32 + 1)));
+            assert_eq!(c.get(2000), Some(&2001));
+        }
+        let mut c = b.clone();
+        c.truncate(1000);
+        assert_eq!((b.height, c.height), (2, 1));
+        let first = match *b.root {
+            Node::Branch(ref children) => children[0].clone(),
+            Node::Leaf(_) => panic!("Expected a branch"),
+        };
+        match (&*first, &*c.root) {
+            (&Node::Branch(ref first), &Node::Branch(ref c)) => {
+                assert_eq!(c.len(), 32);
+                assert!((0 .. 31).all(|i| Rc::ptr_eq(&first[i], &c[i])));
+                assert!(!Rc::ptr_eq(&first[31], &c[31]));
+            },
+            _ => panic!("Expected branches"),
+        }
+        assert!((0 .. 2000).all(|x| b.get(x as usize) == Some(&x)));
+    }
+
+    #[test]
+    fn versioned_tree_roots() {
+        let mut mt = Versioned::default();
+        let mut roots = Vec::new();
+        for x in 0 .. 40 {
+            mt.push(x).unwrap();
+            roots.push(mt.get_root().unwrap());
+        }
+        assert_eq!(mt.tree().versions_len(), 40);
+        for (version, root) in roots.iter().enumerate() {
+            assert_eq!(mt.root_at(version).unwrap(), *root);
+            let size = version as u32 + 1;
+            let expected = Versioned::new_and_rebuild(MemoryDataStorage::with_data((0 .. size).collect::<Vec<_>>()), Default::default()).unwrap();
+            assert_eq!(expected.get_root().unwrap(), *root);
+        }
+
+        mt.clear().unwrap();
+        assert_eq!(mt.root_at(40).unwrap(), None);
+        assert_eq!(mt.root_at(39).unwrap(), roots[39]);
+        assert!(mt.root_at(41).is_err());
+    }
+
+    #[test]
+    fn versioned_tree_proofs() {
+        let mut mt = Versioned::default();
+        for x in 0 .. 10 {
+            mt.push(x).unwrap();
+        }
+        mt.extend((10 .. 20).map(Ok)).unwrap();
+        mt.check_tree().unwrap();
+        for version in 0 .. mt.tree().versions_len() {
+            let root = mt.root_at(version).unwrap().unwrap();
+            let size = mt.tree().at(version).unwrap().get_level_len(0).unwrap();
+            for index in 0 .. size {
+                let path = mt.audit_proof_at(version, index).unwrap();
+                assert_eq!(path.last(), Some(&root));
+                let proof = mt.inclusion_proof_at(version, index).unwrap();
+                assert!(proof.verify::<Sha256, _>(&(index as u32), &root).unwrap());
+            }
+            assert!(mt.inclusion_proof_at(version, size).is_err());
+        }
+    }
+}
@@ -29,6 +29,58 @@ pub fn hex2buf<W: Write, S: AsRef<[u8]>>(mut buf: W, string: S) -> io::Result<()
     Ok(())
 }
 
+static BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encodes byte slice as base64 string (standard alphabet, with padding)
+pub fn buf2base64<S: AsRef<[u8]>>(data: S) -> String {
+    let data = data.as_ref();
+    let mut result = String::with_capacity((data.len() + 2) / 3 * 4);
+    for chunk in data.chunks(3) {
+        let n = (chunk[0] as usize) << 16
+            | (chunk.get(1).cloned().unwrap_or(0) as usize) << 8
+            | chunk.get(2).cloned().unwrap_or(0) as usize;
+        result.push(BASE64_ALPHABET[n >> 18 & 63] as char);
+        result.push(BASE64_ALPHABET[n >> 12 & 63] as char);
+        result.push(if chunk.len() > 1 { BASE64_ALPHABET[n >> 6 & 63] as char } else { '=' });
+        result.push(if chunk.len() > 2 { BASE64_ALPHABET[n & 63] as char } else { '=' });
+    }
+    result
+}
+
+/// Parses base64 string (standard alphabet, with padding) into byte buffer
+pub fn base642buf<W: Write, S: AsRef<[u8]>>(mut buf: W, string: S) -> io::Result<()> {
+    let string = string.as_ref();
+    if string.len() % 4 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, ""));
+    }
+    let last = string.len() / 4;
+    for (i, ch) in string.chunks(4).enumerate() {
+        let padding = ch.iter().rev().take_while(|&&c| c == b'=').count();
+        if padding > 2 || padding > 0 && i + 1 != last {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, ""));
+        }
+        let mut n = 0;
+        for &c in &ch[.. 4 - padding] {
+            n = n << 6 | base64_digit(c).ok_or(io::Error::new(io::ErrorKind::InvalidData, ""))?;
+        }
+        n <<= 6 * padding;
+        let bytes = [(n >> 16) as u8, (n >> 8) as u8, n as u8];
+        buf.write_all(&bytes[.. 3 - padding])?;
+    }
+    Ok(())
+}
+
+fn base64_digit(c: u8) -> Option<u32> {
+    match c {
+        b'A' ..= b'Z' => Some((c - b'A') as u32),
+        b'a' ..= b'z' => Some((c - b'a') as u32 + 26),
+        b'0' ..= b'9' => Some((c - b'0') as u32 + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
@@ -60,4 +112,27 @@ mod tests {
         let mut buf = Vec::new();
         assert!(hex2buf(&mut buf, "xx").is_err());
     }
+
+    #[test]
+    fn base64_encode_decode() {
+        let samples: [(&[u8], &str); 5] = [
+            (b"", ""),
+            (b"f", "Zg=="),
+            (b"fo", "Zm8="),
+            (b"foo", "Zm9v"),
+            (&DATA, "Aw4PXAY="),
+        ];
+        for &(data, encoded) in samples.iter() {
+            assert_eq!(buf2base64(data), encoded);
+            let mut buf = Vec::new();
+            base642buf(&mut buf, encoded).unwrap();
+            assert_eq!(&buf[..], data);
+        }
+
+        let mut buf = Vec::new();
+        assert!(base642buf(&mut buf, "Zg=").is_err());
+        assert!(base642buf(&mut buf, "Z===").is_err());
+        assert!(base642buf(&mut buf, "Zg==Zm8=").is_err());
+        assert!(base642buf(&mut buf, "Zm9*").is_err());
+    }
 }